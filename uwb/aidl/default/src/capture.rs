@@ -0,0 +1,307 @@
+//! Capture of UCI packets in pcapng format, for offline analysis
+//! with tools such as Wireshark, or raw for hardware sniffers.
+
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Link type registered for FiRa UCI packets.
+const LINKTYPE_FIRA_UCI: u16 = 299;
+
+const SECTION_HEADER_BLOCK: u32 = 0x0a0d0d0a;
+const INTERFACE_DESCRIPTION_BLOCK: u32 = 0x00000001;
+const ENHANCED_PACKET_BLOCK: u32 = 0x00000006;
+const BYTE_ORDER_MAGIC: u32 = 0x1a2b3c4d;
+
+const OPT_ENDOFOPT: u16 = 0;
+const OPT_EPB_FLAGS: u16 = 2;
+
+/// Maximum number of packets queued for the writer thread.
+/// Packets are dropped when the queue is full.
+const QUEUE_SIZE: usize = 256;
+
+/// Direction of a captured packet, relative to the host.
+#[derive(Clone, Copy, Debug)]
+pub enum Direction {
+    /// Packet received from the UWBS.
+    Inbound,
+    /// Packet sent to the UWBS.
+    Outbound,
+}
+
+impl Direction {
+    /// Value of the direction bits of the epb_flags option.
+    fn epb_flags(self) -> u32 {
+        match self {
+            Direction::Inbound => 1,
+            Direction::Outbound => 2,
+        }
+    }
+}
+
+struct Record {
+    timestamp_us: u64,
+    direction: Direction,
+    packet: Vec<u8>,
+}
+
+/// Best-effort packet recorder. Packets are queued to a dedicated thread
+/// so that capture errors and file I/O never block the data path.
+pub struct Capture {
+    sender: mpsc::SyncSender<Record>,
+}
+
+impl Capture {
+    /// Start capturing to the file at `path`, replaced if it exists.
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        let path = path.into();
+        Self::spawn(move |receiver| {
+            if let Err(err) = write_capture(&path, receiver) {
+                tracing::warn!(path = %path.display(), %err, "UCI capture failed");
+            }
+        })
+    }
+
+    /// Copy the raw UCI packets to `sink`, without pcapng framing.
+    pub fn tee(sink: impl Write + Send + 'static) -> Self {
+        Self::spawn(move |receiver| {
+            if let Err(err) = write_tee(sink, receiver) {
+                tracing::warn!(%err, "UCI tee failed");
+            }
+        })
+    }
+
+    fn spawn(writer: impl FnOnce(mpsc::Receiver<Record>) + Send + 'static) -> Self {
+        let (sender, receiver) = mpsc::sync_channel(QUEUE_SIZE);
+        thread::spawn(move || writer(receiver));
+        Capture { sender }
+    }
+
+    /// Record a single UCI packet.
+    pub fn record(&self, direction: Direction, packet: &[u8]) {
+        let timestamp_us = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|timestamp| timestamp.as_micros() as u64)
+            .unwrap_or(0);
+        let record = Record {
+            timestamp_us,
+            direction,
+            packet: packet.to_vec(),
+        };
+        if let Err(mpsc::TrySendError::Full(_)) = self.sender.try_send(record) {
+            tracing::warn!(
+                length = packet.len(),
+                "UCI capture queue is full, dropping packet"
+            );
+        }
+    }
+}
+
+/// Write the records received from `receiver` until all senders
+/// are dropped.
+fn write_capture(path: &Path, receiver: mpsc::Receiver<Record>) -> io::Result<()> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    write_section_header_block(&mut writer)?;
+    write_interface_description_block(&mut writer)?;
+    writer.flush()?;
+
+    for record in receiver {
+        write_enhanced_packet_block(&mut writer, &record)?;
+        writer.flush()?;
+    }
+    Ok(())
+}
+
+/// Write the packets of the records received from `receiver` until all
+/// senders are dropped.
+fn write_tee(mut sink: impl Write, receiver: mpsc::Receiver<Record>) -> io::Result<()> {
+    for record in receiver {
+        sink.write_all(&record.packet)?;
+        sink.flush()?;
+    }
+    Ok(())
+}
+
+fn write_section_header_block(writer: &mut impl Write) -> io::Result<()> {
+    const LENGTH: u32 = 28;
+    writer.write_all(&SECTION_HEADER_BLOCK.to_le_bytes())?;
+    writer.write_all(&LENGTH.to_le_bytes())?;
+    writer.write_all(&BYTE_ORDER_MAGIC.to_le_bytes())?;
+    // Version 1.0.
+    writer.write_all(&1u16.to_le_bytes())?;
+    writer.write_all(&0u16.to_le_bytes())?;
+    // Unspecified section length.
+    writer.write_all(&(-1i64).to_le_bytes())?;
+    writer.write_all(&LENGTH.to_le_bytes())
+}
+
+fn write_interface_description_block(writer: &mut impl Write) -> io::Result<()> {
+    const LENGTH: u32 = 20;
+    writer.write_all(&INTERFACE_DESCRIPTION_BLOCK.to_le_bytes())?;
+    writer.write_all(&LENGTH.to_le_bytes())?;
+    writer.write_all(&LINKTYPE_FIRA_UCI.to_le_bytes())?;
+    writer.write_all(&0u16.to_le_bytes())?;
+    // No snapshot length limit.
+    writer.write_all(&0u32.to_le_bytes())?;
+    writer.write_all(&LENGTH.to_le_bytes())
+}
+
+fn write_enhanced_packet_block(writer: &mut impl Write, record: &Record) -> io::Result<()> {
+    let packet_len = record.packet.len() as u32;
+    let padding = (4 - record.packet.len() % 4) % 4;
+    // Fixed fields, padded packet data, epb_flags and end of options.
+    let length = 32 + packet_len + padding as u32 + 12;
+
+    writer.write_all(&ENHANCED_PACKET_BLOCK.to_le_bytes())?;
+    writer.write_all(&length.to_le_bytes())?;
+    // Interface identifier.
+    writer.write_all(&0u32.to_le_bytes())?;
+    writer.write_all(&((record.timestamp_us >> 32) as u32).to_le_bytes())?;
+    writer.write_all(&(record.timestamp_us as u32).to_le_bytes())?;
+    writer.write_all(&packet_len.to_le_bytes())?;
+    writer.write_all(&packet_len.to_le_bytes())?;
+    writer.write_all(&record.packet)?;
+    writer.write_all(&[0; 3][..padding])?;
+    writer.write_all(&OPT_EPB_FLAGS.to_le_bytes())?;
+    writer.write_all(&4u16.to_le_bytes())?;
+    writer.write_all(&record.direction.epb_flags().to_le_bytes())?;
+    writer.write_all(&OPT_ENDOFOPT.to_le_bytes())?;
+    writer.write_all(&0u16.to_le_bytes())?;
+    writer.write_all(&length.to_le_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Condvar, Mutex};
+    use std::time::{Duration, Instant};
+
+    const GET_DEVICE_INFO_CMD: [u8; 4] = [0x20, 0x02, 0x00, 0x00];
+    const DEVICE_STATUS_READY_NTF: [u8; 5] = [0x60, 0x01, 0x00, 0x01, 0x01];
+
+    /// Wait for the writer thread to meet a condition.
+    fn wait_until(condition: impl Fn() -> bool) {
+        let deadline = Instant::now() + Duration::from_secs(1);
+        while !condition() {
+            assert!(
+                Instant::now() < deadline,
+                "timed out waiting for the capture"
+            );
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    fn u16_at(bytes: &[u8], offset: usize) -> u16 {
+        u16::from_le_bytes(bytes[offset..offset + 2].try_into().unwrap())
+    }
+
+    fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+    }
+
+    #[test]
+    fn packets_are_captured() {
+        let path = std::env::temp_dir().join(format!("uwb-capture-{}.pcapng", std::process::id()));
+        let capture = Capture::new(&path);
+        capture.record(Direction::Outbound, &GET_DEVICE_INFO_CMD);
+        capture.record(Direction::Inbound, &DEVICE_STATUS_READY_NTF);
+        drop(capture);
+
+        // Section header, interface description, then the packets padded
+        // to 32 bits.
+        let length = 28 + 20 + 48 + 52;
+        wait_until(|| std::fs::metadata(&path).is_ok_and(|file| file.len() == length));
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let (shb, bytes) = bytes.split_at(28);
+        assert_eq!(u32_at(shb, 0), SECTION_HEADER_BLOCK);
+        assert_eq!(u32_at(shb, 4), 28);
+        assert_eq!(u32_at(shb, 8), BYTE_ORDER_MAGIC);
+        assert_eq!(u32_at(shb, 24), 28);
+
+        let (idb, bytes) = bytes.split_at(20);
+        assert_eq!(u32_at(idb, 0), INTERFACE_DESCRIPTION_BLOCK);
+        assert_eq!(u16_at(idb, 8), LINKTYPE_FIRA_UCI);
+        assert_eq!(u32_at(idb, 16), 20);
+
+        let (epb, bytes) = bytes.split_at(48);
+        assert_eq!(u32_at(epb, 0), ENHANCED_PACKET_BLOCK);
+        assert_eq!(u32_at(epb, 4), 48);
+        assert_eq!(u32_at(epb, 20), 4);
+        assert_eq!(u32_at(epb, 24), 4);
+        assert_eq!(epb[28..32], GET_DEVICE_INFO_CMD);
+        assert_eq!(u32_at(epb, 36), Direction::Outbound.epb_flags());
+        assert_eq!(u32_at(epb, 44), 48);
+
+        let epb = bytes;
+        assert_eq!(u32_at(epb, 0), ENHANCED_PACKET_BLOCK);
+        assert_eq!(u32_at(epb, 4), 52);
+        assert_eq!(u32_at(epb, 20), 5);
+        assert_eq!(u32_at(epb, 24), 5);
+        assert_eq!(epb[28..33], DEVICE_STATUS_READY_NTF);
+        assert_eq!(epb[33..36], [0; 3]);
+        assert_eq!(u32_at(epb, 40), Direction::Inbound.epb_flags());
+        assert_eq!(u32_at(epb, 48), 52);
+    }
+
+    /// Blocked, released and closed flags, and bytes written to the sink.
+    #[derive(Default)]
+    struct SinkState {
+        blocked: bool,
+        released: bool,
+        closed: bool,
+        written: Vec<u8>,
+    }
+
+    /// Sink blocking the writer thread until released.
+    struct BlockingSink(Arc<(Mutex<SinkState>, Condvar)>);
+
+    impl Write for BlockingSink {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let (state, condvar) = &*self.0;
+            let mut state = state.lock().unwrap();
+            state.blocked = true;
+            let mut state = condvar.wait_while(state, |state| !state.released).unwrap();
+            state.written.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Drop for BlockingSink {
+        fn drop(&mut self) {
+            self.0 .0.lock().unwrap().closed = true;
+        }
+    }
+
+    #[test]
+    fn packets_are_dropped_when_queue_is_full() {
+        let sink = Arc::new((Mutex::new(SinkState::default()), Condvar::new()));
+        let capture = Capture::tee(BlockingSink(sink.clone()));
+        let state = || sink.0.lock().unwrap();
+
+        // The writer thread blocks on the first packet, then the queue
+        // fills up and the last packet is dropped.
+        capture.record(Direction::Inbound, &DEVICE_STATUS_READY_NTF);
+        wait_until(|| state().blocked);
+        for _ in 0..QUEUE_SIZE + 1 {
+            capture.record(Direction::Outbound, &GET_DEVICE_INFO_CMD);
+        }
+        state().released = true;
+        sink.1.notify_all();
+        drop(capture);
+
+        wait_until(|| state().closed);
+        assert_eq!(
+            state().written.len(),
+            DEVICE_STATUS_READY_NTF.len() + QUEUE_SIZE * GET_DEVICE_INFO_CMD.len()
+        );
+    }
+}
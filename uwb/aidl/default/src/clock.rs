@@ -0,0 +1,139 @@
+//! Time source of the chips, for the timestamps and timeouts of the UCI
+//! traffic. The tests replace it with a fake clock, advanced explicitly
+//! to trigger the timeouts deterministically.
+
+use futures::future::BoxFuture;
+use std::future::Future;
+use std::time::{Duration, Instant};
+use tokio::select;
+
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+
+    /// Return a future completing once `duration` has elapsed.
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()>;
+}
+
+/// Clock of the tokio runtime.
+#[derive(Debug, Default)]
+pub struct TokioClock;
+
+impl Clock for TokioClock {
+    fn now(&self) -> Instant {
+        tokio::time::Instant::now().into_std()
+    }
+
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()> {
+        Box::pin(tokio::time::sleep(duration))
+    }
+}
+
+/// Run `future` until it completes, or until `duration` has elapsed on
+/// `clock`. Returns None on timeout.
+pub async fn timeout<F: Future>(
+    clock: &dyn Clock,
+    duration: Duration,
+    future: F,
+) -> Option<F::Output> {
+    select! {
+        biased;
+        output = future => Some(output),
+        _ = clock.sleep(duration) => None,
+    }
+}
+
+/// Clock only advancing when the test calls advance().
+#[cfg(test)]
+#[derive(Clone)]
+pub struct FakeClock(std::sync::Arc<std::sync::Mutex<FakeTime>>);
+
+#[cfg(test)]
+struct FakeTime {
+    start: Instant,
+    elapsed: Duration,
+    /// Wakers of the pending sleeps, with their deadlines.
+    sleeps: Vec<(Duration, tokio::sync::oneshot::Sender<()>)>,
+}
+
+#[cfg(test)]
+impl Default for FakeClock {
+    fn default() -> Self {
+        FakeClock(std::sync::Arc::new(std::sync::Mutex::new(FakeTime {
+            start: Instant::now(),
+            elapsed: Duration::ZERO,
+            sleeps: Vec::new(),
+        })))
+    }
+}
+
+#[cfg(test)]
+impl FakeClock {
+    /// Advance the clock, completing the sleeps which are over.
+    pub fn advance(&self, duration: Duration) {
+        let mut time = self.0.lock().unwrap();
+        time.elapsed += duration;
+        let elapsed = time.elapsed;
+        let (over, pending): (Vec<_>, Vec<_>) = std::mem::take(&mut time.sleeps)
+            .into_iter()
+            .partition(|(deadline, _)| *deadline <= elapsed);
+        time.sleeps = pending;
+        for (_, waker) in over {
+            let _ = waker.send(());
+        }
+    }
+
+    /// Number of pending sleeps.
+    pub fn sleeps(&self) -> usize {
+        let mut time = self.0.lock().unwrap();
+        time.sleeps.retain(|(_, waker)| !waker.is_closed());
+        time.sleeps.len()
+    }
+}
+
+#[cfg(test)]
+impl Clock for FakeClock {
+    fn now(&self) -> Instant {
+        let time = self.0.lock().unwrap();
+        time.start + time.elapsed
+    }
+
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()> {
+        let mut time = self.0.lock().unwrap();
+        let deadline = time.elapsed + duration;
+        if deadline <= time.elapsed {
+            return Box::pin(std::future::ready(()));
+        }
+        let (waker, sleep) = tokio::sync::oneshot::channel();
+        time.sleeps.push((deadline, waker));
+        Box::pin(async move {
+            let _ = sleep.await;
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn timeout_on_fake_clock() {
+        let clock = FakeClock::default();
+        let start = clock.now();
+        let pending = timeout(&clock, Duration::from_secs(1), std::future::pending::<()>());
+        let advance = async {
+            while clock.sleeps() == 0 {
+                tokio::task::yield_now().await;
+            }
+            clock.advance(Duration::from_millis(999));
+            assert_eq!(clock.sleeps(), 1);
+            clock.advance(Duration::from_millis(1));
+        };
+        let (result, ()) = tokio::join!(pending, advance);
+        assert_eq!(result, None);
+        assert_eq!(clock.now() - start, Duration::from_secs(1));
+        assert_eq!(
+            timeout(&clock, Duration::from_secs(1), async { 1 }).await,
+            Some(1)
+        );
+    }
+}
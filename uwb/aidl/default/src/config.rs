@@ -0,0 +1,299 @@
+//! Configuration file listing the UWB chips exposed by the service,
+//! for boards with multiple UWBS.
+//!
+//! The file contains a JSON list of chips, for example:
+//!
+//! ```json
+//! [
+//!     { "name": "0", "path": "/dev/ttyUSB0" },
+//!     { "name": "1", "path": "127.0.0.1:7000", "transport": "tcp" }
+//! ]
+//! ```
+//!
+//! Emulated devices checking the integrity of the UCI packets use the
+//! `tcp-crc` transport. With the `spi` feature, the `spi` transport opens
+//! the spidev at `path`, and requires the sysfs gpio of the interrupt line
+//! of the UWBS, e.g. `{ "name": "0", "path": "/dev/spidev0.0",
+//! "transport": "spi", "irq_gpio": 42 }`.
+//!
+//! The optional `vendor` entry of a chip lists the values of its UWBS not
+//! reported by the standard UCI messages, e.g. `"vendor": { "max_sessions":
+//! 4, "android_uci_version_offset": 0, "session_app_configs": [[227, [1]]] }`.
+
+use std::collections::HashSet;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Arc;
+
+use anyhow::{bail, Context};
+use serde::Deserialize;
+
+use crate::uci::AppConfigTag;
+use crate::uwb_chip::UwbChip;
+use crate::vendor;
+
+/// Transport used to connect to the UWBS.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Transport {
+    /// `path` is the path of a serial device.
+    #[default]
+    Serial,
+    /// `path` is the socket address of an emulated device.
+    Tcp,
+    /// `path` is the socket address of an emulated device exchanging
+    /// the UCI packets in frames checked with a CRC.
+    #[serde(rename = "tcp-crc")]
+    CrcTcp,
+    /// `path` is the path of a spidev, and `irq_gpio` the gpio signaling
+    /// the pending packets of the UWBS.
+    #[cfg(feature = "spi")]
+    Spi,
+}
+
+/// Configuration of a single chip.
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct ChipConfig {
+    /// Name of the chip reported by IUwb.getChips().
+    pub name: String,
+    pub path: String,
+    #[serde(default)]
+    pub transport: Transport,
+    /// Interrupt gpio of the UWBS, for the SPI transport.
+    #[serde(default)]
+    pub irq_gpio: Option<u32>,
+    /// Vendor specific values of the UWBS.
+    #[serde(default)]
+    pub vendor: Option<VendorConfig>,
+}
+
+/// Vendor specific values of a UWBS, handled by `vendor::Configured`.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct VendorConfig {
+    /// Offset of the octet carrying the Android UCI version in the vendor
+    /// specific information of CORE_GET_DEVICE_INFO_RSP.
+    #[serde(default)]
+    pub android_uci_version_offset: Option<usize>,
+    /// Maximum number of concurrent sessions of the UWBS.
+    #[serde(default)]
+    pub max_sessions: Option<usize>,
+    /// Tags and values of the app configurations set on each session
+    /// by sessionInit().
+    #[serde(default)]
+    pub session_app_configs: Vec<(u8, Vec<u8>)>,
+}
+
+impl VendorConfig {
+    fn into_handler(self) -> vendor::Configured {
+        vendor::Configured {
+            android_uci_version_offset: self.android_uci_version_offset,
+            max_sessions: self.max_sessions,
+            session_app_configs: self
+                .session_app_configs
+                .into_iter()
+                .map(|(tag, value)| (AppConfigTag(tag), value))
+                .collect(),
+        }
+    }
+}
+
+impl ChipConfig {
+    /// Configuration of a chip given as a command line argument,
+    /// a serial device path or a socket address for emulated devices.
+    pub fn from_arg(name: String, arg: String) -> Self {
+        let transport = match arg.parse::<SocketAddr>() {
+            Ok(_) => Transport::Tcp,
+            Err(_) => Transport::Serial,
+        };
+        ChipConfig {
+            name,
+            path: arg,
+            transport,
+            irq_gpio: None,
+            vendor: None,
+        }
+    }
+
+    /// Create the configured chip.
+    pub fn into_chip(mut self) -> anyhow::Result<UwbChip> {
+        let vendor = self.vendor.take();
+        let chip = match self.transport {
+            Transport::Serial => UwbChip::new(self.name, self.path),
+            Transport::Tcp => {
+                let addr = self.address()?;
+                UwbChip::new_tcp(self.name, addr)
+            }
+            Transport::CrcTcp => {
+                let addr = self.address()?;
+                UwbChip::new_crc_tcp(self.name, addr)
+            }
+            #[cfg(feature = "spi")]
+            Transport::Spi => {
+                let irq_gpio = self.irq_gpio()?;
+                UwbChip::new_spi(self.name, self.path, irq_gpio)
+            }
+        };
+        Ok(match vendor {
+            Some(vendor) => chip.with_vendor_handler(Arc::new(vendor.into_handler())),
+            None => chip,
+        })
+    }
+
+    fn address(&self) -> anyhow::Result<SocketAddr> {
+        self.path
+            .parse()
+            .with_context(|| format!("invalid address {} for chip {}", self.path, self.name))
+    }
+
+    #[cfg(feature = "spi")]
+    fn irq_gpio(&self) -> anyhow::Result<u32> {
+        self.irq_gpio
+            .with_context(|| format!("missing irq_gpio for chip {}", self.name))
+    }
+}
+
+/// Load the chip configurations from the file at `path`.
+pub fn load(path: &Path) -> anyhow::Result<Vec<ChipConfig>> {
+    let contents = fs::read_to_string(path)
+        .with_context(|| format!("failed to read UWB config file {}", path.display()))?;
+    parse(&contents).with_context(|| format!("invalid UWB config file {}", path.display()))
+}
+
+fn parse(contents: &str) -> anyhow::Result<Vec<ChipConfig>> {
+    let chips: Vec<ChipConfig> = serde_json::from_str(contents)?;
+    if chips.is_empty() {
+        bail!("no chips are configured");
+    }
+    let mut names = HashSet::new();
+    for chip in chips.iter() {
+        if !names.insert(&chip.name) {
+            bail!("chip {} is configured more than once", chip.name);
+        }
+        if matches!(chip.transport, Transport::Tcp | Transport::CrcTcp) {
+            chip.address()?;
+        }
+        #[cfg(feature = "spi")]
+        if chip.transport == Transport::Spi {
+            chip.irq_gpio()?;
+        }
+    }
+    Ok(chips)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_chips() {
+        let chips = parse(
+            r#"[
+                { "name": "0", "path": "/dev/ttyUSB0" },
+                { "name": "1", "path": "127.0.0.1:7000", "transport": "tcp" },
+                { "name": "2", "path": "127.0.0.1:7001", "transport": "tcp-crc" }
+            ]"#,
+        )
+        .unwrap();
+        assert_eq!(
+            chips,
+            [
+                ChipConfig {
+                    name: "0".to_owned(),
+                    path: "/dev/ttyUSB0".to_owned(),
+                    transport: Transport::Serial,
+                    irq_gpio: None,
+                    vendor: None,
+                },
+                ChipConfig {
+                    name: "1".to_owned(),
+                    path: "127.0.0.1:7000".to_owned(),
+                    transport: Transport::Tcp,
+                    irq_gpio: None,
+                    vendor: None,
+                },
+                ChipConfig {
+                    name: "2".to_owned(),
+                    path: "127.0.0.1:7001".to_owned(),
+                    transport: Transport::CrcTcp,
+                    irq_gpio: None,
+                    vendor: None,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_rejects_invalid_configs() {
+        assert!(parse("[]").is_err());
+        assert!(parse(r#"[{ "name": "0" }]"#).is_err());
+        assert!(parse(r#"[{ "name": "0", "path": "/dev/ttyUSB0", "transport": "usb" }]"#).is_err());
+        assert!(parse(r#"[{ "name": "0", "path": "/dev/ttyUSB0", "transport": "tcp" }]"#).is_err());
+        assert!(parse(
+            r#"[
+                { "name": "0", "path": "/dev/ttyUSB0" },
+                { "name": "0", "path": "/dev/ttyUSB1" }
+            ]"#
+        )
+        .is_err());
+    }
+
+    #[cfg(feature = "spi")]
+    #[test]
+    fn parse_spi_chip() {
+        let chips = parse(
+            r#"[{ "name": "0", "path": "/dev/spidev0.0", "transport": "spi", "irq_gpio": 42 }]"#,
+        )
+        .unwrap();
+        assert_eq!(chips[0].transport, Transport::Spi);
+        assert_eq!(chips[0].irq_gpio, Some(42));
+        assert!(
+            parse(r#"[{ "name": "0", "path": "/dev/spidev0.0", "transport": "spi" }]"#).is_err()
+        );
+    }
+
+    #[test]
+    fn parse_vendor_config() {
+        let chips = parse(
+            r#"[{
+                "name": "0",
+                "path": "/dev/ttyUSB0",
+                "vendor": { "max_sessions": 4, "session_app_configs": [[227, [1, 2]]] }
+            }]"#,
+        )
+        .unwrap();
+        let vendor = chips[0].vendor.clone().unwrap();
+        assert_eq!(
+            vendor.into_handler(),
+            vendor::Configured {
+                android_uci_version_offset: None,
+                max_sessions: Some(4),
+                session_app_configs: vec![(AppConfigTag(227), vec![1, 2])],
+            }
+        );
+        assert!(
+            parse(r#"[{ "name": "0", "path": "/dev/ttyUSB0", "vendor": { "sessions": 4 } }]"#)
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn chip_from_arg() {
+        assert_eq!(
+            ChipConfig::from_arg("0".to_owned(), "/dev/ttyUSB0".to_owned()).transport,
+            Transport::Serial
+        );
+        assert_eq!(
+            ChipConfig::from_arg("0".to_owned(), "127.0.0.1:7000".to_owned()).transport,
+            Transport::Tcp
+        );
+    }
+
+    #[test]
+    fn load_reports_missing_file() {
+        let err = load(Path::new("/nonexistent/uwb.json")).unwrap_err();
+        assert!(err.to_string().contains("/nonexistent/uwb.json"));
+    }
+}
@@ -0,0 +1,236 @@
+//! Framing of the UCI packets exchanged with an emulated UWBS over TCP,
+//! detecting the corruption of the packets between the emulator and the HAL.
+//!
+//! Each UCI packet is sent in a frame made of the 32-bit little-endian
+//! length of the packet, the packet, and the CRC-32 of the packet, also
+//! little-endian. The frames received with an invalid CRC are dropped.
+
+use std::io;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::uci::{UciHeader, UCI_HEADER_SIZE};
+
+const LENGTH_SIZE: usize = 4;
+const CRC_SIZE: usize = 4;
+
+/// Largest UCI packet carried by a frame, with an extended length.
+const MAX_PACKET_SIZE: usize = UCI_HEADER_SIZE + u16::MAX as usize;
+
+/// CRC-32 of `bytes`, as used by Ethernet and zlib.
+pub fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in bytes {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xedb88320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+/// Frame a UCI packet.
+fn encode(packet: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(LENGTH_SIZE + packet.len() + CRC_SIZE);
+    frame.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+    frame.extend_from_slice(packet);
+    frame.extend_from_slice(&crc32(packet).to_le_bytes());
+    frame
+}
+
+/// Connection to the UWBS carrying the UCI packets in frames.
+pub struct CrcFramed<T> {
+    inner: T,
+    /// Bytes received, not yet decoded.
+    received: Vec<u8>,
+    /// UCI packet decoded, not yet read.
+    decoded: Vec<u8>,
+    /// Bytes written, up to the end of the last complete UCI packet.
+    written: Vec<u8>,
+    /// Frames not yet sent.
+    encoded: Vec<u8>,
+}
+
+impl<T> CrcFramed<T> {
+    pub fn new(inner: T) -> Self {
+        CrcFramed {
+            inner,
+            received: Vec::new(),
+            decoded: Vec::new(),
+            written: Vec::new(),
+            encoded: Vec::new(),
+        }
+    }
+
+    /// Decode the next complete frame received. Returns whether a frame
+    /// was complete, and an error if the stream is desynchronized.
+    fn decode(&mut self) -> io::Result<bool> {
+        let Some(length) = self.received.get(..LENGTH_SIZE) else {
+            return Ok(false);
+        };
+        let length = u32::from_le_bytes(length.try_into().unwrap()) as usize;
+        if !(UCI_HEADER_SIZE..=MAX_PACKET_SIZE).contains(&length) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("invalid UCI frame length {}", length),
+            ));
+        }
+        let size = LENGTH_SIZE + length + CRC_SIZE;
+        if self.received.len() < size {
+            return Ok(false);
+        }
+        let frame: Vec<u8> = self.received.drain(..size).collect();
+        let packet = &frame[LENGTH_SIZE..LENGTH_SIZE + length];
+        let crc = u32::from_le_bytes(frame[LENGTH_SIZE + length..].try_into().unwrap());
+        if crc != crc32(packet) {
+            tracing::warn!(length, "dropping corrupted UCI frame");
+        } else {
+            self.decoded = packet.to_vec();
+        }
+        Ok(true)
+    }
+
+    /// Frame the complete UCI packets written.
+    fn encode_written(&mut self) {
+        while let Ok(header) = UciHeader::parse(&self.written) {
+            let length = UCI_HEADER_SIZE + header.payload_length;
+            if self.written.len() < length {
+                break;
+            }
+            let frame = encode(&self.written[..length]);
+            self.encoded.extend_from_slice(&frame);
+            self.written.drain(..length);
+        }
+    }
+}
+
+impl<T: AsyncWrite + Unpin> CrcFramed<T> {
+    /// Send the pending frames.
+    fn poll_send(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while !self.encoded.is_empty() {
+            let len = ready!(Pin::new(&mut self.inner).poll_write(cx, &self.encoded))?;
+            if len == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.encoded.drain(..len);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for CrcFramed<T> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            if !this.decoded.is_empty() {
+                let len = buf.remaining().min(this.decoded.len());
+                buf.put_slice(&this.decoded[..len]);
+                this.decoded.drain(..len);
+                return Poll::Ready(Ok(()));
+            }
+            if this.decode()? {
+                continue;
+            }
+            let mut bytes = [0; 1024];
+            let mut read = ReadBuf::new(&mut bytes);
+            ready!(Pin::new(&mut this.inner).poll_read(cx, &mut read))?;
+            if read.filled().is_empty() {
+                // End of the stream.
+                return Poll::Ready(Ok(()));
+            }
+            this.received.extend_from_slice(read.filled());
+        }
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for CrcFramed<T> {
+    /// The bytes are framed once a complete UCI packet is written, and
+    /// sent by the next write or flush if they cannot be sent immediately.
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        ready!(this.poll_send(cx))?;
+        this.written.extend_from_slice(buf);
+        this.encode_written();
+        if let Poll::Ready(Err(err)) = this.poll_send(cx) {
+            return Poll::Ready(Err(err));
+        }
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_send(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_send(cx))?;
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    const DEVICE_STATUS_READY_NTF: [u8; 5] = [0x60, 0x01, 0x00, 0x01, 0x01];
+    const GET_CAPS_INFO_CMD: [u8; 4] = [0x20, 0x03, 0x00, 0x00];
+
+    #[test]
+    fn crc32_check_value() {
+        assert_eq!(crc32(b"123456789"), 0xcbf43926);
+    }
+
+    #[tokio::test]
+    async fn packets_are_framed() {
+        let (hal, mut uwbs) = tokio::io::duplex(64);
+        let mut hal = CrcFramed::new(hal);
+
+        // The packet is framed once complete.
+        hal.write_all(&GET_CAPS_INFO_CMD[..2]).await.unwrap();
+        hal.write_all(&GET_CAPS_INFO_CMD[2..]).await.unwrap();
+        hal.flush().await.unwrap();
+        let mut frame = [0; 12];
+        uwbs.read_exact(&mut frame).await.unwrap();
+        assert_eq!(frame[..], encode(&GET_CAPS_INFO_CMD));
+
+        uwbs.write_all(&encode(&DEVICE_STATUS_READY_NTF))
+            .await
+            .unwrap();
+        let mut packet = [0; 5];
+        hal.read_exact(&mut packet).await.unwrap();
+        assert_eq!(packet, DEVICE_STATUS_READY_NTF);
+    }
+
+    #[tokio::test]
+    async fn corrupted_frame_is_dropped() {
+        let (hal, mut uwbs) = tokio::io::duplex(64);
+        let mut hal = CrcFramed::new(hal);
+
+        let mut corrupted = encode(&DEVICE_STATUS_READY_NTF);
+        corrupted[LENGTH_SIZE + 4] ^= 0xff;
+        uwbs.write_all(&corrupted).await.unwrap();
+        uwbs.write_all(&encode(&GET_CAPS_INFO_CMD)).await.unwrap();
+        drop(uwbs);
+
+        let mut packets = Vec::new();
+        hal.read_to_end(&mut packets).await.unwrap();
+        assert_eq!(packets, GET_CAPS_INFO_CMD);
+    }
+}
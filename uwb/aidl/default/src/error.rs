@@ -0,0 +1,103 @@
+//! Errors of the HAL, and their mapping to binder statuses.
+
+use android_hardware_uwb::binder::{self, ExceptionCode, StatusCode};
+
+use std::fmt;
+use std::io;
+
+use crate::uci::UciParseError;
+
+#[derive(Debug)]
+pub enum HalError {
+    /// Failed to communicate with the UWBS.
+    IoError(io::Error),
+    /// Invalid UCI message received from the UWBS.
+    ProtocolError(String),
+    /// Invalid UCI message sent by the client.
+    InvalidArgument(String),
+    /// The operation is not allowed in the current state of the chip.
+    IllegalState,
+    /// The UWBS did not respond in time.
+    Timeout,
+    /// The operation needs to write to a chip opened read-only.
+    NotSupported,
+    /// The client exceeded its rate of commands.
+    Busy,
+}
+
+pub type HalResult<T> = Result<T, HalError>;
+
+impl fmt::Display for HalError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HalError::IoError(err) => write!(f, "I/O error: {}", err),
+            HalError::ProtocolError(err) => write!(f, "protocol error: {}", err),
+            HalError::InvalidArgument(err) => write!(f, "invalid argument: {}", err),
+            HalError::IllegalState => write!(f, "illegal state"),
+            HalError::Timeout => write!(f, "timed out"),
+            HalError::NotSupported => write!(f, "not supported"),
+            HalError::Busy => write!(f, "busy"),
+        }
+    }
+}
+
+impl std::error::Error for HalError {}
+
+impl From<io::Error> for HalError {
+    fn from(err: io::Error) -> Self {
+        HalError::IoError(err)
+    }
+}
+
+impl From<UciParseError> for HalError {
+    fn from(err: UciParseError) -> Self {
+        HalError::ProtocolError(err.to_string())
+    }
+}
+
+impl From<HalError> for binder::Status {
+    fn from(err: HalError) -> Self {
+        match err {
+            HalError::IoError(_) => StatusCode::UNKNOWN_ERROR.into(),
+            HalError::ProtocolError(_) => StatusCode::BAD_VALUE.into(),
+            HalError::InvalidArgument(_) => ExceptionCode::ILLEGAL_ARGUMENT.into(),
+            HalError::IllegalState => ExceptionCode::ILLEGAL_STATE.into(),
+            HalError::Timeout => StatusCode::TIMED_OUT.into(),
+            HalError::NotSupported => ExceptionCode::UNSUPPORTED_OPERATION.into(),
+            HalError::Busy => StatusCode::WOULD_BLOCK.into(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn status_mapping() {
+        let status = binder::Status::from(HalError::IllegalState);
+        assert_eq!(status.exception_code(), ExceptionCode::ILLEGAL_STATE);
+
+        let status = binder::Status::from(HalError::InvalidArgument("empty".to_owned()));
+        assert_eq!(status.exception_code(), ExceptionCode::ILLEGAL_ARGUMENT);
+
+        let status = binder::Status::from(HalError::Timeout);
+        assert_eq!(status.transaction_error(), StatusCode::TIMED_OUT);
+
+        let status = binder::Status::from(HalError::NotSupported);
+        assert_eq!(
+            status.exception_code(),
+            ExceptionCode::UNSUPPORTED_OPERATION
+        );
+
+        let status = binder::Status::from(HalError::Busy);
+        assert_eq!(status.transaction_error(), StatusCode::WOULD_BLOCK);
+
+        let status =
+            binder::Status::from(HalError::from(io::Error::from(io::ErrorKind::BrokenPipe)));
+        assert_eq!(status.transaction_error(), StatusCode::UNKNOWN_ERROR);
+
+        let status = binder::Status::from(HalError::from(UciParseError::Truncated(2)));
+        assert_eq!(status.transaction_error(), StatusCode::BAD_VALUE);
+    }
+}
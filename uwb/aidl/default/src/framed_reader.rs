@@ -0,0 +1,325 @@
+//! Framing of the UCI packets read from the UWBS.
+
+use std::io;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
+
+use futures::future::BoxFuture;
+use futures::Stream;
+use std::sync::Arc;
+use tokio::io::{AsyncRead, ReadBuf};
+
+use crate::clock::Clock;
+use crate::error::HalError;
+use crate::uci::{MessageType, UciHeader, UCI_HEADER_SIZE};
+
+/// Absolute maximum size of a UCI packet received from the UWBS.
+/// Control packets are accepted up to this size regardless of the
+/// configured maximum packet size, which is also bounded by it.
+pub const UCI_MAX_PACKET_SIZE: usize = 8192;
+
+/// Number of bytes read from the UWBS at once.
+const READ_SIZE: usize = 1024;
+
+/// UCI packet received from the UWBS.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct UciPacket {
+    pub header: UciHeader,
+    /// Header and payload bytes.
+    pub bytes: Vec<u8>,
+}
+
+/// Parse the UCI packet header at the start of `bytes`, if it has a valid
+/// message type and a length within `UCI_MAX_PACKET_SIZE`.
+fn parse_plausible_header(bytes: &[u8]) -> Option<UciHeader> {
+    UciHeader::parse(bytes)
+        .ok()
+        .filter(|header| UCI_HEADER_SIZE + header.payload_length <= UCI_MAX_PACKET_SIZE)
+}
+
+/// Parse `header` if it is plausible, and the `following` bytes already
+/// received start with a plausible header once its payload is skipped.
+/// The header is accepted if not enough bytes were received to check
+/// the following one.
+fn parse_consistent_header(header: &[u8], following: &[u8]) -> Option<UciHeader> {
+    let parsed = parse_plausible_header(header)?;
+    match following.get(parsed.payload_length..) {
+        Some(next) if next.len() >= UCI_HEADER_SIZE => parse_plausible_header(next).map(|_| parsed),
+        _ => Some(parsed),
+    }
+}
+
+/// Stream of the UCI packets read from the UWBS.
+///
+/// The stream yields an `IoError` when the UWBS fails or is closed, after
+/// which it ends. `Timeout` and `ProtocolError` are yielded for the packets
+/// discarded because they were not received in time or are too large,
+/// and the stream continues with the next packet.
+pub struct UciFramedReader<R> {
+    reader: R,
+    max_packet_size: usize,
+    /// Time allowed for reading a packet, on the clock of the chip.
+    read_timeout: Option<(Duration, Arc<dyn Clock>)>,
+    /// Bytes received from the UWBS, not yet framed.
+    buffer: Vec<u8>,
+    /// Remaining payload bytes of a discarded packet.
+    discard: usize,
+    /// Bytes discarded since the framing was lost, or zero.
+    resync_discarded: usize,
+    /// Expiry of the read timeout of the partially received packet.
+    deadline: Option<BoxFuture<'static, ()>>,
+    done: bool,
+}
+
+impl<R: AsyncRead + Unpin> UciFramedReader<R> {
+    /// Frame the packets read from `reader`. Data packets larger than
+    /// `max_packet_size` are discarded.
+    pub fn new(reader: R, max_packet_size: usize) -> Self {
+        UciFramedReader {
+            reader,
+            max_packet_size,
+            read_timeout: None,
+            buffer: Vec::new(),
+            discard: 0,
+            resync_discarded: 0,
+            deadline: None,
+            done: false,
+        }
+    }
+
+    /// Bound the time allowed for reading the remainder of a UCI packet
+    /// once its first bytes have been received, as measured by `clock`.
+    pub fn with_read_timeout(mut self, read_timeout: Duration, clock: Arc<dyn Clock>) -> Self {
+        self.read_timeout = Some((read_timeout, clock));
+        self
+    }
+
+    /// Skip the bytes preceding the next consistent header after the
+    /// framing was lost, e.g. when the serial link dropped a byte.
+    fn resync(&mut self) {
+        if self.buffer.len() < UCI_HEADER_SIZE
+            || (self.resync_discarded == 0 && parse_plausible_header(&self.buffer).is_some())
+        {
+            return;
+        }
+        let start = if self.resync_discarded == 0 { 1 } else { 0 };
+        let offset = (start..=self.buffer.len() - UCI_HEADER_SIZE).find(|&offset| {
+            parse_consistent_header(
+                &self.buffer[offset..],
+                &self.buffer[offset + UCI_HEADER_SIZE..],
+            )
+            .is_some()
+        });
+        match offset {
+            Some(offset) => {
+                self.buffer.drain(..offset);
+                tracing::warn!(
+                    "resynchronized UCI stream, discarded {} bytes",
+                    self.resync_discarded + offset
+                );
+                self.resync_discarded = 0;
+            }
+            None => {
+                // Keep the bytes which may start the next header.
+                let len = self.buffer.len() - (UCI_HEADER_SIZE - 1);
+                self.buffer.drain(..len);
+                self.resync_discarded += len;
+            }
+        }
+    }
+
+    /// Frame the next packet of the buffer, if completely received.
+    fn next_packet(&mut self) -> Option<Result<UciPacket, HalError>> {
+        if self.discard > 0 {
+            let len = self.discard.min(self.buffer.len());
+            self.buffer.drain(..len);
+            self.discard -= len;
+            if self.discard > 0 {
+                return None;
+            }
+        }
+
+        self.resync();
+        if self.resync_discarded > 0 {
+            return None;
+        }
+        let header = parse_plausible_header(&self.buffer)?;
+
+        let length = UCI_HEADER_SIZE + header.payload_length;
+        let max_packet_size = match header.message_type {
+            MessageType::Data => self.max_packet_size,
+            _ => UCI_MAX_PACKET_SIZE,
+        };
+        if length > max_packet_size {
+            tracing::warn!(
+                "discarding UCI packet of {} bytes, larger than {} bytes",
+                length,
+                max_packet_size
+            );
+            let len = length.min(self.buffer.len());
+            self.buffer.drain(..len);
+            self.discard = length - len;
+            return Some(Err(HalError::ProtocolError(format!(
+                "UCI packet of {} bytes is too large",
+                length
+            ))));
+        }
+
+        if self.buffer.len() < length {
+            return None;
+        }
+        let bytes = self.buffer.drain(..length).collect();
+        Some(Ok(UciPacket { header, bytes }))
+    }
+}
+
+impl<R: AsyncRead + Unpin> Stream for UciFramedReader<R> {
+    type Item = Result<UciPacket, HalError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+        if this.done {
+            return Poll::Ready(None);
+        }
+
+        loop {
+            if let Some(packet) = this.next_packet() {
+                this.deadline = None;
+                return Poll::Ready(Some(packet));
+            }
+
+            // The timeout runs from the first bytes of the packet.
+            if this.buffer.is_empty() && this.discard == 0 {
+                this.deadline = None;
+            } else if let Some((read_timeout, ref clock)) = this.read_timeout {
+                let deadline = this
+                    .deadline
+                    .get_or_insert_with(|| clock.sleep(read_timeout));
+                if deadline.as_mut().poll(cx).is_ready() {
+                    this.deadline = None;
+                    this.buffer.clear();
+                    this.discard = 0;
+                    this.resync_discarded = 0;
+                    return Poll::Ready(Some(Err(HalError::Timeout)));
+                }
+            }
+
+            let mut bytes = [0; READ_SIZE];
+            let mut read_buf = ReadBuf::new(&mut bytes);
+            if let Err(err) = ready!(Pin::new(&mut this.reader).poll_read(cx, &mut read_buf)) {
+                this.done = true;
+                return Poll::Ready(Some(Err(err.into())));
+            }
+            if read_buf.filled().is_empty() {
+                this.done = true;
+                return Poll::Ready(Some(Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "file unexpectedly closed",
+                )
+                .into())));
+            }
+            this.buffer.extend_from_slice(read_buf.filled());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::clock::FakeClock;
+    use futures::StreamExt;
+    use std::io::Cursor;
+    use tokio::io::AsyncWriteExt;
+
+    const DEVICE_STATUS_READY_NTF: [u8; 5] = [0x60, 0x01, 0x00, 0x01, 0x01];
+    const DATA_PACKET: [u8; 6] = [0x00, 0x00, 0x02, 0x00, 0xaa, 0xbb];
+
+    /// Frame all the packets of `bytes`, until the end of the stream.
+    async fn frame(bytes: Vec<u8>, max_packet_size: usize) -> Vec<Result<Vec<u8>, String>> {
+        let mut reader = UciFramedReader::new(Cursor::new(bytes), max_packet_size);
+        let mut packets = Vec::new();
+        while let Some(packet) = reader.next().await {
+            match packet {
+                Ok(packet) => packets.push(Ok(packet.bytes)),
+                Err(HalError::IoError(err)) if err.kind() == io::ErrorKind::UnexpectedEof => (),
+                Err(err) => packets.push(Err(err.to_string())),
+            }
+        }
+        packets
+    }
+
+    #[tokio::test]
+    async fn frames_packets() {
+        let bytes = [&DEVICE_STATUS_READY_NTF[..], &DATA_PACKET[..]].concat();
+        assert_eq!(
+            frame(bytes, UCI_MAX_PACKET_SIZE).await,
+            [
+                Ok(DEVICE_STATUS_READY_NTF.to_vec()),
+                Ok(DATA_PACKET.to_vec())
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn resyncs_after_garbage() {
+        let bytes = [
+            &[0xff, 0xe0][..],
+            &DEVICE_STATUS_READY_NTF[..],
+            &DATA_PACKET[..],
+        ]
+        .concat();
+        assert_eq!(
+            frame(bytes, UCI_MAX_PACKET_SIZE).await,
+            [
+                Ok(DEVICE_STATUS_READY_NTF.to_vec()),
+                Ok(DATA_PACKET.to_vec())
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn discards_large_data_packets() {
+        let bytes = [&DATA_PACKET[..], &DEVICE_STATUS_READY_NTF[..]].concat();
+        let packets = frame(bytes, 5).await;
+        assert_eq!(packets.len(), 2);
+        assert!(packets[0].is_err());
+        assert_eq!(packets[1], Ok(DEVICE_STATUS_READY_NTF.to_vec()));
+    }
+
+    #[tokio::test]
+    async fn partial_packet_times_out() {
+        let clock = FakeClock::default();
+        let (mut uwbs, hal) = tokio::io::duplex(64);
+        let mut reader = UciFramedReader::new(hal, UCI_MAX_PACKET_SIZE)
+            .with_read_timeout(Duration::from_millis(10), Arc::new(clock.clone()));
+
+        // The timeout runs on the clock from the first bytes of the packet.
+        uwbs.write_all(&DEVICE_STATUS_READY_NTF[..2]).await.unwrap();
+        let advance = async {
+            while clock.sleeps() == 0 {
+                tokio::task::yield_now().await;
+            }
+            clock.advance(Duration::from_millis(10));
+        };
+        let (packet, ()) = tokio::join!(reader.next(), advance);
+        assert!(matches!(packet, Some(Err(HalError::Timeout))));
+
+        uwbs.write_all(&DEVICE_STATUS_READY_NTF).await.unwrap();
+        assert_eq!(
+            reader.next().await.unwrap().unwrap().bytes,
+            DEVICE_STATUS_READY_NTF
+        );
+    }
+
+    #[tokio::test]
+    async fn reports_end_of_stream() {
+        let mut reader = UciFramedReader::new(Cursor::new(vec![0x60, 0x01]), UCI_MAX_PACKET_SIZE);
+        assert!(matches!(
+            reader.next().await,
+            Some(Err(HalError::IoError(err))) if err.kind() == io::ErrorKind::UnexpectedEof
+        ));
+        assert!(reader.next().await.is_none());
+    }
+}
@@ -0,0 +1,22 @@
+//! Default implementation of the UWB HAL, served by the IUwb service and
+//! linked by the tools driving the chips directly.
+
+mod capture;
+mod clock;
+pub mod config;
+mod crc_frame;
+pub mod error;
+mod framed_reader;
+pub mod metrics;
+#[cfg(test)]
+mod mock;
+#[cfg(feature = "spi")]
+mod spi;
+#[cfg(feature = "sync-runtime")]
+pub mod sync_runtime;
+pub mod transport;
+pub mod uci;
+pub mod uwb;
+pub mod uwb_chip;
+pub mod uwb_chip_sync;
+pub mod vendor;
@@ -0,0 +1,116 @@
+//! Counters of the UCI traffic exchanged with the UWBS.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use crate::uci::{MessageType, UciHeader};
+
+/// Upper bounds, in milliseconds, of the buckets of the response latency
+/// histogram. The last bucket counts the larger latencies.
+pub const LATENCY_BUCKETS_MS: [u64; 10] = [1, 2, 5, 10, 20, 50, 100, 200, 500, 1000];
+
+const LATENCY_BUCKET_COUNT: usize = LATENCY_BUCKETS_MS.len() + 1;
+
+/// Snapshot of the UCI traffic counters of a chip.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct UwbMetrics {
+    pub commands_sent: u64,
+    pub data_packets_sent: u64,
+    pub bytes_sent: u64,
+    pub responses_received: u64,
+    pub notifications_received: u64,
+    pub data_packets_received: u64,
+    pub bytes_received: u64,
+    /// Failed writes, discarded packets and reader failures.
+    pub errors: u64,
+    /// Responses to the commands awaited by the HAL, by latency bucket.
+    pub response_latency: [u64; LATENCY_BUCKET_COUNT],
+    /// Commands awaited by the HAL that did not get a response in time.
+    pub response_timeouts: u64,
+    /// Reconnections of the reader task after the UWBS failed.
+    pub reader_restarts: u64,
+}
+
+/// Counters updated without locking from the binder threads
+/// and the reader and writer tasks.
+#[derive(Default)]
+pub struct Metrics {
+    commands_sent: AtomicU64,
+    data_packets_sent: AtomicU64,
+    bytes_sent: AtomicU64,
+    responses_received: AtomicU64,
+    notifications_received: AtomicU64,
+    data_packets_received: AtomicU64,
+    bytes_received: AtomicU64,
+    errors: AtomicU64,
+    response_latency: [AtomicU64; LATENCY_BUCKET_COUNT],
+    response_timeouts: AtomicU64,
+    reader_restarts: AtomicU64,
+}
+
+impl Metrics {
+    /// Count a UCI packet sent to the UWBS.
+    pub fn record_sent(&self, packet: &[u8]) {
+        self.bytes_sent
+            .fetch_add(packet.len() as u64, Ordering::Relaxed);
+        let counter = match UciHeader::parse(packet).map(|header| header.message_type) {
+            Ok(MessageType::Command) => &self.commands_sent,
+            Ok(MessageType::Data) => &self.data_packets_sent,
+            _ => return,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a UCI packet received from the UWBS.
+    pub fn record_received(&self, header: &UciHeader, packet: &[u8]) {
+        self.bytes_received
+            .fetch_add(packet.len() as u64, Ordering::Relaxed);
+        let counter = match header.message_type {
+            MessageType::Response => &self.responses_received,
+            MessageType::Notification => &self.notifications_received,
+            MessageType::Data => &self.data_packets_received,
+            MessageType::Command => return,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_error(&self) {
+        self.errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a response received `latency` after its command was sent.
+    pub fn record_response_latency(&self, latency: Duration) {
+        let bucket = LATENCY_BUCKETS_MS
+            .iter()
+            .position(|&bound| latency < Duration::from_millis(bound))
+            .unwrap_or(LATENCY_BUCKETS_MS.len());
+        self.response_latency[bucket].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_response_timeout(&self) {
+        self.response_timeouts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_reader_restart(&self) {
+        self.reader_restarts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> UwbMetrics {
+        UwbMetrics {
+            commands_sent: self.commands_sent.load(Ordering::Relaxed),
+            data_packets_sent: self.data_packets_sent.load(Ordering::Relaxed),
+            bytes_sent: self.bytes_sent.load(Ordering::Relaxed),
+            responses_received: self.responses_received.load(Ordering::Relaxed),
+            notifications_received: self.notifications_received.load(Ordering::Relaxed),
+            data_packets_received: self.data_packets_received.load(Ordering::Relaxed),
+            bytes_received: self.bytes_received.load(Ordering::Relaxed),
+            errors: self.errors.load(Ordering::Relaxed),
+            response_latency: self
+                .response_latency
+                .each_ref()
+                .map(|counter| counter.load(Ordering::Relaxed)),
+            response_timeouts: self.response_timeouts.load(Ordering::Relaxed),
+            reader_restarts: self.reader_restarts.load(Ordering::Relaxed),
+        }
+    }
+}
@@ -0,0 +1,296 @@
+//! In-memory UWBS and client used to test the HAL without hardware.
+
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Wake, Waker};
+use std::thread;
+
+use android_hardware_uwb::aidl::android::hardware::uwb::{
+    IUwbClientCallback::{BnUwbClientCallback, IUwbClientCallback},
+    UwbEvent::UwbEvent,
+    UwbStatus::UwbStatus,
+};
+use android_hardware_uwb::binder::{self, Strong};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::transport::Transport;
+use crate::uci::{MessageType, UciHeader, UCI_HEADER_SIZE};
+
+#[derive(Default)]
+struct Inner {
+    /// Canned responses, keyed by command group and opcode.
+    responses: HashMap<(u8, u8), Vec<u8>>,
+    /// All bytes written by the HAL.
+    written: Vec<u8>,
+    /// Offset of the first command in `written` not yet answered.
+    parsed: usize,
+    /// Bytes waiting to be read by the HAL.
+    pending: VecDeque<u8>,
+    /// Reader waiting for `pending` bytes.
+    waker: Option<Waker>,
+    /// Set to fail all writes by the HAL.
+    fail_writes: bool,
+    /// Set to fail all reads by the HAL.
+    fail_reads: bool,
+    /// Set to fail the next read by the HAL.
+    fail_next_read: bool,
+    /// Set when the UWBS has closed the connection.
+    closed: bool,
+}
+
+impl Inner {
+    fn wake(&mut self) {
+        if let Some(waker) = self.waker.take() {
+            waker.wake();
+        }
+    }
+
+    fn push(&mut self, bytes: &[u8]) {
+        self.pending.extend(bytes);
+        self.wake();
+    }
+
+    /// Read the pending bytes, or return None if the read must wait
+    /// for the UWBS.
+    fn read(&mut self, buf: &mut [u8]) -> Option<io::Result<usize>> {
+        if self.fail_reads || std::mem::take(&mut self.fail_next_read) {
+            return Some(Err(io::ErrorKind::BrokenPipe.into()));
+        }
+        if self.pending.is_empty() {
+            return self.closed.then_some(Ok(0));
+        }
+        let len = buf.len().min(self.pending.len());
+        for (byte, pending) in buf.iter_mut().zip(self.pending.drain(..len)) {
+            *byte = pending;
+        }
+        Some(Ok(len))
+    }
+
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.fail_writes {
+            return Err(io::ErrorKind::BrokenPipe.into());
+        }
+        self.written.extend_from_slice(buf);
+        self.respond();
+        Ok(buf.len())
+    }
+
+    /// Answer the complete commands written since the last call.
+    fn respond(&mut self) {
+        while let Ok(header) = UciHeader::parse(&self.written[self.parsed..]) {
+            let length = UCI_HEADER_SIZE + header.payload_length;
+            if self.written.len() < self.parsed + length {
+                break;
+            }
+            self.parsed += length;
+            if header.message_type != MessageType::Command {
+                continue;
+            }
+            if let Some(response) = self
+                .responses
+                .get(&(header.group_id, header.opcode))
+                .cloned()
+            {
+                self.push(&response);
+            }
+        }
+    }
+}
+
+/// UWBS answering the commands written by the HAL with canned responses.
+/// Clones share the same state, so that the test can inspect the
+/// connection handed over to the chip.
+#[derive(Clone, Default)]
+pub struct MockUwbs(Arc<Mutex<Inner>>);
+
+impl std::fmt::Debug for MockUwbs {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("MockUwbs")
+    }
+}
+
+impl MockUwbs {
+    /// Reply with `response` to the commands of the selected group and opcode.
+    pub fn with_response(self, group_id: u8, opcode: u8, response: &[u8]) -> Self {
+        self.0
+            .lock()
+            .unwrap()
+            .responses
+            .insert((group_id, opcode), response.to_vec());
+        self
+    }
+
+    /// Fail the subsequent writes by the HAL, as if the device was gone.
+    pub fn fail_writes(&self) {
+        self.0.lock().unwrap().fail_writes = true;
+    }
+
+    /// Send an unsolicited message to the HAL, e.g. a notification.
+    pub fn notify(&self, message: &[u8]) {
+        self.0.lock().unwrap().push(message);
+    }
+
+    /// Fail the subsequent reads by the HAL, as if the device had an
+    /// I/O error.
+    pub fn fail_reads(&self) {
+        let mut inner = self.0.lock().unwrap();
+        inner.fail_reads = true;
+        inner.wake();
+    }
+
+    /// Fail the next read by the HAL, as if the device had a transient
+    /// I/O error.
+    pub fn fail_next_read(&self) {
+        let mut inner = self.0.lock().unwrap();
+        inner.fail_next_read = true;
+        inner.wake();
+    }
+
+    /// Close the connection once the pending bytes have been read,
+    /// as if the device was powered down.
+    pub fn close(&self) {
+        let mut inner = self.0.lock().unwrap();
+        inner.closed = true;
+        inner.wake();
+    }
+
+    /// Bytes written by the HAL so far.
+    pub fn written(&self) -> Vec<u8> {
+        self.0.lock().unwrap().written.clone()
+    }
+}
+
+impl AsyncRead for MockUwbs {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let mut inner = self.0.lock().unwrap();
+        match inner.read(buf.initialize_unfilled()) {
+            Some(Ok(len)) => {
+                buf.advance(len);
+                Poll::Ready(Ok(()))
+            }
+            Some(Err(err)) => Poll::Ready(Err(err)),
+            None => {
+                inner.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl AsyncWrite for MockUwbs {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Poll::Ready(self.0.lock().unwrap().write(buf))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Waker of a thread blocked reading from the UWBS.
+struct ThreadWaker(thread::Thread);
+
+impl Wake for ThreadWaker {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
+}
+
+/// Blocking reads, for the threads reading the UWBS without a runtime.
+impl io::Read for MockUwbs {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let mut inner = self.0.lock().unwrap();
+            if let Some(result) = inner.read(buf) {
+                return result;
+            }
+            inner.waker = Some(Arc::new(ThreadWaker(thread::current())).into());
+            drop(inner);
+            thread::park();
+        }
+    }
+}
+
+impl io::Write for MockUwbs {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Connection injected by a test, handed over to the first connection
+/// attempt of the chip.
+#[derive(Clone)]
+pub struct InjectedTransport(Arc<Mutex<Option<Box<dyn Transport>>>>);
+
+impl std::fmt::Debug for InjectedTransport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("InjectedTransport")
+    }
+}
+
+impl InjectedTransport {
+    pub fn new(transport: impl Transport + 'static) -> Self {
+        InjectedTransport(Arc::new(Mutex::new(Some(Box::new(transport)))))
+    }
+
+    pub fn take(&self) -> io::Result<Box<dyn Transport>> {
+        self.0
+            .lock()
+            .unwrap()
+            .take()
+            .ok_or_else(|| io::ErrorKind::NotConnected.into())
+    }
+}
+
+/// Client recording the UCI messages and HAL events sent by the chip.
+#[derive(Clone, Default)]
+pub struct TestClient {
+    messages: Arc<Mutex<Vec<Vec<u8>>>>,
+    events: Arc<Mutex<Vec<(UwbEvent, UwbStatus)>>>,
+}
+
+impl binder::Interface for TestClient {}
+
+impl IUwbClientCallback for TestClient {
+    fn onUciMessage(&self, data: &[u8]) -> binder::Result<()> {
+        self.messages.lock().unwrap().push(data.to_vec());
+        Ok(())
+    }
+
+    fn onHalEvent(&self, event: UwbEvent, status: UwbStatus) -> binder::Result<()> {
+        self.events.lock().unwrap().push((event, status));
+        Ok(())
+    }
+}
+
+impl TestClient {
+    pub fn callbacks(&self) -> Strong<dyn IUwbClientCallback> {
+        BnUwbClientCallback::new_binder(self.clone(), binder::BinderFeatures::default())
+    }
+
+    pub fn messages(&self) -> Vec<Vec<u8>> {
+        self.messages.lock().unwrap().clone()
+    }
+
+    pub fn events(&self) -> Vec<(UwbEvent, UwbStatus)> {
+        self.events.lock().unwrap().clone()
+    }
+}
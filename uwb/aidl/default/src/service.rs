@@ -1,15 +1,160 @@
 use android_hardware_uwb::aidl::android::hardware::uwb::IUwb::{self, IUwb as _};
 use android_hardware_uwb::binder;
 
+use rustutils::system_properties;
 use tokio::runtime::Runtime;
+use tokio::signal::unix::{signal, SignalKind};
 
 use std::env;
+use std::fmt;
+use std::os::unix::net::UnixStream;
 use std::panic;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
 
 use log::LevelFilter;
 
-mod uwb;
-mod uwb_chip;
+use uwb_default_hal::{config, transport, uwb, uwb_chip};
+
+/// Optional timeout, in milliseconds, for reading the remainder of
+/// a UCI packet once its first bytes have been received.
+const READ_TIMEOUT_PROPERTY: &str = "ro.vendor.uwb.read_timeout_ms";
+
+/// Optional delay, in milliseconds, without UCI traffic after which the
+/// clients of an opened chip are warned, and whether the chip is then
+/// also closed.
+const IDLE_TIMEOUT_PROPERTY: &str = "ro.vendor.uwb.idle_timeout_ms";
+const IDLE_CLOSE_PROPERTY: &str = "ro.vendor.uwb.idle_close";
+
+/// Optional maximum delay, in milliseconds, between two attempts to
+/// reconnect to a failed UWBS.
+const RECONNECT_MAX_BACKOFF_PROPERTY: &str = "ro.vendor.uwb.reconnect_max_backoff_ms";
+
+/// Optional number of times the commands of the HAL are sent to the UWBS
+/// before failing, and time allowed in milliseconds for each attempt.
+const COMMAND_ATTEMPTS_PROPERTY: &str = "ro.vendor.uwb.command_attempts";
+const COMMAND_TIMEOUT_PROPERTY: &str = "ro.vendor.uwb.command_timeout_ms";
+
+/// Optional number of times the UWBS of an opened chip is connected again
+/// after it failed, before the clients are notified.
+const RESTART_BUDGET_PROPERTY: &str = "ro.vendor.uwb.restart_budget";
+
+/// Optional number of notifications sent by a reconnected UWBS, e.g. once
+/// rebooted, which are retained until the chip is opened again and
+/// replayed to the next client.
+const PRE_OPEN_REPLAY_PROPERTY: &str = "ro.vendor.uwb.pre_open_replay";
+
+/// Optional rate of the commands allowed per second for each client, and
+/// number of commands allowed at once, the rate by default. The commands
+/// exceeding the rate are delayed if the wait property is set, and
+/// rejected otherwise.
+const COMMAND_RATE_PROPERTY: &str = "ro.vendor.uwb.command_rate";
+const COMMAND_BURST_PROPERTY: &str = "ro.vendor.uwb.command_burst";
+const COMMAND_RATE_WAIT_PROPERTY: &str = "ro.vendor.uwb.command_rate_wait";
+
+/// Optional maximum size, in bytes, of the UCI data packets received
+/// from the UWBS.
+const MAX_PACKET_SIZE_PROPERTY: &str = "ro.vendor.uwb.max_packet_size";
+
+/// Optional maximum payload size, in bytes, of the UCI data packets
+/// written to the UWBS, and whether the larger data packets sent by the
+/// clients are segmented rather than rejected.
+const MAX_DATA_PAYLOAD_SIZE_PROPERTY: &str = "ro.vendor.uwb.max_data_payload_size";
+const SEGMENT_DATA_PROPERTY: &str = "ro.vendor.uwb.segment_data";
+
+/// Probe the devices with CORE_GET_DEVICE_INFO_CMD when opened.
+const PROBE_PROPERTY: &str = "ro.vendor.uwb.probe";
+
+/// Open the devices read-only, to sniff the UCI packets exchanged
+/// by the UWBS with another host.
+const READ_ONLY_PROPERTY: &str = "ro.vendor.uwb.read_only";
+
+/// Optional directory where the UCI packets exchanged with each chip
+/// are captured, to the file `uwb<index>.pcapng`.
+const CAPTURE_DIR_PROPERTY: &str = "vendor.uwb.capture_dir";
+
+/// Optional directory of the unix sockets `uwb<index>.sock`, opened by
+/// a sniffer, to which the UCI packets exchanged with each chip are copied.
+const TEE_DIR_PROPERTY: &str = "vendor.uwb.tee_dir";
+
+/// Argument selecting the configuration file listing the chips,
+/// in place of the device paths.
+const CONFIG_ARG: &str = "--config";
+
+/// Read and parse an optional system property.
+fn read_property<T: FromStr>(property: &str) -> Option<T>
+where
+    T::Err: fmt::Display,
+{
+    let value = system_properties::read(property).ok().flatten()?;
+    match value.parse() {
+        Ok(value) => Some(value),
+        Err(err) => {
+            tracing::warn!(property, value, %err, "invalid property value");
+            None
+        }
+    }
+}
+
+/// Read a duration in milliseconds from a system property.
+fn read_duration_property(property: &str) -> Option<Duration> {
+    read_property(property).map(Duration::from_millis)
+}
+
+/// Return the configuration of the chips, either listed in the
+/// configuration file or given as arguments.
+fn chip_configs() -> anyhow::Result<Vec<config::ChipConfig>> {
+    let args: Vec<String> = env::args().skip(1).collect(); // Skip binary name
+    if let [arg, path] = &args[..] {
+        if arg == CONFIG_ARG {
+            return config::load(&PathBuf::from(path));
+        }
+    }
+    // Chips given as arguments are named after their index.
+    Ok(args
+        .into_iter()
+        .enumerate()
+        .map(|(i, arg)| config::ChipConfig::from_arg(i.to_string(), arg))
+        .collect())
+}
+
+/// Register the IUwb service hosting all the chips. Each chip runs
+/// its own reader task on the runtime of `handle`.
+fn register_all(
+    chips: impl IntoIterator<Item = Arc<uwb_chip::UwbChip>>,
+    handle: tokio::runtime::Handle,
+) -> binder::Result<()> {
+    binder::add_service(
+        &format!("{}/default", IUwb::BpUwb::get_descriptor()),
+        IUwb::BnUwb::new_binder(
+            uwb::Uwb::from_chips(chips, handle),
+            binder::BinderFeatures::default(),
+        )
+        .as_binder(),
+    )?;
+    Ok(())
+}
+
+/// Shut the chips down once init terminates the service, e.g. when the
+/// device shuts down, letting their reader tasks deliver the UCI
+/// messages already received.
+async fn shutdown_on_terminate(chips: Vec<Arc<uwb_chip::UwbChip>>) {
+    let mut terminate = match signal(SignalKind::terminate()) {
+        Ok(terminate) => terminate,
+        Err(err) => {
+            tracing::warn!(%err, "failed to handle SIGTERM");
+            return;
+        }
+    };
+    terminate.recv().await;
+    tracing::info!("UWB HAL shutting down");
+    for chip in chips.iter() {
+        chip.shutdown().await;
+    }
+    std::process::exit(0);
+}
 
 fn main() -> anyhow::Result<()> {
     logger::init(
@@ -20,27 +165,110 @@ fn main() -> anyhow::Result<()> {
 
     // Redirect panic messages to logcat.
     panic::set_hook(Box::new(|panic_info| {
-        log::error!("{}", panic_info);
+        tracing::error!("{}", panic_info);
     }));
 
-    log::info!("UWB HAL starting up");
+    tracing::info!("UWB HAL starting up");
 
     // Create the tokio runtime
     let rt = Runtime::new()?;
 
-    let chips = env::args()
-        .skip(1) // Skip binary name
-        .enumerate()
-        .map(|(i, arg)| uwb_chip::UwbChip::new(i.to_string(), arg));
+    let read_timeout = read_duration_property(READ_TIMEOUT_PROPERTY);
+    let idle_timeout = read_duration_property(IDLE_TIMEOUT_PROPERTY);
+    let idle_close = system_properties::read_bool(IDLE_CLOSE_PROPERTY, false).unwrap_or(false);
+    let reconnect_max_backoff = read_duration_property(RECONNECT_MAX_BACKOFF_PROPERTY);
+    let max_packet_size = read_property(MAX_PACKET_SIZE_PROPERTY);
+    let max_data_payload_size = read_property(MAX_DATA_PAYLOAD_SIZE_PROPERTY);
+    let segment_data = system_properties::read_bool(SEGMENT_DATA_PROPERTY, false).unwrap_or(false);
+    let restart_budget = read_property(RESTART_BUDGET_PROPERTY).unwrap_or(0);
+    let pre_open_replay = read_property(PRE_OPEN_REPLAY_PROPERTY).unwrap_or(0);
+    let rate_limit =
+        read_property(COMMAND_RATE_PROPERTY).map(|commands_per_second| uwb_chip::RateLimit {
+            commands_per_second,
+            burst: read_property(COMMAND_BURST_PROPERTY).unwrap_or(commands_per_second),
+            policy: if system_properties::read_bool(COMMAND_RATE_WAIT_PROPERTY, false)
+                .unwrap_or(false)
+            {
+                uwb_chip::RateLimitPolicy::Wait
+            } else {
+                uwb_chip::RateLimitPolicy::Reject
+            },
+        });
+    let default_retry = uwb_chip::CommandRetry::default();
+    let command_retry = uwb_chip::CommandRetry {
+        attempts: read_property(COMMAND_ATTEMPTS_PROPERTY).unwrap_or(default_retry.attempts),
+        timeout: read_duration_property(COMMAND_TIMEOUT_PROPERTY).unwrap_or(default_retry.timeout),
+    };
+    let probe = system_properties::read_bool(PROBE_PROPERTY, false).unwrap_or(false);
+    let open_mode = if system_properties::read_bool(READ_ONLY_PROPERTY, false).unwrap_or(false) {
+        transport::OpenMode::ReadOnly
+    } else {
+        transport::OpenMode::ReadWrite
+    };
+    let capture_dir = system_properties::read(CAPTURE_DIR_PROPERTY).ok().flatten();
+    let tee_dir = system_properties::read(TEE_DIR_PROPERTY).ok().flatten();
+    let chips = chip_configs()?
+        .into_iter()
+        .map(config::ChipConfig::into_chip)
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let chips = chips.into_iter().enumerate().map(|(i, chip)| {
+        let chip = chip
+            .with_probe(probe)
+            .with_open_mode(open_mode)
+            .with_command_retry(command_retry)
+            .with_restart_budget(restart_budget)
+            .with_pre_open_replay(pre_open_replay)
+            .with_idle_close(idle_close)
+            .with_data_segmentation(segment_data);
+        let chip = match max_packet_size {
+            Some(max_packet_size) => chip.with_max_packet_size(max_packet_size),
+            None => chip,
+        };
+        let chip = match max_data_payload_size {
+            Some(max_data_payload_size) => chip.with_max_data_payload_size(max_data_payload_size),
+            None => chip,
+        };
+        let chip = match rate_limit {
+            Some(rate_limit) => chip.with_rate_limit(rate_limit),
+            None => chip,
+        };
+        let chip = match read_timeout {
+            Some(read_timeout) => chip.with_read_timeout(read_timeout),
+            None => chip,
+        };
+        let chip = match idle_timeout {
+            Some(idle_timeout) => chip.with_idle_timeout(idle_timeout),
+            None => chip,
+        };
+        let chip = match reconnect_max_backoff {
+            Some(max) => chip.with_reconnect_backoff(transport::Backoff {
+                max,
+                ..Default::default()
+            }),
+            None => chip,
+        };
+        let chip = match capture_dir {
+            Some(ref capture_dir) => {
+                chip.with_capture(Path::new(capture_dir).join(format!("uwb{}.pcapng", i)))
+            }
+            None => chip,
+        };
+        let Some(ref tee_dir) = tee_dir else {
+            return chip;
+        };
+        let path = Path::new(tee_dir).join(format!("uwb{}.sock", i));
+        match UnixStream::connect(&path) {
+            Ok(sink) => chip.with_tee(sink),
+            Err(err) => {
+                tracing::warn!(path = %path.display(), %err, "failed to connect to UCI tee");
+                chip
+            }
+        }
+    });
+    let chips: Vec<_> = chips.map(Arc::new).collect();
 
-    binder::add_service(
-        &format!("{}/default", IUwb::BpUwb::get_descriptor()),
-        IUwb::BnUwb::new_binder(
-            uwb::Uwb::from_chips(chips, rt.handle().clone()),
-            binder::BinderFeatures::default(),
-        )
-        .as_binder(),
-    )?;
+    rt.spawn(shutdown_on_terminate(chips.clone()));
+    register_all(chips, rt.handle().clone())?;
 
     binder::ProcessState::join_thread_pool();
     Ok(())
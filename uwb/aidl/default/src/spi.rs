@@ -0,0 +1,208 @@
+//! Transport to a UWBS connected over SPI, enabled with the `spi` feature.
+//!
+//! The UWBS asserts its IRQ gpio while it has a UCI packet to send. The
+//! host then reads the packet header in a first transfer to learn the
+//! payload length, and the payload in a second transfer, keeping the chip
+//! select asserted in between. The UCI packets written by the HAL are sent
+//! in a single transfer each.
+//!
+//! The blocking spidev transfers are run by two threads, bridged to the
+//! chip by a socket pair. The threads exit once the chip drops its end
+//! of the socket pair.
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::net::Shutdown;
+use std::os::fd::AsRawFd;
+use std::os::unix::net::UnixStream;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use crate::uci::{UciHeader, UCI_HEADER_SIZE};
+
+/// Argument of SPI_IOC_MESSAGE, `struct spi_ioc_transfer` of
+/// `<linux/spi/spidev.h>`.
+#[repr(C)]
+#[derive(Default)]
+struct SpiIocTransfer {
+    tx_buf: u64,
+    rx_buf: u64,
+    len: u32,
+    speed_hz: u32,
+    delay_usecs: u16,
+    bits_per_word: u8,
+    cs_change: u8,
+    tx_nbits: u8,
+    rx_nbits: u8,
+    word_delay_usecs: u8,
+    pad: u8,
+}
+
+nix::ioctl_write_buf!(spi_ioc_message, b'k', 0, SpiIocTransfer);
+
+/// Run a single half-duplex transfer on the spidev `file`.
+fn transfer(file: &File, transfer: SpiIocTransfer) -> io::Result<()> {
+    // SAFETY: the buffers of the transfer are valid for `len` bytes,
+    // and outlive the ioctl.
+    unsafe { spi_ioc_message(file.as_raw_fd(), &[transfer]) }?;
+    Ok(())
+}
+
+/// Read `rx.len()` bytes. The chip select is kept asserted after the
+/// transfer if `keep_cs` is set.
+fn read(file: &File, rx: &mut [u8], keep_cs: bool) -> io::Result<()> {
+    transfer(
+        file,
+        SpiIocTransfer {
+            rx_buf: rx.as_mut_ptr() as u64,
+            len: rx.len() as u32,
+            cs_change: keep_cs as u8,
+            ..Default::default()
+        },
+    )
+}
+
+fn write(file: &File, tx: &[u8]) -> io::Result<()> {
+    transfer(
+        file,
+        SpiIocTransfer {
+            tx_buf: tx.as_ptr() as u64,
+            len: tx.len() as u32,
+            ..Default::default()
+        },
+    )
+}
+
+/// Spidev connected to the UWBS, shared by the reading and writing threads.
+struct Spidev(Mutex<File>);
+
+impl Spidev {
+    /// Read a UCI packet: its header first, then its payload.
+    fn read_packet(&self) -> io::Result<Vec<u8>> {
+        let file = self.0.lock().unwrap();
+        let mut packet = vec![0; UCI_HEADER_SIZE];
+        read(&file, &mut packet, true)?;
+        let header = UciHeader::parse(&packet)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err.to_string()))?;
+        packet.resize(UCI_HEADER_SIZE + header.payload_length, 0);
+        if header.payload_length > 0 {
+            read(&file, &mut packet[UCI_HEADER_SIZE..], false)?;
+        }
+        Ok(packet)
+    }
+
+    fn write_packet(&self, packet: &[u8]) -> io::Result<()> {
+        write(&self.0.lock().unwrap(), packet)
+    }
+}
+
+/// IRQ gpio of the UWBS, asserted low. The gpio must be exported to sysfs
+/// with edge detection enabled, e.g. by the init script of the board.
+struct Irq(File);
+
+impl Irq {
+    fn open(gpio: u32) -> io::Result<Self> {
+        File::open(format!("/sys/class/gpio/gpio{}/value", gpio)).map(Irq)
+    }
+
+    fn is_asserted(&mut self) -> io::Result<bool> {
+        let mut value = [0; 1];
+        self.0.seek(SeekFrom::Start(0))?;
+        self.0.read_exact(&mut value)?;
+        Ok(value[0] == b'0')
+    }
+
+    /// Wait for an edge of the gpio. Returns false if `socket` is closed
+    /// by the peer first.
+    fn wait(&self, socket: &UnixStream) -> io::Result<bool> {
+        let mut fds = [
+            libc::pollfd {
+                fd: self.0.as_raw_fd(),
+                events: libc::POLLPRI | libc::POLLERR,
+                revents: 0,
+            },
+            libc::pollfd {
+                fd: socket.as_raw_fd(),
+                events: libc::POLLRDHUP,
+                revents: 0,
+            },
+        ];
+        // SAFETY: `fds` is a valid array of two pollfd.
+        if unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, -1) } < 0 {
+            let err = io::Error::last_os_error();
+            return match err.kind() {
+                io::ErrorKind::Interrupted => Ok(true),
+                _ => Err(err),
+            };
+        }
+        Ok(fds[1].revents & (libc::POLLRDHUP | libc::POLLHUP) == 0)
+    }
+}
+
+/// Forward the UCI packets read from the UWBS to the socket.
+fn read_packets(spidev: &Spidev, mut irq: Irq, mut socket: &UnixStream) -> io::Result<()> {
+    loop {
+        // The level is checked after each wakeup, as the edge of a packet
+        // may have been consumed while reading the previous one.
+        if irq.is_asserted()? {
+            socket.write_all(&spidev.read_packet()?)?;
+        } else if !irq.wait(socket)? {
+            return Ok(());
+        }
+    }
+}
+
+/// Write the UCI packets received from the socket to the UWBS.
+fn write_packets(spidev: &Spidev, mut socket: &UnixStream) -> io::Result<()> {
+    let mut buffer = Vec::new();
+    let mut bytes = [0; 1024];
+    loop {
+        let len = socket.read(&mut bytes)?;
+        if len == 0 {
+            return Ok(());
+        }
+        buffer.extend_from_slice(&bytes[..len]);
+        while let Ok(header) = UciHeader::parse(&buffer) {
+            let length = UCI_HEADER_SIZE + header.payload_length;
+            if buffer.len() < length {
+                break;
+            }
+            spidev.write_packet(&buffer[..length])?;
+            buffer.drain(..length);
+        }
+    }
+}
+
+/// Open the spidev at `path`, with the IRQ signaled on the sysfs gpio
+/// `irq_gpio`. Returns the end of the socket pair connected to the UWBS.
+/// The socket pair is shut down if a transfer fails, so that the chip
+/// sees the end of the stream.
+pub fn open(path: &str, irq_gpio: u32) -> io::Result<tokio::net::UnixStream> {
+    let spidev = Arc::new(Spidev(Mutex::new(
+        OpenOptions::new().read(true).write(true).open(path)?,
+    )));
+    let irq = Irq::open(irq_gpio)?;
+    let (host, device) = UnixStream::pair()?;
+
+    let reader_spidev = spidev.clone();
+    let reader_socket = device.try_clone()?;
+    thread::Builder::new()
+        .name("uwb-spi-rx".to_owned())
+        .spawn(move || {
+            if let Err(err) = read_packets(&reader_spidev, irq, &reader_socket) {
+                tracing::error!(%err, "failed to read from the SPI UWBS");
+                let _ = reader_socket.shutdown(Shutdown::Both);
+            }
+        })?;
+    thread::Builder::new()
+        .name("uwb-spi-tx".to_owned())
+        .spawn(move || {
+            if let Err(err) = write_packets(&spidev, &device) {
+                tracing::error!(%err, "failed to write to the SPI UWBS");
+                let _ = device.shutdown(Shutdown::Both);
+            }
+        })?;
+
+    host.set_nonblocking(true)?;
+    tokio::net::UnixStream::from_std(host)
+}
@@ -0,0 +1,244 @@
+//! Blocking UWB chip, enabled with the `sync-runtime` feature.
+//!
+//! The chip reads the UWBS from a background thread with blocking
+//! `std::io` reads, and writes it from the calling thread. It shares the
+//! UCI framing and reassembly of the `uci` module with the tokio chip,
+//! and is meant for the tests and tools which do not run a tokio runtime.
+//! The IUwbChip service keeps using the tokio chip.
+
+use android_hardware_uwb::aidl::android::hardware::uwb::{
+    IUwbClientCallback::IUwbClientCallback, UwbEvent::UwbEvent, UwbStatus::UwbStatus,
+};
+use android_hardware_uwb::binder::Strong;
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::os::unix::net::UnixStream;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use uwb_uci_packets::ResetConfig;
+
+use crate::error::{HalError, HalResult};
+use crate::transport::makeraw;
+use crate::uci::{self, MessageType, Reassembler, UciHeader, UCI_HEADER_SIZE};
+
+/// Time allowed for the UWBS to respond to the device reset on close.
+const RESET_TIMEOUT: Duration = Duration::from_millis(1000);
+
+/// Connection to the UWBS, read by the reader thread while the calling
+/// thread writes to it.
+pub trait SyncTransport: Read + Write + Send + 'static {
+    /// Return another handle to the same connection.
+    fn try_clone_transport(&self) -> io::Result<Box<dyn SyncTransport>>;
+}
+
+impl SyncTransport for File {
+    fn try_clone_transport(&self) -> io::Result<Box<dyn SyncTransport>> {
+        Ok(Box::new(self.try_clone()?))
+    }
+}
+
+impl SyncTransport for UnixStream {
+    fn try_clone_transport(&self) -> io::Result<Box<dyn SyncTransport>> {
+        Ok(Box::new(self.try_clone()?))
+    }
+}
+
+#[cfg(test)]
+impl SyncTransport for crate::mock::MockUwbs {
+    fn try_clone_transport(&self) -> io::Result<Box<dyn SyncTransport>> {
+        Ok(Box::new(self.clone()))
+    }
+}
+
+/// Open the serial device at `path` in blocking mode.
+pub fn open_serial(path: &str) -> io::Result<File> {
+    let file = OpenOptions::new().read(true).write(true).open(path)?;
+    makeraw(&file)?;
+    Ok(file)
+}
+
+/// Read a UCI packet: its header first, then its payload.
+fn read_packet(reader: &mut dyn SyncTransport) -> io::Result<(UciHeader, Vec<u8>)> {
+    let mut packet = vec![0; UCI_HEADER_SIZE];
+    reader.read_exact(&mut packet)?;
+    let header = UciHeader::parse(&packet)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err.to_string()))?;
+    packet.resize(UCI_HEADER_SIZE + header.payload_length, 0);
+    reader.read_exact(&mut packet[UCI_HEADER_SIZE..])?;
+    Ok((header, packet))
+}
+
+/// Deliver the UCI messages read from the UWBS to the client, until
+/// the device reset response once `closing` is set.
+fn read_uci_packets(
+    reader: &mut dyn SyncTransport,
+    callbacks: &Strong<dyn IUwbClientCallback>,
+    closing: &AtomicBool,
+) -> io::Result<()> {
+    let mut reassembler = Reassembler::default();
+    loop {
+        let (header, packet) = read_packet(reader)?;
+        let Some(message) = reassembler.push(header, packet) else {
+            continue;
+        };
+        if closing.load(Ordering::Relaxed)
+            && header.is_control(
+                MessageType::Response,
+                uci::GID_CORE,
+                uci::OID_CORE_DEVICE_RESET,
+            )
+        {
+            return Ok(());
+        }
+        if let Err(err) = callbacks.onUciMessage(&message) {
+            tracing::warn!(?err, "failed to deliver UCI message");
+        }
+    }
+}
+
+/// Opened UWB chip, delivering the UCI messages to a single client.
+pub struct BlockingChip {
+    writer: Mutex<Box<dyn SyncTransport>>,
+    callbacks: Strong<dyn IUwbClientCallback>,
+    /// Set by close(), for the reader thread to exit once the device
+    /// is reset.
+    closing: Arc<AtomicBool>,
+    /// Result of the reader thread, sent when it exits.
+    reader: mpsc::Receiver<io::Result<()>>,
+}
+
+impl BlockingChip {
+    /// Start reading from the UWBS of `transport`, and notify the client
+    /// with OPEN_CPLT.
+    pub fn open(
+        transport: Box<dyn SyncTransport>,
+        callbacks: Strong<dyn IUwbClientCallback>,
+    ) -> io::Result<Self> {
+        let mut reader = transport.try_clone_transport()?;
+        let closing = Arc::new(AtomicBool::new(false));
+        let (result, receiver) = mpsc::channel();
+
+        let reader_callbacks = callbacks.clone();
+        let reader_closing = closing.clone();
+        thread::Builder::new()
+            .name("uwb-reader".to_owned())
+            .spawn(move || {
+                let read = read_uci_packets(&mut *reader, &reader_callbacks, &reader_closing);
+                if let Err(ref err) = read {
+                    tracing::error!(%err, "failed to read from the UWBS");
+                    if let Err(err) =
+                        reader_callbacks.onHalEvent(UwbEvent::ERROR, UwbStatus::FAILED)
+                    {
+                        tracing::warn!(?err, "failed to notify HAL error");
+                    }
+                }
+                let _ = result.send(read);
+            })?;
+
+        if let Err(err) = callbacks.onHalEvent(UwbEvent::OPEN_CPLT, UwbStatus::OK) {
+            tracing::warn!(?err, "failed to notify HAL open");
+        }
+        Ok(BlockingChip {
+            writer: Mutex::new(transport),
+            callbacks,
+            closing,
+            reader: receiver,
+        })
+    }
+
+    /// Send a UCI message to the UWBS. Returns the number of bytes written.
+    pub fn send(&self, data: &[u8]) -> HalResult<i32> {
+        // A malformed packet would desynchronize the UWBS.
+        uci::parse_packet(data).map_err(|err| HalError::InvalidArgument(err.to_string()))?;
+        self.writer.lock().unwrap().write_all(data)?;
+        Ok(data.len() as i32)
+    }
+
+    /// Reset the device, and notify the client with CLOSE_CPLT once the
+    /// reader thread has exited. The reader thread is left running until
+    /// the connection is closed if the UWBS does not respond to the reset.
+    pub fn close(self) {
+        self.closing.store(true, Ordering::Relaxed);
+        let reset = uci::UciCommandBuilder::core_device_reset(ResetConfig::UwbsReset).concat();
+        let mut status = UwbStatus::OK;
+        if let Err(err) = self.writer.lock().unwrap().write_all(&reset) {
+            tracing::error!(%err, "failed to write UCI Device Reset command");
+            status = UwbStatus::FAILED;
+        } else {
+            match self.reader.recv_timeout(RESET_TIMEOUT) {
+                Ok(Ok(())) => (),
+                Ok(Err(_)) => {
+                    tracing::warn!("UCI reader thread exited before the device reset response")
+                }
+                Err(_) => tracing::warn!("timed out waiting for the device reset response"),
+            }
+        }
+        if let Err(err) = self.callbacks.onHalEvent(UwbEvent::CLOSE_CPLT, status) {
+            tracing::warn!(?err, "failed to notify HAL close");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{MockUwbs, TestClient};
+    use std::time::Instant;
+
+    /// Wait for the chip to meet a condition.
+    fn wait_until(condition: impl Fn() -> bool) {
+        let deadline = Instant::now() + Duration::from_secs(1);
+        while !condition() {
+            assert!(Instant::now() < deadline, "timed out waiting for the chip");
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn open_send_close() {
+        const GET_CAPS_INFO_CMD: [u8; 4] = [0x20, 0x03, 0x00, 0x00];
+        const GET_CAPS_INFO_RSP: [u8; 6] = [0x40, 0x03, 0x00, 0x02, 0x00, 0x00];
+        const DEVICE_RESET_RSP: [u8; 5] = [0x40, 0x00, 0x00, 0x01, 0x00];
+        let uwbs = MockUwbs::default()
+            .with_response(
+                uci::GID_CORE,
+                uci::OID_CORE_GET_CAPS_INFO,
+                &GET_CAPS_INFO_RSP,
+            )
+            .with_response(uci::GID_CORE, uci::OID_CORE_DEVICE_RESET, &DEVICE_RESET_RSP);
+        let client = TestClient::default();
+
+        let chip = BlockingChip::open(Box::new(uwbs.clone()), client.callbacks()).unwrap();
+        assert_eq!(client.events(), [(UwbEvent::OPEN_CPLT, UwbStatus::OK)]);
+
+        assert_eq!(chip.send(&GET_CAPS_INFO_CMD).unwrap(), 4);
+        wait_until(|| client.messages() == [GET_CAPS_INFO_RSP]);
+        assert!(chip.send(&GET_CAPS_INFO_CMD[..3]).is_err());
+
+        chip.close();
+        assert_eq!(
+            client.events().last(),
+            Some(&(UwbEvent::CLOSE_CPLT, UwbStatus::OK))
+        );
+        // The device reset response is not delivered to the client.
+        assert_eq!(client.messages(), [GET_CAPS_INFO_RSP]);
+    }
+
+    #[test]
+    fn read_error_notifies_client() {
+        let uwbs = MockUwbs::default();
+        let client = TestClient::default();
+
+        let _chip = BlockingChip::open(Box::new(uwbs.clone()), client.callbacks()).unwrap();
+        uwbs.fail_reads();
+        wait_until(|| {
+            client
+                .events()
+                .contains(&(UwbEvent::ERROR, UwbStatus::FAILED))
+        });
+    }
+}
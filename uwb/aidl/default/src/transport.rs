@@ -0,0 +1,325 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, IsTerminal, Read, Write};
+use std::net::SocketAddr;
+use std::os::unix::fs::OpenOptionsExt;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
+
+use tokio::io::unix::AsyncFd;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+use tokio::select;
+use tokio_util::sync::CancellationToken;
+
+use crate::clock::Clock;
+use crate::crc_frame::CrcFramed;
+
+/// Byte stream connecting the HAL to the UWBS.
+pub trait Transport: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<T: AsyncRead + AsyncWrite + Send + Unpin> Transport for T {}
+
+/// Exponential backoff between reconnection attempts.
+#[derive(Clone, Copy, Debug)]
+pub struct Backoff {
+    /// Delay before the first attempt, doubled after each failure.
+    pub initial: Duration,
+    /// Maximum delay between two attempts.
+    pub max: Duration,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Backoff {
+            initial: Duration::from_millis(100),
+            max: Duration::from_secs(10),
+        }
+    }
+}
+
+/// Access to the UWBS requested when connecting.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OpenMode {
+    #[default]
+    ReadWrite,
+    /// Only read the UCI packets sent by the UWBS, e.g. to sniff a bus
+    /// shared with another host without ever writing to it.
+    ReadOnly,
+}
+
+/// Connection attempt to the UWBS.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Attempt {
+    /// First connection of the chip: all failures are fatal.
+    First,
+    /// Connection of a chip which was connected before. Serial devices
+    /// which cannot be configured are used in their current mode, as the
+    /// termios operations transiently fail on some USB-serial adapters.
+    Reconnect,
+}
+
+/// Location of the UWBS.
+#[derive(Clone, Debug)]
+pub enum TransportConfig {
+    /// Path to a serial device.
+    Serial(String),
+    /// Address of a TCP socket, e.g. exposed by an emulated UWBS.
+    Tcp(SocketAddr),
+    /// Address of a TCP socket exchanging the UCI packets in frames
+    /// checked with a CRC.
+    CrcTcp(SocketAddr),
+    /// Path to a spidev, and sysfs number of the IRQ gpio of the UWBS.
+    #[cfg(feature = "spi")]
+    Spi { path: String, irq_gpio: u32 },
+    /// In-memory UWBS used by the unit tests.
+    #[cfg(test)]
+    Mock(crate::mock::MockUwbs),
+    /// Connection established by the unit tests, e.g. one end of
+    /// a socket pair.
+    #[cfg(test)]
+    Injected(crate::mock::InjectedTransport),
+}
+
+/// Kind of connection to the UWBS.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TransportKind {
+    Serial,
+    Tcp,
+    #[cfg(feature = "spi")]
+    Spi,
+    #[cfg(test)]
+    Test,
+}
+
+/// Transport of a chip, reported to debug the HAL.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TransportInfo {
+    pub kind: TransportKind,
+    /// Path of the device, or address of the socket.
+    pub location: String,
+    /// Whether the chip is currently reading from the UWBS.
+    pub connected: bool,
+}
+
+impl TransportConfig {
+    pub fn kind(&self) -> TransportKind {
+        match self {
+            TransportConfig::Serial(_) => TransportKind::Serial,
+            TransportConfig::Tcp(_) | TransportConfig::CrcTcp(_) => TransportKind::Tcp,
+            #[cfg(feature = "spi")]
+            TransportConfig::Spi { .. } => TransportKind::Spi,
+            #[cfg(test)]
+            TransportConfig::Mock(_) | TransportConfig::Injected(_) => TransportKind::Test,
+        }
+    }
+
+    /// Path of the device, or address of the socket.
+    pub fn location(&self) -> String {
+        match self {
+            TransportConfig::Serial(path) => path.clone(),
+            TransportConfig::Tcp(addr) | TransportConfig::CrcTcp(addr) => addr.to_string(),
+            #[cfg(feature = "spi")]
+            TransportConfig::Spi { path, .. } => path.clone(),
+            #[cfg(test)]
+            TransportConfig::Mock(_) => "mock".to_owned(),
+            #[cfg(test)]
+            TransportConfig::Injected(_) => "injected".to_owned(),
+        }
+    }
+
+    /// Open a new connection to the UWBS. Only serial devices are opened
+    /// read-only with `OpenMode::ReadOnly`, the other connections are never
+    /// written to by the chip in this mode.
+    pub async fn connect(
+        &self,
+        mode: OpenMode,
+        attempt: Attempt,
+    ) -> io::Result<Box<dyn Transport>> {
+        match self {
+            TransportConfig::Serial(path) => Ok(Box::new(Serial::open(path, mode, attempt)?)),
+            TransportConfig::Tcp(addr) => Ok(Box::new(TcpStream::connect(addr).await?)),
+            TransportConfig::CrcTcp(addr) => {
+                Ok(Box::new(CrcFramed::new(TcpStream::connect(addr).await?)))
+            }
+            #[cfg(feature = "spi")]
+            TransportConfig::Spi { path, irq_gpio } => {
+                Ok(Box::new(crate::spi::open(path, *irq_gpio)?))
+            }
+            #[cfg(test)]
+            TransportConfig::Mock(uwbs) => Ok(Box::new(uwbs.clone())),
+            #[cfg(test)]
+            TransportConfig::Injected(transport) => transport.take(),
+        }
+    }
+
+    /// Retry connecting to the UWBS until it succeeds, waiting between
+    /// attempts on `clock` as configured by `backoff`. Returns None if the
+    /// token is cancelled first.
+    pub async fn reconnect(
+        &self,
+        mode: OpenMode,
+        backoff: Backoff,
+        token: &CancellationToken,
+        clock: &dyn Clock,
+    ) -> Option<Box<dyn Transport>> {
+        let mut delay = backoff.initial;
+        loop {
+            select! {
+                _ = token.cancelled() => return None,
+                _ = clock.sleep(delay) => (),
+            }
+            match self.connect(mode, Attempt::Reconnect).await {
+                Ok(transport) => return Some(transport),
+                Err(err) => tracing::debug!(transport = ?self, %err, "failed to reconnect"),
+            }
+            delay = (delay * 2).min(backoff.max);
+        }
+    }
+}
+
+pub fn makeraw(file: &File) -> io::Result<()> {
+    // Only terminals can be configured, other file types such as
+    // pipes or sockets are used unchanged.
+    if !file.is_terminal() {
+        return Ok(());
+    }
+
+    // Configure the file descriptor as raw fd.
+    use nix::sys::termios::*;
+    let mut attrs = tcgetattr(file)?;
+    cfmakeraw(&mut attrs);
+    tcsetattr(file, SetArg::TCSANOW, &attrs)?;
+
+    Ok(())
+}
+
+/// Serial device configured in raw, non-blocking mode.
+pub struct Serial(AsyncFd<File>);
+
+impl Serial {
+    pub fn open(path: &str, mode: OpenMode, attempt: Attempt) -> io::Result<Self> {
+        Self::open_with(path, mode, attempt, makeraw)
+    }
+
+    /// Open the serial device, configured by `makeraw`.
+    fn open_with(
+        path: &str,
+        mode: OpenMode,
+        attempt: Attempt,
+        makeraw: impl FnOnce(&File) -> io::Result<()>,
+    ) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(mode == OpenMode::ReadWrite)
+            .create(false)
+            .custom_flags(libc::O_NONBLOCK)
+            .open(path)?;
+        if let Err(err) = makeraw(&file) {
+            if attempt == Attempt::First {
+                return Err(err);
+            }
+            tracing::warn!(path, %err, "failed to configure raw mode, keeping the device mode");
+        }
+        Ok(Serial(AsyncFd::new(file)?))
+    }
+}
+
+// On some platforms, the readiness detecting mechanism relies on
+// edge-triggered notifications. This means that the OS will only notify
+// Tokio when the file descriptor transitions from not-ready to ready.
+// try_io() takes care of clearing the readiness when the operation
+// fails with std::io::ErrorKind::WouldBlock.
+impl AsyncRead for Serial {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            let mut guard = ready!(this.0.poll_read_ready_mut(cx))?;
+            let unfilled = buf.initialize_unfilled();
+            match guard.try_io(|inner| inner.get_mut().read(unfilled)) {
+                Ok(Ok(read_len)) => {
+                    buf.advance(read_len);
+                    return Poll::Ready(Ok(()));
+                }
+                Ok(Err(err)) => return Poll::Ready(Err(err)),
+                Err(_would_block) => continue,
+            }
+        }
+    }
+}
+
+impl AsyncWrite for Serial {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        loop {
+            let mut guard = ready!(this.0.poll_write_ready_mut(cx))?;
+            match guard.try_io(|inner| inner.get_mut().write(buf)) {
+                Ok(result) => return Poll::Ready(result),
+                Err(_would_block) => continue,
+            }
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::cell::Cell;
+
+    /// Pseudo-terminal master, which can be opened without a device.
+    const PTMX: &str = "/dev/ptmx";
+
+    #[test]
+    fn makeraw_ignores_non_terminals() {
+        let path = std::env::temp_dir().join(format!("uwb-makeraw-{}", std::process::id()));
+        let file = File::create(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(makeraw(&file).is_ok());
+
+        let (socket, _peer) = std::os::unix::net::UnixStream::pair().unwrap();
+        assert!(makeraw(&File::from(std::os::fd::OwnedFd::from(socket))).is_ok());
+    }
+
+    #[tokio::test]
+    async fn reconnect_tolerates_termios_errors() {
+        // The termios step fails on the second call.
+        let calls = Cell::new(0);
+        let makeraw = |_: &File| {
+            calls.set(calls.get() + 1);
+            match calls.get() {
+                2 => Err(io::Error::from_raw_os_error(libc::EIO)),
+                _ => Ok(()),
+            }
+        };
+
+        assert!(Serial::open_with(PTMX, OpenMode::ReadWrite, Attempt::First, makeraw).is_ok());
+        assert!(Serial::open_with(PTMX, OpenMode::ReadWrite, Attempt::Reconnect, makeraw).is_ok());
+        assert_eq!(calls.get(), 2);
+    }
+
+    #[tokio::test]
+    async fn first_open_fails_on_termios_errors() {
+        let makeraw = |_: &File| Err(io::Error::from_raw_os_error(libc::EIO));
+        let err = Serial::open_with(PTMX, OpenMode::ReadWrite, Attempt::First, makeraw)
+            .err()
+            .unwrap();
+        assert_eq!(err.raw_os_error(), Some(libc::EIO));
+    }
+}
@@ -0,0 +1,1003 @@
+//! UCI packet framing, as defined by the FiRa UCI Generic Specification.
+
+use std::collections::HashMap;
+use std::fmt;
+
+use pdl_runtime::Packet;
+use uwb_uci_packets::{
+    AppConfigTlv, AppConfigTlvType, DeviceResetCmdBuilder, GetCapsInfoCmdBuilder,
+    GetDeviceInfoCmdBuilder, ResetConfig, SessionDeinitCmdBuilder, SessionSetAppConfigCmdBuilder,
+    UciControlPacket, UciControlPacketHal,
+};
+#[cfg(test)]
+use uwb_uci_packets::{SessionInitCmdBuilder, SessionType};
+
+pub const UCI_HEADER_SIZE: usize = 4;
+
+const MESSAGE_TYPE_SHIFT: u8 = 5;
+const MESSAGE_TYPE_COMMAND: u8 = 1;
+const PBF_MASK: u8 = 0x10;
+const GID_MASK: u8 = 0x0f;
+const OID_MASK: u8 = 0x3f;
+const EXTENDED_LENGTH_MASK: u8 = 0x80;
+
+pub const GID_CORE: u8 = 0x00;
+pub const GID_SESSION_CONFIG: u8 = 0x01;
+pub const GID_SESSION_CONTROL: u8 = 0x02;
+#[cfg(any(test, feature = "sync-runtime"))]
+pub const OID_CORE_DEVICE_RESET: u8 = 0x00;
+pub const OID_CORE_DEVICE_STATUS: u8 = 0x01;
+pub const OID_CORE_GET_DEVICE_INFO: u8 = 0x02;
+pub const OID_CORE_GET_CAPS_INFO: u8 = 0x03;
+pub const OID_CORE_GENERIC_ERROR: u8 = 0x07;
+pub const OID_SESSION_DEINIT: u8 = 0x01;
+pub const OID_SESSION_STATUS: u8 = 0x02;
+pub const OID_SESSION_DATA_CREDIT: u8 = 0x04;
+pub const OID_SESSION_DATA_TRANSFER_STATUS: u8 = 0x05;
+pub const OID_SESSION_SET_APP_CONFIG: u8 = 0x03;
+
+pub const STATUS_OK: u8 = 0x00;
+pub const STATUS_FAILED: u8 = 0x02;
+pub const STATUS_UNKNOWN: u8 = 0x0b;
+
+/// Device state reported in CORE_DEVICE_STATUS_NTF when the UWBS has
+/// failed, e.g. before it is restarted by its watchdog.
+pub const DEVICE_STATE_ERROR: u8 = 0xff;
+
+/// Session states reported in SESSION_STATUS_NTF.
+pub const SESSION_STATE_DEINIT: u8 = 0x01;
+pub const SESSION_STATE_ACTIVE: u8 = 0x02;
+pub const SESSION_STATE_ERROR: u8 = 0xff;
+
+/// Tags of the capability TLVs of CORE_GET_CAPS_INFO_RSP.
+pub const CAP_RANGING_METHOD: u8 = 0x03;
+pub const CAP_CHANNELS: u8 = 0x0b;
+
+/// Names of the control messages of the FiRa UCI Generic Specification,
+/// by message type, group identifier and opcode.
+const MESSAGE_NAMES: &[(MessageType, u8, u8, &str)] = {
+    use MessageType::{Command as CMD, Notification as NTF, Response as RSP};
+    &[
+        (CMD, GID_CORE, 0x00, "CORE_DEVICE_RESET_CMD"),
+        (RSP, GID_CORE, 0x00, "CORE_DEVICE_RESET_RSP"),
+        (NTF, GID_CORE, 0x01, "CORE_DEVICE_STATUS_NTF"),
+        (CMD, GID_CORE, 0x02, "CORE_GET_DEVICE_INFO_CMD"),
+        (RSP, GID_CORE, 0x02, "CORE_GET_DEVICE_INFO_RSP"),
+        (CMD, GID_CORE, 0x03, "CORE_GET_CAPS_INFO_CMD"),
+        (RSP, GID_CORE, 0x03, "CORE_GET_CAPS_INFO_RSP"),
+        (CMD, GID_CORE, 0x04, "CORE_SET_CONFIG_CMD"),
+        (RSP, GID_CORE, 0x04, "CORE_SET_CONFIG_RSP"),
+        (CMD, GID_CORE, 0x05, "CORE_GET_CONFIG_CMD"),
+        (RSP, GID_CORE, 0x05, "CORE_GET_CONFIG_RSP"),
+        (NTF, GID_CORE, 0x07, "CORE_GENERIC_ERROR_NTF"),
+        (CMD, GID_CORE, 0x08, "CORE_QUERY_UWBS_TIMESTAMP_CMD"),
+        (RSP, GID_CORE, 0x08, "CORE_QUERY_UWBS_TIMESTAMP_RSP"),
+        (CMD, GID_SESSION_CONFIG, 0x00, "SESSION_INIT_CMD"),
+        (RSP, GID_SESSION_CONFIG, 0x00, "SESSION_INIT_RSP"),
+        (CMD, GID_SESSION_CONFIG, 0x01, "SESSION_DEINIT_CMD"),
+        (RSP, GID_SESSION_CONFIG, 0x01, "SESSION_DEINIT_RSP"),
+        (NTF, GID_SESSION_CONFIG, 0x02, "SESSION_STATUS_NTF"),
+        (CMD, GID_SESSION_CONFIG, 0x03, "SESSION_SET_APP_CONFIG_CMD"),
+        (RSP, GID_SESSION_CONFIG, 0x03, "SESSION_SET_APP_CONFIG_RSP"),
+        (CMD, GID_SESSION_CONFIG, 0x04, "SESSION_GET_APP_CONFIG_CMD"),
+        (RSP, GID_SESSION_CONFIG, 0x04, "SESSION_GET_APP_CONFIG_RSP"),
+        (CMD, GID_SESSION_CONFIG, 0x05, "SESSION_GET_COUNT_CMD"),
+        (RSP, GID_SESSION_CONFIG, 0x05, "SESSION_GET_COUNT_RSP"),
+        (CMD, GID_SESSION_CONFIG, 0x06, "SESSION_GET_STATE_CMD"),
+        (RSP, GID_SESSION_CONFIG, 0x06, "SESSION_GET_STATE_RSP"),
+        (
+            CMD,
+            GID_SESSION_CONFIG,
+            0x07,
+            "SESSION_UPDATE_CONTROLLER_MULTICAST_LIST_CMD",
+        ),
+        (
+            RSP,
+            GID_SESSION_CONFIG,
+            0x07,
+            "SESSION_UPDATE_CONTROLLER_MULTICAST_LIST_RSP",
+        ),
+        (
+            NTF,
+            GID_SESSION_CONFIG,
+            0x07,
+            "SESSION_UPDATE_CONTROLLER_MULTICAST_LIST_NTF",
+        ),
+        (
+            CMD,
+            GID_SESSION_CONFIG,
+            0x08,
+            "SESSION_UPDATE_DT_ANCHOR_RANGING_ROUNDS_CMD",
+        ),
+        (
+            RSP,
+            GID_SESSION_CONFIG,
+            0x08,
+            "SESSION_UPDATE_DT_ANCHOR_RANGING_ROUNDS_RSP",
+        ),
+        (
+            CMD,
+            GID_SESSION_CONFIG,
+            0x09,
+            "SESSION_UPDATE_DT_TAG_RANGING_ROUNDS_CMD",
+        ),
+        (
+            RSP,
+            GID_SESSION_CONFIG,
+            0x09,
+            "SESSION_UPDATE_DT_TAG_RANGING_ROUNDS_RSP",
+        ),
+        (
+            CMD,
+            GID_SESSION_CONFIG,
+            0x0b,
+            "SESSION_QUERY_DATA_SIZE_IN_RANGING_CMD",
+        ),
+        (
+            RSP,
+            GID_SESSION_CONFIG,
+            0x0b,
+            "SESSION_QUERY_DATA_SIZE_IN_RANGING_RSP",
+        ),
+        (CMD, GID_SESSION_CONTROL, 0x00, "SESSION_START_CMD"),
+        (RSP, GID_SESSION_CONTROL, 0x00, "SESSION_START_RSP"),
+        (NTF, GID_SESSION_CONTROL, 0x00, "SESSION_INFO_NTF"),
+        (CMD, GID_SESSION_CONTROL, 0x01, "SESSION_STOP_CMD"),
+        (RSP, GID_SESSION_CONTROL, 0x01, "SESSION_STOP_RSP"),
+        (
+            CMD,
+            GID_SESSION_CONTROL,
+            0x03,
+            "SESSION_GET_RANGING_COUNT_CMD",
+        ),
+        (
+            RSP,
+            GID_SESSION_CONTROL,
+            0x03,
+            "SESSION_GET_RANGING_COUNT_RSP",
+        ),
+        (NTF, GID_SESSION_CONTROL, 0x04, "SESSION_DATA_CREDIT_NTF"),
+        (
+            NTF,
+            GID_SESSION_CONTROL,
+            0x05,
+            "SESSION_DATA_TRANSFER_STATUS_NTF",
+        ),
+    ]
+};
+
+/// Channels of the bits of the CAP_CHANNELS bitmask, from bit 0.
+const CHANNELS: [u8; 8] = [5, 6, 8, 9, 10, 12, 13, 14];
+
+/// UCI message type, encoded in the MT field of the packet header.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum MessageType {
+    Data,
+    Command,
+    Response,
+    Notification,
+}
+
+impl TryFrom<u8> for MessageType {
+    type Error = UciParseError;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(MessageType::Data),
+            MESSAGE_TYPE_COMMAND => Ok(MessageType::Command),
+            2 => Ok(MessageType::Response),
+            3 => Ok(MessageType::Notification),
+            _ => Err(UciParseError::InvalidMessageType(value)),
+        }
+    }
+}
+
+/// Error returned when a UCI packet header cannot be parsed.
+#[derive(Debug, PartialEq, Eq)]
+pub enum UciParseError {
+    /// Fewer than `UCI_HEADER_SIZE` bytes were provided.
+    Truncated(usize),
+    /// The MT field holds a reserved value.
+    InvalidMessageType(u8),
+    /// The payload length field does not match the size of the packet.
+    LengthMismatch { declared: usize, actual: usize },
+}
+
+impl fmt::Display for UciParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UciParseError::Truncated(len) => {
+                write!(f, "truncated UCI header ({len} of {UCI_HEADER_SIZE} bytes)")
+            }
+            UciParseError::InvalidMessageType(mt) => {
+                write!(f, "invalid UCI message type {mt:#x}")
+            }
+            UciParseError::LengthMismatch { declared, actual } => {
+                write!(
+                    f,
+                    "UCI payload length {declared} does not match the {actual} payload bytes"
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for UciParseError {}
+
+/// Common header of UCI data and control packets.
+///
+/// The header is decoded here rather than with the generated pdl packets,
+/// which only parse complete packets: the reader needs the payload length
+/// from the first bytes received to frame the packets of the stream.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct UciHeader {
+    pub message_type: MessageType,
+    /// Packet Boundary Flag, set on all segments of a message but the last.
+    pub pbf: bool,
+    /// Group identifier of control packets, data packet format of data packets.
+    pub group_id: u8,
+    /// Opcode identifier of control packets, always zero for data packets.
+    pub opcode: u8,
+    pub payload_length: usize,
+}
+
+impl UciHeader {
+    /// Parse the header at the start of `bytes`.
+    /// Data packets and extended control packets use a 16-bit little-endian
+    /// length field, other control packets a single byte.
+    pub fn parse(bytes: &[u8]) -> Result<Self, UciParseError> {
+        if bytes.len() < UCI_HEADER_SIZE {
+            return Err(UciParseError::Truncated(bytes.len()));
+        }
+
+        let message_type = MessageType::try_from(bytes[0] >> MESSAGE_TYPE_SHIFT)?;
+        let pbf = bytes[0] & PBF_MASK != 0;
+        let group_id = bytes[0] & GID_MASK;
+
+        let (opcode, payload_length) = match message_type {
+            MessageType::Data => (0, u16::from_le_bytes([bytes[2], bytes[3]]) as usize),
+            _ if bytes[1] & EXTENDED_LENGTH_MASK != 0 => (
+                bytes[1] & OID_MASK,
+                u16::from_le_bytes([bytes[2], bytes[3]]) as usize,
+            ),
+            _ => (bytes[1] & OID_MASK, bytes[3] as usize),
+        };
+
+        Ok(UciHeader {
+            message_type,
+            pbf,
+            group_id,
+            opcode,
+            payload_length,
+        })
+    }
+
+    /// Name of the message, for the logs.
+    pub fn name(&self) -> MessageName {
+        MessageName(*self)
+    }
+
+    /// Return true if this is the header of the control message
+    /// of the selected type, group and opcode.
+    pub fn is_control(&self, message_type: MessageType, group_id: u8, opcode: u8) -> bool {
+        self.message_type == message_type && self.group_id == group_id && self.opcode == opcode
+    }
+}
+
+/// Name of a UCI message, formatted from the standard names, or with
+/// the hex group identifier and opcode of the other control messages.
+pub struct MessageName(UciHeader);
+
+impl fmt::Display for MessageName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let header = &self.0;
+        if header.message_type == MessageType::Data {
+            return write!(f, "DATA");
+        }
+        let name = MESSAGE_NAMES
+            .iter()
+            .find(|(message_type, group_id, opcode, _)| {
+                header.is_control(*message_type, *group_id, *opcode)
+            });
+        match name {
+            Some((_, _, _, name)) => write!(f, "{name}"),
+            None => write!(
+                f,
+                "{:?}(gid={:#04x}, oid={:#04x})",
+                header.message_type, header.group_id, header.opcode
+            ),
+        }
+    }
+}
+
+/// Parse the header of a single UCI packet, and check that its payload
+/// length matches the size of `packet`.
+pub fn parse_packet(packet: &[u8]) -> Result<UciHeader, UciParseError> {
+    let header = UciHeader::parse(packet)?;
+    let actual = packet.len() - UCI_HEADER_SIZE;
+    if header.payload_length != actual {
+        return Err(UciParseError::LengthMismatch {
+            declared: header.payload_length,
+            actual,
+        });
+    }
+    Ok(header)
+}
+
+/// Rewrite the payload length of a UCI packet header.
+/// Control packets switch to the extended length encoding when the
+/// payload does not fit in a single byte.
+fn set_payload_length(header: &mut [u8], length: usize) {
+    if header[0] >> MESSAGE_TYPE_SHIFT == 0 {
+        header[2..4].copy_from_slice(&(length as u16).to_le_bytes());
+    } else if length > u8::MAX as usize {
+        header[1] |= EXTENDED_LENGTH_MASK;
+        header[2..4].copy_from_slice(&(length as u16).to_le_bytes());
+    } else {
+        header[3] = length as u8;
+    }
+}
+
+/// Return the session handle of a UCI data packet,
+/// or None for control packets.
+pub fn data_packet_session_handle(packet: &[u8]) -> Option<u32> {
+    let header = UciHeader::parse(packet).ok()?;
+    if header.message_type != MessageType::Data || packet.len() < UCI_HEADER_SIZE + 4 {
+        return None;
+    }
+    let handle = &packet[UCI_HEADER_SIZE..UCI_HEADER_SIZE + 4];
+    Some(u32::from_le_bytes(handle.try_into().unwrap()))
+}
+
+/// Split a UCI data packet into segments carrying at most
+/// `max_payload_size` bytes of its payload each, chained with the packet
+/// boundary flag. The last segment keeps the flag of `packet`.
+pub fn segment_data_packet(packet: &[u8], max_payload_size: usize) -> Vec<Vec<u8>> {
+    let (header, payload) = packet.split_at(UCI_HEADER_SIZE);
+    let chunks: Vec<&[u8]> = payload.chunks(max_payload_size).collect();
+    chunks
+        .iter()
+        .enumerate()
+        .map(|(i, chunk)| {
+            let mut segment = header.to_vec();
+            if i + 1 < chunks.len() {
+                segment[0] |= PBF_MASK;
+            }
+            set_payload_length(&mut segment, chunk.len());
+            segment.extend_from_slice(chunk);
+            segment
+        })
+        .collect()
+}
+
+/// Parse the session identifier of a SESSION_DEINIT_CMD.
+pub fn parse_session_deinit_cmd(message: &[u8]) -> Option<u32> {
+    let header = UciHeader::parse(message).ok()?;
+    if !header.is_control(MessageType::Command, GID_SESSION_CONFIG, OID_SESSION_DEINIT)
+        || message.len() < UCI_HEADER_SIZE + 4
+    {
+        return None;
+    }
+    let payload = &message[UCI_HEADER_SIZE..];
+    Some(u32::from_le_bytes(payload[0..4].try_into().unwrap()))
+}
+
+/// Return the status of a control response message.
+pub fn response_status(message: &[u8]) -> Option<u8> {
+    let header = UciHeader::parse(message).ok()?;
+    if header.message_type != MessageType::Response {
+        return None;
+    }
+    message.get(UCI_HEADER_SIZE).copied()
+}
+
+/// Parse the device state reported by a CORE_DEVICE_STATUS_NTF.
+pub fn parse_device_status_ntf(message: &[u8]) -> Option<u8> {
+    let header = UciHeader::parse(message).ok()?;
+    if !header.is_control(MessageType::Notification, GID_CORE, OID_CORE_DEVICE_STATUS) {
+        return None;
+    }
+    message.get(UCI_HEADER_SIZE).copied()
+}
+
+/// Parse the status reported by a CORE_GENERIC_ERROR_NTF.
+pub fn parse_generic_error_ntf(message: &[u8]) -> Option<u8> {
+    let header = UciHeader::parse(message).ok()?;
+    if !header.is_control(MessageType::Notification, GID_CORE, OID_CORE_GENERIC_ERROR) {
+        return None;
+    }
+    message.get(UCI_HEADER_SIZE).copied()
+}
+
+/// Parse a DATA_CREDIT_NTF into the session handle and credit availability.
+pub fn parse_data_credit_ntf(message: &[u8]) -> Option<(u32, bool)> {
+    let header = UciHeader::parse(message).ok()?;
+    if !header.is_control(
+        MessageType::Notification,
+        GID_SESSION_CONTROL,
+        OID_SESSION_DATA_CREDIT,
+    ) || message.len() < UCI_HEADER_SIZE + 5
+    {
+        return None;
+    }
+    let payload = &message[UCI_HEADER_SIZE..];
+    let handle = u32::from_le_bytes(payload[0..4].try_into().unwrap());
+    Some((handle, payload[4] != 0))
+}
+
+/// Parse a SESSION_STATUS_NTF into the session handle and state.
+pub fn parse_session_status_ntf(message: &[u8]) -> Option<(u32, u8)> {
+    let header = UciHeader::parse(message).ok()?;
+    if !header.is_control(
+        MessageType::Notification,
+        GID_SESSION_CONFIG,
+        OID_SESSION_STATUS,
+    ) || message.len() < UCI_HEADER_SIZE + 5
+    {
+        return None;
+    }
+    let payload = &message[UCI_HEADER_SIZE..];
+    let handle = u32::from_le_bytes(payload[0..4].try_into().unwrap());
+    Some((handle, payload[4]))
+}
+
+/// Parse a SESSION_DATA_TRANSFER_STATUS_NTF into the session handle
+/// and transfer status.
+pub fn parse_data_transfer_status_ntf(message: &[u8]) -> Option<(u32, u8)> {
+    let header = UciHeader::parse(message).ok()?;
+    if !header.is_control(
+        MessageType::Notification,
+        GID_SESSION_CONTROL,
+        OID_SESSION_DATA_TRANSFER_STATUS,
+    ) || message.len() < UCI_HEADER_SIZE + 6
+    {
+        return None;
+    }
+    // Session handle, UCI sequence number, then status.
+    let payload = &message[UCI_HEADER_SIZE..];
+    let handle = u32::from_le_bytes(payload[0..4].try_into().unwrap());
+    Some((handle, payload[5]))
+}
+
+/// Version number reported in CORE_GET_DEVICE_INFO_RSP.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Version {
+    pub major: u8,
+    pub minor: u8,
+    pub maintenance: u8,
+}
+
+impl Version {
+    fn parse(bytes: [u8; 2]) -> Self {
+        Version {
+            major: bytes[0],
+            minor: bytes[1] >> 4,
+            maintenance: bytes[1] & 0x0f,
+        }
+    }
+}
+
+/// Information reported by the UWBS in CORE_GET_DEVICE_INFO_RSP.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DeviceInfo {
+    pub uci_version: Version,
+    pub mac_version: Version,
+    pub phy_version: Version,
+    pub uci_test_version: Version,
+    pub vendor_specific_info: Vec<u8>,
+}
+
+/// Selection of UCI messages by message type and, for control messages,
+/// by group. The default filter selects all messages.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MessageFilter {
+    /// Bit `1 << MT` of each selected message type.
+    message_types: u8,
+    /// Bit `1 << GID` of each selected group of control messages.
+    group_ids: u16,
+}
+
+impl Default for MessageFilter {
+    fn default() -> Self {
+        MessageFilter {
+            message_types: u8::MAX,
+            group_ids: u16::MAX,
+        }
+    }
+}
+
+impl MessageFilter {
+    /// Only select the messages of `message_types`. The clients of the
+    /// AIDL interface select all messages, only the tests filter them.
+    #[cfg(test)]
+    pub fn with_message_types(mut self, message_types: &[MessageType]) -> Self {
+        self.message_types = message_types
+            .iter()
+            .fold(0, |mask, message_type| mask | 1 << *message_type as u8);
+        self
+    }
+
+    /// Only select the control messages of `group_ids`. Data messages,
+    /// which have no group, are not affected.
+    #[cfg(test)]
+    pub fn with_group_ids(mut self, group_ids: &[u8]) -> Self {
+        self.group_ids = group_ids
+            .iter()
+            .fold(0, |mask, group_id| mask | 1 << (group_id & GID_MASK));
+        self
+    }
+
+    /// Return true if `message` is selected. Messages without a valid
+    /// header are always selected.
+    pub fn matches(&self, message: &[u8]) -> bool {
+        let Ok(header) = UciHeader::parse(message) else {
+            return true;
+        };
+        self.message_types & 1 << header.message_type as u8 != 0
+            && (header.message_type == MessageType::Data
+                || self.group_ids & 1 << header.group_id != 0)
+    }
+}
+
+/// Encoder of the UCI commands sent by the HAL, generated with the UCI
+/// packets of the pdl. The commands are segmented in packets of at most
+/// 255 bytes of payload.
+pub struct UciCommandBuilder;
+
+impl UciCommandBuilder {
+    /// CORE_DEVICE_RESET_CMD.
+    pub fn core_device_reset(reset_config: ResetConfig) -> Vec<Vec<u8>> {
+        encode(DeviceResetCmdBuilder { reset_config }.build().into())
+    }
+
+    /// CORE_GET_DEVICE_INFO_CMD.
+    pub fn core_get_device_info() -> Vec<Vec<u8>> {
+        encode(GetDeviceInfoCmdBuilder {}.build().into())
+    }
+
+    /// CORE_GET_CAPS_INFO_CMD.
+    pub fn core_get_caps_info() -> Vec<Vec<u8>> {
+        encode(GetCapsInfoCmdBuilder {}.build().into())
+    }
+
+    /// SESSION_INIT_CMD.
+    #[cfg(test)]
+    pub fn session_init(session_id: u32, session_type: SessionType) -> Vec<Vec<u8>> {
+        encode(
+            SessionInitCmdBuilder {
+                session_id,
+                session_type,
+            }
+            .build()
+            .into(),
+        )
+    }
+
+    /// SESSION_DEINIT_CMD.
+    pub fn session_deinit(session_handle: u32) -> Vec<Vec<u8>> {
+        encode(
+            SessionDeinitCmdBuilder {
+                session_token: session_handle,
+            }
+            .build()
+            .into(),
+        )
+    }
+
+    /// SESSION_SET_APP_CONFIG_CMD, with the values of the configurations
+    /// keyed by tag. Returns the first tag unknown to the UCI packets as
+    /// an error.
+    pub fn session_set_app_config(
+        session_handle: u32,
+        configs: &[(AppConfigTag, Vec<u8>)],
+    ) -> Result<Vec<Vec<u8>>, AppConfigTag> {
+        let tlvs = configs
+            .iter()
+            .map(|(tag, value)| {
+                Ok(AppConfigTlv {
+                    cfg_id: AppConfigTlvType::try_from(tag.0).map_err(|_| *tag)?,
+                    v: value.clone(),
+                })
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(encode(
+            SessionSetAppConfigCmdBuilder {
+                session_token: session_handle,
+                tlvs,
+            }
+            .build()
+            .into(),
+        ))
+    }
+}
+
+/// Encoder of arbitrary UCI commands sent by the tests, e.g. vendor
+/// specific. The command is sent in a single packet, with the extended
+/// length encoding if its payload exceeds 255 bytes.
+#[cfg(test)]
+#[derive(Clone, Debug)]
+pub struct RawCommandBuilder {
+    group_id: u8,
+    opcode: u8,
+    payload: Vec<u8>,
+}
+
+#[cfg(test)]
+impl RawCommandBuilder {
+    pub fn new(group_id: u8, opcode: u8) -> Self {
+        RawCommandBuilder {
+            group_id: group_id & GID_MASK,
+            opcode: opcode & OID_MASK,
+            payload: Vec::new(),
+        }
+    }
+
+    /// Append `bytes` to the payload of the command.
+    pub fn payload(mut self, bytes: &[u8]) -> Self {
+        self.payload.extend_from_slice(bytes);
+        self
+    }
+
+    /// Encode the command, header included.
+    pub fn build(self) -> Vec<u8> {
+        let mut packet = vec![
+            MESSAGE_TYPE_COMMAND << MESSAGE_TYPE_SHIFT | self.group_id,
+            self.opcode,
+            0,
+            0,
+        ];
+        set_payload_length(&mut packet, self.payload.len());
+        packet.extend_from_slice(&self.payload);
+        packet
+    }
+}
+
+/// Segment a command generated with the UCI packets of the pdl.
+fn encode(packet: UciControlPacket) -> Vec<Vec<u8>> {
+    let packets: Vec<UciControlPacketHal> = packet.into();
+    packets.into_iter().map(|packet| packet.to_vec()).collect()
+}
+
+/// Parse a successful CORE_GET_DEVICE_INFO_RSP.
+pub fn parse_device_info_rsp(message: &[u8]) -> Option<DeviceInfo> {
+    let header = UciHeader::parse(message).ok()?;
+    if !header.is_control(MessageType::Response, GID_CORE, OID_CORE_GET_DEVICE_INFO) {
+        return None;
+    }
+    // Status, UCI, MAC, PHY and UCI test versions, then the length
+    // of the vendor specific information.
+    let payload = &message[UCI_HEADER_SIZE..];
+    if payload.len() < 10 || payload[0] != STATUS_OK {
+        return None;
+    }
+    let vendor_specific_info = payload.get(10..10 + payload[9] as usize)?;
+    Some(DeviceInfo {
+        uci_version: Version::parse([payload[1], payload[2]]),
+        mac_version: Version::parse([payload[3], payload[4]]),
+        phy_version: Version::parse([payload[5], payload[6]]),
+        uci_test_version: Version::parse([payload[7], payload[8]]),
+        vendor_specific_info: vendor_specific_info.to_vec(),
+    })
+}
+
+/// Tag of a session app configuration TLV.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct AppConfigTag(pub u8);
+
+#[allow(dead_code)] // Not all the tags are set by the vendor handlers.
+impl AppConfigTag {
+    pub const DEVICE_TYPE: AppConfigTag = AppConfigTag(0x00);
+    pub const RANGING_ROUND_USAGE: AppConfigTag = AppConfigTag(0x01);
+    pub const STS_CONFIG: AppConfigTag = AppConfigTag(0x02);
+    pub const MULTI_NODE_MODE: AppConfigTag = AppConfigTag(0x03);
+    pub const CHANNEL_NUMBER: AppConfigTag = AppConfigTag(0x04);
+    pub const NUMBER_OF_CONTROLEES: AppConfigTag = AppConfigTag(0x05);
+    pub const DEVICE_MAC_ADDRESS: AppConfigTag = AppConfigTag(0x06);
+    pub const DST_MAC_ADDRESS: AppConfigTag = AppConfigTag(0x07);
+    pub const SLOT_DURATION: AppConfigTag = AppConfigTag(0x08);
+    pub const RANGING_DURATION: AppConfigTag = AppConfigTag(0x09);
+    pub const DEVICE_ROLE: AppConfigTag = AppConfigTag(0x11);
+}
+
+/// Outcome of a SESSION_SET_APP_CONFIG_CMD.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AppConfigStatus {
+    pub status: u8,
+    /// Configurations rejected by the UWBS, with the status of each.
+    pub rejected: Vec<(AppConfigTag, u8)>,
+}
+
+/// Parse a SESSION_SET_APP_CONFIG_RSP.
+pub fn parse_set_app_config_rsp(message: &[u8]) -> Option<AppConfigStatus> {
+    let header = UciHeader::parse(message).ok()?;
+    if !header.is_control(
+        MessageType::Response,
+        GID_SESSION_CONFIG,
+        OID_SESSION_SET_APP_CONFIG,
+    ) {
+        return None;
+    }
+    // Status and number of rejected configurations, then the tag
+    // and status of each.
+    let payload = &message[UCI_HEADER_SIZE..];
+    let (&[status, count], rejected) = payload.split_first_chunk()?;
+    let rejected = rejected.get(..2 * count as usize)?;
+    Some(AppConfigStatus {
+        status,
+        rejected: rejected
+            .chunks_exact(2)
+            .map(|config| (AppConfigTag(config[0]), config[1]))
+            .collect(),
+    })
+}
+
+/// Capabilities reported by the UWBS in CORE_GET_CAPS_INFO_RSP.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct UwbCapabilities {
+    /// Supported channels, in increasing order.
+    pub channels: Vec<u8>,
+    /// Bitmask of the supported ranging methods.
+    pub ranging_methods: u8,
+    /// Maximum number of concurrent sessions. No standard TLV carries it,
+    /// so it is only reported by the vendor handler.
+    pub max_sessions: Option<usize>,
+}
+
+/// Parse a successful CORE_GET_CAPS_INFO_RSP. The unknown capabilities
+/// are ignored.
+pub fn parse_caps_info_rsp(message: &[u8]) -> Option<UwbCapabilities> {
+    let header = UciHeader::parse(message).ok()?;
+    if !header.is_control(MessageType::Response, GID_CORE, OID_CORE_GET_CAPS_INFO) {
+        return None;
+    }
+    // Status and number of TLVs, then the TLVs.
+    let payload = &message[UCI_HEADER_SIZE..];
+    if payload.len() < 2 || payload[0] != STATUS_OK {
+        return None;
+    }
+    let mut capabilities = UwbCapabilities::default();
+    let mut tlvs = &payload[2..];
+    for _ in 0..payload[1] {
+        let (&[tag, length], rest) = tlvs.split_first_chunk()?;
+        let value = rest.get(..length as usize)?;
+        tlvs = &rest[length as usize..];
+        match (tag, value) {
+            (CAP_RANGING_METHOD, &[methods, ..]) => capabilities.ranging_methods = methods,
+            (CAP_CHANNELS, &[channels, ..]) => {
+                capabilities.channels = (0..CHANNELS.len())
+                    .filter(|bit| channels & (1 << bit) != 0)
+                    .map(|bit| CHANNELS[bit])
+                    .collect()
+            }
+            _ => (),
+        }
+    }
+    Some(capabilities)
+}
+
+/// Reassemble UCI messages segmented with the Packet Boundary Flag.
+#[derive(Default)]
+pub struct Reassembler {
+    /// Partially received messages, keyed by message type, group and opcode.
+    pending: HashMap<(MessageType, u8, u8), Vec<u8>>,
+}
+
+impl Reassembler {
+    /// Process a single UCI packet with the parsed `header`. Returns the
+    /// complete message once its last segment has been received.
+    pub fn push(&mut self, header: UciHeader, packet: Vec<u8>) -> Option<Vec<u8>> {
+        let key = (header.message_type, header.group_id, header.opcode);
+
+        let mut message = match self.pending.remove(&key) {
+            Some(mut message) => {
+                message.extend_from_slice(&packet[UCI_HEADER_SIZE..]);
+                message
+            }
+            None => packet,
+        };
+
+        if header.pbf {
+            self.pending.insert(key, message);
+            return None;
+        }
+
+        if message[0] & PBF_MASK != 0 {
+            // The message was segmented: the header of the first segment
+            // is kept, with the flag cleared and the total payload length.
+            let length = message.len() - UCI_HEADER_SIZE;
+            message[0] &= !PBF_MASK;
+            set_payload_length(&mut message, length);
+        }
+        Some(message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_headers() {
+        // Control packet, with a single byte length.
+        assert_eq!(
+            UciHeader::parse(&[0x61, 0x02, 0x00, 0x06]),
+            Ok(UciHeader {
+                message_type: MessageType::Notification,
+                pbf: false,
+                group_id: GID_SESSION_CONFIG,
+                opcode: OID_SESSION_STATUS,
+                payload_length: 6,
+            })
+        );
+        // Data packet segment, with a 16-bit little-endian length.
+        assert_eq!(
+            UciHeader::parse(&[0x11, 0x00, 0x04, 0x01]),
+            Ok(UciHeader {
+                message_type: MessageType::Data,
+                pbf: true,
+                group_id: 0x01,
+                opcode: 0,
+                payload_length: 0x104,
+            })
+        );
+        // Control packet with the extended length flag.
+        assert_eq!(
+            UciHeader::parse(&[0x21, 0x83, 0x2c, 0x01]),
+            Ok(UciHeader {
+                message_type: MessageType::Command,
+                pbf: false,
+                group_id: GID_SESSION_CONFIG,
+                opcode: OID_SESSION_SET_APP_CONFIG,
+                payload_length: 300,
+            })
+        );
+        assert_eq!(
+            UciHeader::parse(&[0x20, 0x00, 0x00]),
+            Err(UciParseError::Truncated(3))
+        );
+        assert_eq!(
+            UciHeader::parse(&[0x80, 0x00, 0x00, 0x00]),
+            Err(UciParseError::InvalidMessageType(4))
+        );
+    }
+
+    #[test]
+    fn message_names() {
+        let name = |bytes: &[u8]| UciHeader::parse(bytes).unwrap().name().to_string();
+        assert_eq!(name(&[0x20, 0x00, 0x00, 0x01]), "CORE_DEVICE_RESET_CMD");
+        assert_eq!(name(&[0x40, 0x02, 0x00, 0x00]), "CORE_GET_DEVICE_INFO_RSP");
+        assert_eq!(name(&[0x61, 0x02, 0x00, 0x06]), "SESSION_STATUS_NTF");
+        assert_eq!(name(&[0x62, 0x00, 0x00, 0x00]), "SESSION_INFO_NTF");
+        assert_eq!(name(&[0x01, 0x00, 0x06, 0x00]), "DATA");
+        assert_eq!(
+            name(&[0x2e, 0x21, 0x00, 0x00]),
+            "Command(gid=0x0e, oid=0x21)"
+        );
+    }
+
+    #[test]
+    fn build_core_commands() {
+        assert_eq!(
+            UciCommandBuilder::core_device_reset(ResetConfig::UwbsReset),
+            [[0x20, 0x00, 0x00, 0x01, 0x00]]
+        );
+        assert_eq!(
+            UciCommandBuilder::core_get_device_info(),
+            [[0x20, 0x02, 0x00, 0x00]]
+        );
+        assert_eq!(
+            UciCommandBuilder::core_get_caps_info(),
+            [[0x20, 0x03, 0x00, 0x00]]
+        );
+    }
+
+    #[test]
+    fn build_session_commands() {
+        assert_eq!(
+            UciCommandBuilder::session_init(1, SessionType::FiraRangingSession),
+            [[0x21, 0x00, 0x00, 0x05, 0x01, 0x00, 0x00, 0x00, 0x00]]
+        );
+        assert_eq!(
+            UciCommandBuilder::session_deinit(1),
+            [[0x21, 0x01, 0x00, 0x04, 0x01, 0x00, 0x00, 0x00]]
+        );
+        assert_eq!(
+            UciCommandBuilder::session_set_app_config(
+                0x0102_0304,
+                &[(AppConfigTag::DEVICE_TYPE, vec![0x01])]
+            ),
+            Ok(vec![vec![
+                0x21, 0x03, 0x00, 0x08, 0x04, 0x03, 0x02, 0x01, 0x01, 0x00, 0x01, 0x01
+            ]])
+        );
+    }
+
+    #[test]
+    fn build_segmented_command() {
+        let packets = UciCommandBuilder::session_set_app_config(
+            1,
+            &[(AppConfigTag::DEVICE_MAC_ADDRESS, vec![0; 255])],
+        )
+        .unwrap();
+        // The payload of 262 bytes is segmented after 255 bytes.
+        assert_eq!(packets.len(), 2);
+        assert_eq!(packets[0][..4], [0x31, 0x03, 0x00, 0xff]);
+        assert_eq!(packets[1][..4], [0x21, 0x03, 0x00, 0x07]);
+    }
+
+    #[test]
+    fn parse_caps_info() {
+        const CAPS_INFO_RSP: [u8; 16] = [
+            0x40, 0x03, 0x00, 0x0c, 0x00, 0x04, 0x03, 0x01, 0x01, 0x0b, 0x01, 0x09, 0x80, 0x00,
+            0xe3, 0x01,
+        ];
+        // The last TLV is truncated.
+        assert_eq!(parse_caps_info_rsp(&CAPS_INFO_RSP), None);
+
+        let mut message = CAPS_INFO_RSP.to_vec();
+        message[3] += 1;
+        message.push(0x04);
+        assert_eq!(
+            parse_caps_info_rsp(&message),
+            Some(UwbCapabilities {
+                channels: vec![5, 9],
+                ranging_methods: 0x01,
+                max_sessions: None,
+            })
+        );
+    }
+
+    #[test]
+    fn filter_messages() {
+        const DEVICE_STATUS_NTF: [u8; 5] = [0x60, 0x01, 0x00, 0x01, 0x01];
+        const SESSION_STATUS_NTF: [u8; 4] = [0x61, 0x02, 0x00, 0x00];
+        const DATA_MESSAGE: [u8; 4] = [0x02, 0x00, 0x00, 0x00];
+
+        let filter = MessageFilter::default();
+        assert!(filter.matches(&DEVICE_STATUS_NTF));
+        assert!(filter.matches(&DATA_MESSAGE));
+
+        let filter = MessageFilter::default().with_message_types(&[MessageType::Data]);
+        assert!(!filter.matches(&DEVICE_STATUS_NTF));
+        assert!(filter.matches(&DATA_MESSAGE));
+
+        let filter = MessageFilter::default().with_group_ids(&[GID_SESSION_CONFIG]);
+        assert!(!filter.matches(&DEVICE_STATUS_NTF));
+        assert!(filter.matches(&SESSION_STATUS_NTF));
+        assert!(filter.matches(&DATA_MESSAGE));
+    }
+
+    #[test]
+    fn build_extended_length_command() {
+        let packet = RawCommandBuilder::new(GID_SESSION_CONFIG, OID_SESSION_SET_APP_CONFIG)
+            .payload(&[0; 300])
+            .build();
+        assert_eq!(packet.len(), UCI_HEADER_SIZE + 300);
+        assert_eq!(
+            parse_packet(&packet).map(|header| header.payload_length),
+            Ok(300)
+        );
+    }
+
+    #[test]
+    fn reassemble_segmented_messages() {
+        let mut reassembler = Reassembler::default();
+        let mut push =
+            |packet: &[u8]| reassembler.push(UciHeader::parse(packet).unwrap(), packet.to_vec());
+
+        // First segment of a SESSION_STATUS_NTF.
+        assert_eq!(push(&[0x71, 0x02, 0x00, 0x02, 0x01, 0x00]), None);
+        // A message of another group and opcode is not reassembled with it.
+        assert_eq!(
+            push(&[0x60, 0x01, 0x00, 0x01, 0x01]),
+            Some(vec![0x60, 0x01, 0x00, 0x01, 0x01])
+        );
+        // The last segment completes the message, with the total length.
+        assert_eq!(
+            push(&[0x61, 0x02, 0x00, 0x04, 0x00, 0x00, 0x02, 0x00]),
+            Some(vec![
+                0x61, 0x02, 0x00, 0x06, 0x01, 0x00, 0x00, 0x00, 0x02, 0x00
+            ])
+        );
+    }
+}
@@ -1,45 +1,130 @@
-use android_hardware_uwb::aidl::android::hardware::uwb::{IUwb, IUwbChip};
+use android_hardware_uwb::aidl::android::hardware::uwb::{
+    IUwb, IUwbChip, IUwbChip::IUwbChipAsyncServer, IUwbClientCallback::IUwbClientCallback,
+};
 use android_hardware_uwb::binder;
+use async_trait::async_trait;
 use binder::{Result, Strong};
 use binder_tokio::TokioRuntime;
 use tokio::runtime::Handle as TokioHandle;
 
+use std::ffi::CStr;
+use std::io::{self, Write};
+use std::sync::Arc;
+
 use crate::uwb_chip;
 
 pub struct Uwb {
     chips: Vec<Strong<dyn IUwbChip::IUwbChip>>,
+    /// Chips served by the binders, whose state is dumped.
+    shared: Vec<Arc<uwb_chip::UwbChip>>,
 }
 
 impl Uwb {
     pub fn from_chips(
-        chips: impl IntoIterator<Item = uwb_chip::UwbChip>,
+        chips: impl IntoIterator<Item = Arc<uwb_chip::UwbChip>>,
         handle: TokioHandle,
     ) -> Self {
+        let shared: Vec<_> = chips.into_iter().collect();
         Self {
-            chips: chips
-                .into_iter()
+            chips: shared
+                .iter()
                 .map(|chip| {
                     IUwbChip::BnUwbChip::new_async_binder(
-                        chip,
+                        SharedChip(chip.clone()),
                         TokioRuntime(handle.clone()),
                         binder::BinderFeatures::default(),
                     )
                 })
                 .collect(),
+            shared,
+        }
+    }
+}
+
+/// Write the state of `chip` reported by dumpsys.
+fn dump_chip(writer: &mut dyn Write, chip: &uwb_chip::UwbChip) -> io::Result<()> {
+    writeln!(writer, "chip {}:", chip.name())?;
+    let transport = chip.transport_info();
+    writeln!(
+        writer,
+        "  transport: {:?} {} ({})",
+        transport.kind,
+        transport.location,
+        if transport.connected {
+            "connected"
+        } else {
+            "disconnected"
+        }
+    )?;
+    if let Some(err) = chip.last_error() {
+        writeln!(writer, "  last error: {}", err)?;
+    }
+    match chip.capabilities() {
+        Some(capabilities) => writeln!(writer, "  capabilities: {:?}", capabilities)?,
+        None => writeln!(writer, "  capabilities: not queried")?,
+    }
+    writeln!(writer, "  metrics: {:?}", chip.metrics_snapshot())
+}
+
+impl binder::Interface for Uwb {
+    /// Report the state of the chips to `dumpsys android.hardware.uwb.IUwb/default`.
+    fn dump(
+        &self,
+        writer: &mut dyn Write,
+        _args: &[&CStr],
+    ) -> std::result::Result<(), binder::StatusCode> {
+        for chip in self.shared.iter() {
+            dump_chip(writer, chip).map_err(|_| binder::StatusCode::UNKNOWN_ERROR)?;
         }
+        Ok(())
     }
 }
 
-impl binder::Interface for Uwb {}
+/// Chip served by its binder, and still reachable by the service
+/// to shut it down and dump its state.
+struct SharedChip(Arc<uwb_chip::UwbChip>);
+
+impl binder::Interface for SharedChip {}
+
+#[async_trait]
+impl IUwbChipAsyncServer for SharedChip {
+    async fn getName(&self) -> Result<String> {
+        self.0.getName().await
+    }
+
+    async fn open(&self, callbacks: &Strong<dyn IUwbClientCallback>) -> Result<()> {
+        self.0.open(callbacks).await
+    }
+
+    async fn close(&self) -> Result<()> {
+        self.0.close().await
+    }
+
+    async fn coreInit(&self) -> Result<()> {
+        self.0.coreInit().await
+    }
+
+    async fn sessionInit(&self, id: i32) -> Result<()> {
+        self.0.sessionInit(id).await
+    }
+
+    async fn getSupportedAndroidUciVersion(&self) -> Result<i32> {
+        self.0.getSupportedAndroidUciVersion().await
+    }
+
+    async fn sendUciMessage(&self, data: &[u8]) -> Result<i32> {
+        self.0.sendUciMessage(data).await
+    }
+}
 
 impl IUwb::IUwb for Uwb {
     fn getChips(&self) -> Result<Vec<String>> {
-        log::debug!("getChips");
+        tracing::debug!("getChips");
         self.chips.iter().map(|chip| chip.getName()).collect()
     }
 
     fn getChip(&self, name: &str) -> Result<Strong<dyn IUwbChip::IUwbChip>> {
-        log::debug!("getChip {}", name);
+        tracing::debug!(name, "getChip");
         let chip = self
             .chips
             .iter()
@@ -51,3 +136,29 @@ impl IUwb::IUwb for Uwb {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::MockUwbs;
+    use binder::Interface;
+
+    #[tokio::test]
+    async fn dump_reports_chips() {
+        let uwb = Uwb::from_chips(
+            [Arc::new(uwb_chip::UwbChip::new_mock(
+                "0".to_owned(),
+                MockUwbs::default(),
+            ))],
+            TokioHandle::current(),
+        );
+        let mut dump = Vec::new();
+        uwb.dump(&mut dump, &[]).unwrap();
+        let dump = String::from_utf8(dump).unwrap();
+        assert!(dump.starts_with("chip 0:\n"));
+        assert!(dump.contains("  transport: Test mock (disconnected)\n"));
+        assert!(!dump.contains("last error"));
+        assert!(dump.contains("  capabilities: not queried\n"));
+        assert!(dump.contains("  metrics: UwbMetrics {"));
+    }
+}
@@ -4,292 +4,3978 @@ use android_hardware_uwb::aidl::android::hardware::uwb::{
 };
 use android_hardware_uwb::binder;
 use async_trait::async_trait;
-use binder::{DeathRecipient, IBinder, Result, Strong};
+use binder::{DeathRecipient, IBinder, Result, SpIBinder, Strong};
 
+use futures::{FutureExt, StreamExt};
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
 use std::sync::Arc;
-use tokio::io::unix::AsyncFd;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWriteExt, ReadHalf, WriteHalf};
 use tokio::select;
-use tokio::sync::Mutex;
+use tokio::sync::{mpsc, oneshot, Mutex, Notify};
 use tokio_util::sync::CancellationToken;
+use tracing::Instrument;
+use uwb_uci_packets::ResetConfig;
 
-use std::fs::{File, OpenOptions};
-use std::io::{self, Read, Write};
-use std::os::unix::fs::OpenOptionsExt;
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::path::PathBuf;
 
-use pdl_runtime::Packet;
-use uwb_uci_packets::{DeviceResetCmdBuilder, ResetConfig, UciControlPacket, UciControlPacketHal};
+use crate::capture::{Capture, Direction};
+use crate::clock::{self, Clock, TokioClock};
+use crate::error::{HalError, HalResult};
+use crate::framed_reader::{UciFramedReader, UciPacket, UCI_MAX_PACKET_SIZE};
+use crate::metrics::{Metrics, UwbMetrics};
+use crate::transport::{Attempt, Backoff, OpenMode, Transport, TransportConfig, TransportInfo};
+use crate::uci::{
+    self, AppConfigStatus, AppConfigTag, DeviceInfo, MessageFilter, MessageType, Reassembler,
+    UciHeader, UwbCapabilities,
+};
+use crate::vendor::{self, VendorUciHandler};
+
+/// Android UCI version reported when the UWBS has not provided one.
+const DEFAULT_ANDROID_UCI_VERSION: i32 = 1;
+
+/// Default maximum size of a UCI packet received from the UWBS.
+const DEFAULT_MAX_PACKET_SIZE: usize = 1024;
+
+/// Time allowed for the UWBS to respond to the commands sent by the HAL.
+const UCI_RESPONSE_TIMEOUT: Duration = Duration::from_millis(1000);
+
+/// Maximum number of UCI messages queued for delivery to the clients.
+/// The reader task stops reading from the UWBS while the queue is full.
+const DELIVERY_QUEUE_SIZE: usize = 64;
+
+/// Time allowed on shutdown for delivering the UCI messages already
+/// received from the UWBS.
+const SHUTDOWN_GRACE_PERIOD: Duration = Duration::from_millis(100);
+
+/// Maximum number of UCI packets queued for writing to the UWBS.
+/// The senders wait for room in the queue while it is full.
+const WRITE_QUEUE_SIZE: usize = 16;
+
+type Writer = WriteHalf<Box<dyn Transport>>;
+
+type Reader = UciFramedReader<ReadHalf<Box<dyn Transport>>>;
+
+/// UCI packets queued for writing together, with the sender of the
+/// write result.
+type WriteRequest = (Vec<Vec<u8>>, oneshot::Sender<io::Result<()>>);
+
+/// Queue of the UCI packets written to the UWBS by the writer task,
+/// which owns the writer half of the transport. The packets are written
+/// in queue order, without holding the state lock during the writes.
+#[derive(Clone)]
+struct WriteQueue(mpsc::Sender<WriteRequest>);
+
+impl WriteQueue {
+    /// Spawn the writer task, recording the packets to `captures` and
+    /// counting them in `metrics` and `last_traffic` as they are written,
+    /// including the commands of the HAL. The task exits once all the
+    /// queues are dropped.
+    fn spawn(
+        mut writer: Writer,
+        captures: Vec<Arc<Capture>>,
+        metrics: Arc<Metrics>,
+        last_traffic: Arc<LastTraffic>,
+    ) -> Self {
+        let (sender, mut receiver) = mpsc::channel::<WriteRequest>(WRITE_QUEUE_SIZE);
+        tokio::task::spawn(async move {
+            while let Some((packets, result)) = receiver.recv().await {
+                let mut written = Ok(());
+                for packet in packets {
+                    for capture in captures.iter() {
+                        capture.record(Direction::Outbound, &packet);
+                    }
+                    written = writer.write_all(&packet).await;
+                    if written.is_err() {
+                        break;
+                    }
+                    metrics.record_sent(&packet);
+                    last_traffic.record();
+                }
+                // Framed transports send the complete packets on flush.
+                if written.is_ok() {
+                    written = writer.flush().await;
+                }
+                if written.is_err() {
+                    metrics.record_error();
+                }
+                let _ = result.send(written);
+            }
+        });
+        WriteQueue(sender)
+    }
+
+    /// Wait for room in the queue.
+    async fn reserve(&self) -> io::Result<WriteSlot<'_>> {
+        self.0
+            .reserve()
+            .await
+            .map(WriteSlot)
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "UCI writer task exited"))
+    }
+
+    /// Write `packet` to the UWBS.
+    async fn write(&self, packet: Vec<u8>) -> io::Result<()> {
+        self.reserve().await?.write(vec![packet]).await
+    }
+
+    fn same_queue(&self, other: &WriteQueue) -> bool {
+        self.0.same_channel(&other.0)
+    }
+}
+
+/// Room reserved in the write queue.
+struct WriteSlot<'a>(mpsc::Permit<'a, WriteRequest>);
+
+impl WriteSlot<'_> {
+    /// Queue `packets`, written back to back. The returned future
+    /// completes once all the packets are written to the UWBS.
+    fn write(self, packets: Vec<Vec<u8>>) -> impl Future<Output = io::Result<()>> {
+        let (sender, receiver) = oneshot::channel();
+        self.0.send((packets, sender));
+        async move {
+            receiver.await.unwrap_or_else(|_| {
+                Err(io::Error::new(
+                    io::ErrorKind::BrokenPipe,
+                    "UCI writer task exited",
+                ))
+            })
+        }
+    }
+}
+
+/// Attempts of the commands sent by the HAL.
+#[derive(Clone, Copy, Debug)]
+pub struct CommandRetry {
+    /// Number of times a command is sent before failing with a timeout.
+    pub attempts: u32,
+    /// Time allowed for the UWBS to respond to each attempt.
+    pub timeout: Duration,
+}
+
+impl Default for CommandRetry {
+    fn default() -> Self {
+        CommandRetry {
+            attempts: 1,
+            timeout: UCI_RESPONSE_TIMEOUT,
+        }
+    }
+}
+
+/// Limit of the rate of the commands sent by each client with
+/// sendUciMessage(), enforced with a token bucket per calling process.
+#[derive(Clone, Copy, Debug)]
+pub struct RateLimit {
+    /// Average rate of the commands allowed.
+    pub commands_per_second: u32,
+    /// Number of commands allowed at once, after the client was idle.
+    pub burst: u32,
+    pub policy: RateLimitPolicy,
+}
+
+/// Handling of the commands exceeding the rate limit.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum RateLimitPolicy {
+    /// Fail the command with WOULD_BLOCK.
+    #[default]
+    Reject,
+    /// Delay the command until it is allowed.
+    Wait,
+}
+
+/// Commands a client may send, replenished at the rate of the limit.
+struct TokenBucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Token buckets of the clients, keyed by calling pid.
+struct RateLimiter {
+    limit: RateLimit,
+    buckets: std::sync::Mutex<HashMap<i32, TokenBucket>>,
+}
+
+impl RateLimiter {
+    /// Take a token from the bucket of `client`, or return the delay
+    /// until one is available.
+    fn try_acquire(&self, client: i32, now: Instant) -> std::result::Result<(), Duration> {
+        let rate = self.limit.commands_per_second.max(1) as f64;
+        let burst = self.limit.burst.max(1) as f64;
+        let mut buckets = self.buckets.lock().unwrap();
+        let bucket = buckets.entry(client).or_insert(TokenBucket {
+            tokens: burst,
+            updated: now,
+        });
+        let elapsed = now.saturating_duration_since(bucket.updated);
+        bucket.tokens = (bucket.tokens + elapsed.as_secs_f64() * rate).min(burst);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / rate))
+        }
+    }
+}
+
+/// Sender of a UCI message awaited by the HAL.
+type MessageSender = oneshot::Sender<Vec<u8>>;
+
+/// Recipient of the response to a command sent by the HAL, shared by
+/// the attempts of the command: the first response received completes
+/// the command, the responses to the other attempts are discarded.
+#[derive(Clone)]
+struct Recipient {
+    sender: Arc<std::sync::Mutex<Option<MessageSender>>>,
+    /// Also deliver the response to the clients, for the commands
+    /// sent by the clients.
+    forward: bool,
+}
+
+impl Recipient {
+    /// Create a recipient, and the receiver of the response.
+    fn new(forward: bool) -> (Self, oneshot::Receiver<Vec<u8>>) {
+        let (sender, receiver) = oneshot::channel();
+        let recipient = Recipient {
+            sender: Arc::new(std::sync::Mutex::new(Some(sender))),
+            forward,
+        };
+        (recipient, receiver)
+    }
+}
+
+/// Command written to the UWBS, awaiting its response.
+struct PendingResponse {
+    id: u64,
+    /// Recipient of the response, None for the commands of the clients
+    /// which are only forwarded.
+    recipient: Option<Recipient>,
+    sent_at: Instant,
+    /// Time after which the response is assumed lost.
+    expires: Instant,
+}
+
+/// Correlation of the responses received from the UWBS with the
+/// outstanding commands, keyed by group identifier and opcode. The
+/// commands of the HAL and of the clients are registered as they are
+/// queued for writing, and the UWBS responds to them in the same order,
+/// so that a client sending the same command as the HAL gets its own
+/// response. The response latencies are recorded to the metrics of the
+/// chip.
+struct PendingResponses {
+    pending: std::sync::Mutex<HashMap<(u8, u8), VecDeque<PendingResponse>>>,
+    next_id: AtomicU64,
+    /// Notifications awaited by the HAL, e.g. the device status following
+    /// a device reset, which are not forwarded to the clients.
+    notifications: std::sync::Mutex<HashMap<(u8, u8), MessageSender>>,
+    metrics: Arc<Metrics>,
+    /// Clock of the chip, also timing out the commands.
+    clock: Arc<dyn Clock>,
+}
+
+impl PendingResponses {
+    fn new(metrics: Arc<Metrics>, clock: Arc<dyn Clock>) -> Self {
+        PendingResponses {
+            pending: Default::default(),
+            next_id: AtomicU64::new(0),
+            notifications: Default::default(),
+            metrics,
+            clock,
+        }
+    }
+
+    /// Queue `packets` for writing to the UWBS, and register the command
+    /// completed by the last packet, if any, in the same order. Its
+    /// response is delivered to `recipient`, or only forwarded to the
+    /// clients if None. The response is assumed lost if not received
+    /// for `timeout` and UCI_RESPONSE_TIMEOUT. The returned future
+    /// completes once the packets are written.
+    fn write(
+        self: &Arc<Self>,
+        slot: WriteSlot<'_>,
+        packets: Vec<Vec<u8>>,
+        recipient: Option<&Recipient>,
+        timeout: Duration,
+    ) -> impl Future<Output = io::Result<()>> {
+        let command = packets
+            .last()
+            .and_then(|packet| UciHeader::parse(packet).ok())
+            .filter(|header| header.message_type == MessageType::Command && !header.pbf)
+            .map(|header| (header.group_id, header.opcode));
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let mut pending = self.pending.lock().unwrap();
+        if let Some(key) = command {
+            let sent_at = self.clock.now();
+            pending.entry(key).or_default().push_back(PendingResponse {
+                id,
+                recipient: recipient.cloned(),
+                sent_at,
+                expires: sent_at + timeout + UCI_RESPONSE_TIMEOUT,
+            });
+        }
+        let written = slot.write(packets);
+        drop(pending);
+
+        let pending_rsp = self.clone();
+        async move {
+            let result = written.await;
+            if let (Err(_), Some(key)) = (&result, command) {
+                // No response is expected for a command not written.
+                pending_rsp.remove(key, id);
+            }
+            result
+        }
+    }
+
+    fn remove(&self, key: (u8, u8), id: u64) {
+        let mut pending = self.pending.lock().unwrap();
+        if let Some(commands) = pending.get_mut(&key) {
+            commands.retain(|command| command.id != id);
+            if commands.is_empty() {
+                pending.remove(&key);
+            }
+        }
+    }
+
+    /// Register the HAL for the next notification of a group and opcode,
+    /// and return its receiver.
+    fn expect_notification(&self, group_id: u8, opcode: u8) -> oneshot::Receiver<Vec<u8>> {
+        let (sender, receiver) = oneshot::channel();
+        self.notifications
+            .lock()
+            .unwrap()
+            .insert((group_id, opcode), sender);
+        receiver
+    }
+
+    /// Count a command that did not get its response in time.
+    fn timed_out(&self) {
+        self.metrics.record_response_timeout();
+    }
+
+    /// Complete the command, or the notification expected by the HAL,
+    /// matching a received message. Returns the message if it must be
+    /// delivered to the clients: other notifications, data packets, and
+    /// responses to the commands sent by the clients.
+    fn complete(&self, header: &UciHeader, message: Vec<u8>) -> Option<Vec<u8>> {
+        let key = (header.group_id, header.opcode);
+        if header.message_type == MessageType::Notification {
+            let Some(sender) = self.notifications.lock().unwrap().remove(&key) else {
+                return Some(message);
+            };
+            let _ = sender.send(message);
+            return None;
+        }
+        if header.message_type != MessageType::Response {
+            return Some(message);
+        }
+
+        let now = self.clock.now();
+        let command = {
+            let mut pending = self.pending.lock().unwrap();
+            let Some(commands) = pending.get_mut(&key) else {
+                return Some(message);
+            };
+            commands.retain(|command| now < command.expires);
+            let command = commands.pop_front();
+            if commands.is_empty() {
+                pending.remove(&key);
+            }
+            command
+        };
+        let Some(PendingResponse {
+            recipient: Some(recipient),
+            sent_at,
+            ..
+        }) = command
+        else {
+            // Response to a command of the clients, or unexpected.
+            return Some(message);
+        };
+        let Some(sender) = recipient.sender.lock().unwrap().take() else {
+            tracing::debug!(
+                name = %header.name(),
+                "discarding the response to a retried command"
+            );
+            return None;
+        };
+        self.metrics
+            .record_response_latency(now.saturating_duration_since(sent_at));
+        if recipient.forward {
+            let _ = sender.send(message.clone());
+            Some(message)
+        } else {
+            let _ = sender.send(message);
+            None
+        }
+    }
+}
 
 enum State {
     Closed,
+    /// The device failed while opened, and is being reconnected.
+    /// Clients need to open the chip again once it is available.
+    Reconnecting {
+        token: CancellationToken,
+    },
+    /// The device was reconnected, and is read until the chip is opened
+    /// again to retain its notifications, e.g. the device status
+    /// notification sent by the UWBS once it has rebooted.
+    Standby {
+        token: CancellationToken,
+        /// Task reading the device, returning the reader once cancelled,
+        /// or None if the connection failed in the meantime.
+        reader: tokio::task::JoinHandle<Option<Reader>>,
+        writer: Writer,
+        /// Last notifications received, replayed to the next client.
+        notifications: Arc<std::sync::Mutex<VecDeque<Vec<u8>>>>,
+    },
     Opened {
-        callbacks: Strong<dyn IUwbClientCallback>,
-        handle: tokio::task::JoinHandle<()>,
-        serial: File,
-        death_recipient: DeathRecipient,
+        clients: Arc<Clients>,
+        handle: tokio::task::JoinHandle<io::Result<()>>,
+        /// Thread delivering the UCI messages to the clients, which exits
+        /// after the reader task.
+        delivery: tokio::task::JoinHandle<()>,
+        /// None when the chip is opened read-only.
+        writer: Option<WriteQueue>,
         token: CancellationToken,
+        /// Identifiers of the sessions initialized since the chip was opened,
+        /// with the span tracing the session until it is deinitialized.
+        sessions: HashMap<i32, tracing::Span>,
+        credits: Arc<DataCredits>,
+        phases: Arc<SessionPhases>,
+        pending_rsp: Arc<PendingResponses>,
     },
 }
 
-pub struct UwbChip {
-    name: String,
-    path: String,
-    state: Arc<Mutex<State>>,
+/// Client registered with open().
+struct Client {
+    callbacks: Strong<dyn IUwbClientCallback>,
+    death_recipient: DeathRecipient,
+    /// UCI messages forwarded to the client.
+    filter: MessageFilter,
 }
 
-impl UwbChip {
-    pub fn new(name: String, path: String) -> Self {
-        Self {
-            name,
-            path,
-            state: Arc::new(Mutex::new(State::Closed)),
+impl Client {
+    fn unlink(&mut self) {
+        if let Err(err) = self
+            .callbacks
+            .as_binder()
+            .unlink_to_death(&mut self.death_recipient)
+        {
+            tracing::warn!("failed to unlink death recipient: {:?}", err);
         }
     }
 }
 
-impl State {
-    /// Terminate the reader task.
-    async fn close(&mut self) -> Result<()> {
-        if let State::Opened {
-            ref mut token,
-            ref callbacks,
-            ref mut death_recipient,
-            ref mut handle,
-            ref mut serial,
-        } = *self
-        {
-            log::info!("waiting for task cancellation");
-            callbacks.as_binder().unlink_to_death(death_recipient)?;
-            token.cancel();
-            handle.await.unwrap();
-            let packet: UciControlPacket = DeviceResetCmdBuilder {
-                reset_config: ResetConfig::UwbsReset,
+/// Clients of an opened chip. All clients receive the UCI messages
+/// and HAL events emitted by the chip.
+#[derive(Default)]
+struct Clients(std::sync::Mutex<Vec<Client>>);
+
+impl Clients {
+    fn add(&self, client: Client) {
+        self.0.lock().unwrap().push(client);
+    }
+
+    fn contains(&self, binder: &SpIBinder) -> bool {
+        self.0
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|client| client.callbacks.as_binder() == *binder)
+    }
+
+    /// Unregister a client. Returns true if no clients remain.
+    fn remove(&self, binder: &SpIBinder) -> bool {
+        let mut clients = self.0.lock().unwrap();
+        clients.retain(|client| client.callbacks.as_binder() != *binder);
+        clients.is_empty()
+    }
+
+    /// Unregister all clients.
+    fn take(&self) -> Vec<Client> {
+        std::mem::take(&mut *self.0.lock().unwrap())
+    }
+
+    /// Return the callbacks of the registered clients. The callbacks
+    /// are invoked without holding the lock, so that a slow client does
+    /// not block the registration of other clients.
+    fn callbacks(&self) -> Vec<Strong<dyn IUwbClientCallback>> {
+        self.0
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|client| client.callbacks.clone())
+            .collect()
+    }
+
+    /// Forward a UCI message to the clients subscribed to it, pruning
+    /// the clients that have died.
+    fn on_uci_message(&self, message: &[u8]) {
+        let subscribed: Vec<_> = self
+            .0
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|client| client.filter.matches(message))
+            .map(|client| client.callbacks.clone())
+            .collect();
+        for callbacks in subscribed {
+            let Err(err) = callbacks.onUciMessage(message) else {
+                continue;
+            };
+            tracing::warn!("failed to deliver UCI message: {:?}", err);
+            if !callbacks.as_binder().is_binder_alive() {
+                tracing::info!("removing dead client");
+                self.remove(&callbacks.as_binder());
+            }
+        }
+    }
+
+    /// Notify a HAL event to a single client, if it is still registered.
+    fn on_client_hal_event(&self, binder: &SpIBinder, event: UwbEvent, status: UwbStatus) {
+        let Some(callbacks) = self
+            .callbacks()
+            .into_iter()
+            .find(|callbacks| callbacks.as_binder() == *binder)
+        else {
+            return;
+        };
+        if let Err(err) = callbacks.onHalEvent(event, status) {
+            tracing::warn!("failed to notify HAL event {:?}: {:?}", event, err);
+        }
+    }
+
+    fn on_hal_event(&self, event: UwbEvent, status: UwbStatus) {
+        for callbacks in self.callbacks() {
+            if let Err(err) = callbacks.onHalEvent(event, status) {
+                tracing::warn!("failed to notify HAL event {:?}: {:?}", event, err);
             }
-            .build()
-            .into();
-            // DeviceResetCmd need to be send to reset the device to stop all running
-            // activities on UWBS.
-            let packet_vec: Vec<UciControlPacketHal> = packet.into();
-            for hal_packet in packet_vec.into_iter() {
-                serial
-                    .write(&hal_packet.to_vec())
-                    .map(|written| written as i32)
-                    .map_err(|_| binder::StatusCode::UNKNOWN_ERROR)?;
-            }
-            consume_device_reset_rsp_and_ntf(
-                &mut serial
-                    .try_clone()
-                    .map_err(|_| binder::StatusCode::UNKNOWN_ERROR)?,
-            );
-            log::info!("task successfully cancelled");
-            callbacks.onHalEvent(UwbEvent::CLOSE_CPLT, UwbStatus::OK)?;
-            *self = State::Closed;
         }
-        Ok(())
     }
 }
 
-fn consume_device_reset_rsp_and_ntf(reader: &mut File) {
-    // Poll the DeviceResetRsp and DeviceStatusNtf before hal is closed to prevent
-    // the host from getting response and notifications from a 'powered down' UWBS.
-    // Do nothing when these packets are received.
-    const DEVICE_RESET_RSP: [u8; 5] = [64, 0, 0, 1, 0];
-    const DEVICE_STATUS_NTF: [u8; 5] = [96, 1, 0, 1, 1];
-    let mut buffer = vec![0; DEVICE_RESET_RSP.len() + DEVICE_STATUS_NTF.len()];
-    read_exact(reader, &mut buffer).unwrap();
+/// Deliver the UCI messages received by the reader task to the clients
+/// from a blocking thread. Slow clients thus do not stall the reader task,
+/// and the responses to the commands sent by the HAL, until the queue
+/// is full. The thread exits once the returned sender is dropped and
+/// the queued messages are delivered.
+fn spawn_delivery(clients: Arc<Clients>) -> (mpsc::Sender<Vec<u8>>, tokio::task::JoinHandle<()>) {
+    let (sender, mut receiver) = mpsc::channel::<Vec<u8>>(DELIVERY_QUEUE_SIZE);
+    let handle = tokio::task::spawn_blocking(move || {
+        while let Some(message) = receiver.blocking_recv() {
+            clients.on_uci_message(&message);
+        }
+    });
+    (sender, handle)
+}
 
-    // Make sure received packets are the expected ones.
-    assert_eq!(&buffer[0..DEVICE_RESET_RSP.len()], &DEVICE_RESET_RSP);
-    assert_eq!(&buffer[DEVICE_RESET_RSP.len()..], &DEVICE_STATUS_NTF);
+/// Phase of a session, tracked from the notifications of the UWBS.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum SessionPhase {
+    /// Initialized or stopped: data packets are rejected.
+    Idle,
+    /// Started, with no data packet being transferred.
+    Active,
+    /// Started, and a data packet was sent for which no
+    /// SESSION_DATA_TRANSFER_STATUS_NTF was received yet.
+    DataTransfer,
 }
 
-pub fn makeraw(file: File) -> io::Result<File> {
-    // Configure the file descriptor as raw fd.
-    use nix::sys::termios::*;
-    let mut attrs = tcgetattr(&file)?;
-    cfmakeraw(&mut attrs);
-    tcsetattr(&file, SetArg::TCSANOW, &attrs)?;
+/// Phases of the sessions of the UWBS, keyed by session handle.
+/// A data packet may only be sent to a started session.
+#[derive(Default)]
+struct SessionPhases(std::sync::Mutex<HashMap<u32, SessionPhase>>);
+
+impl SessionPhases {
+    /// Record the session state reported by a SESSION_STATUS_NTF.
+    fn update(&self, session_handle: u32, state: u8) {
+        let mut phases = self.0.lock().unwrap();
+        match state {
+            uci::SESSION_STATE_ACTIVE => {
+                let phase = phases.entry(session_handle).or_insert(SessionPhase::Active);
+                if *phase == SessionPhase::Idle {
+                    *phase = SessionPhase::Active;
+                }
+            }
+            uci::SESSION_STATE_DEINIT | uci::SESSION_STATE_ERROR => {
+                phases.remove(&session_handle);
+            }
+            _ => {
+                phases.insert(session_handle, SessionPhase::Idle);
+            }
+        }
+    }
+
+    /// Record the end of a data transfer reported by a
+    /// SESSION_DATA_TRANSFER_STATUS_NTF.
+    fn transfer_completed(&self, session_handle: u32) {
+        if let Some(phase) = self.0.lock().unwrap().get_mut(&session_handle) {
+            if *phase == SessionPhase::DataTransfer {
+                *phase = SessionPhase::Active;
+            }
+        }
+    }
+
+    /// Record a data packet sent to a session.
+    fn transfer_started(&self, session_handle: u32) {
+        if let Some(phase) = self.0.lock().unwrap().get_mut(&session_handle) {
+            if *phase == SessionPhase::Active {
+                *phase = SessionPhase::DataTransfer;
+            }
+        }
+    }
+
+    /// Return true if data packets may be sent to the session.
+    fn accepts_data(&self, session_handle: u32) -> bool {
+        matches!(
+            self.0.lock().unwrap().get(&session_handle),
+            Some(SessionPhase::Active | SessionPhase::DataTransfer)
+        )
+    }
+
+    /// Forget the phases of all sessions, after the UWBS has lost them.
+    fn reset(&self) {
+        self.0.lock().unwrap().clear();
+    }
+}
 
-    Ok(file)
+/// Data credits granted by the UWBS for each session.
+/// A data packet may only be sent when the session has a credit available,
+/// and each segment of a segmented data packet consumes a credit.
+#[derive(Default)]
+struct DataCredits {
+    available: std::sync::Mutex<HashMap<u32, bool>>,
+    notify: Notify,
+    /// Data packets being sent for each session, for the segments of
+    /// a packet to not be interleaved with another packet of the session.
+    senders: std::sync::Mutex<HashMap<u32, Arc<Mutex<()>>>>,
 }
 
-/// Wrapper around Read::read to handle EWOULDBLOCK.
-/// /!\ will actively wait for more data, make sure to call
-/// this method only when data is immediately expected.
-fn read_exact(file: &mut File, mut buf: &mut [u8]) -> io::Result<()> {
-    while buf.len() > 0 {
-        match file.read(buf) {
-            Ok(0) => panic!("unexpectedly reached end of file"),
-            Ok(read_len) => buf = &mut buf[read_len..],
-            Err(err) if err.kind() == io::ErrorKind::WouldBlock => continue,
-            Err(err) => return Err(err),
+impl DataCredits {
+    /// Record the credit availability reported by a DATA_CREDIT_NTF.
+    fn update(&self, session_handle: u32, available: bool) {
+        self.available
+            .lock()
+            .unwrap()
+            .insert(session_handle, available);
+        if available {
+            self.notify.notify_waiters();
+        }
+    }
+
+    /// Forget the credits of all sessions, after the UWBS has lost them.
+    fn reset(&self) {
+        self.available.lock().unwrap().clear();
+        self.notify.notify_waiters();
+    }
+
+    /// Wait for the data packets of a session already being sent to be
+    /// written, and hold off the next ones until the guard is dropped.
+    async fn lock_session(&self, session_handle: u32) -> tokio::sync::OwnedMutexGuard<()> {
+        let sender = self
+            .senders
+            .lock()
+            .unwrap()
+            .entry(session_handle)
+            .or_default()
+            .clone();
+        sender.lock_owned().await
+    }
+
+    /// Take the credit of a session, waiting for the UWBS to grant
+    /// a new one if needed. Sessions start with one credit available.
+    async fn acquire(&self, session_handle: u32) -> Credit<'_> {
+        loop {
+            // Register for notifications before checking availability
+            // to not miss updates happening in between.
+            let notified = self.notify.notified();
+            {
+                let mut available = self.available.lock().unwrap();
+                let credit = available.entry(session_handle).or_insert(true);
+                if *credit {
+                    *credit = false;
+                    return Credit {
+                        credits: self,
+                        session_handle,
+                    };
+                }
+            }
+            notified.await;
         }
     }
-    Ok(())
 }
 
-impl binder::Interface for UwbChip {}
+/// Credit taken from a session, given back to the session when dropped
+/// unless the data packet was written.
+#[must_use]
+struct Credit<'a> {
+    credits: &'a DataCredits,
+    session_handle: u32,
+}
 
-#[async_trait]
-impl IUwbChipAsyncServer for UwbChip {
-    async fn getName(&self) -> Result<String> {
-        Ok(self.name.clone())
+impl Credit<'_> {
+    /// Consume the credit, once the data packet is written.
+    fn consume(self) {
+        std::mem::forget(self);
     }
+}
 
-    async fn open(&self, callbacks: &Strong<dyn IUwbClientCallback>) -> Result<()> {
-        log::debug!("open: {:?}", &self.path);
+impl Drop for Credit<'_> {
+    fn drop(&mut self) {
+        self.credits.update(self.session_handle, true);
+    }
+}
 
-        let mut state = self.state.lock().await;
+pub struct UwbChip {
+    name: String,
+    transport: TransportConfig,
+    open_mode: OpenMode,
+    /// Set once the UWBS was connected a first time.
+    connected: AtomicBool,
+    /// Number of reader tasks connected to the UWBS. The reader task of a
+    /// released chip may still be running while the chip is reopened.
+    readers: Arc<AtomicUsize>,
+    read_timeout: Option<Duration>,
+    idle_timeout: Option<Duration>,
+    /// Close the chip once idle, rather than only warning the clients.
+    idle_close: bool,
+    max_packet_size: usize,
+    /// Maximum payload size of the UCI data packets written to the UWBS.
+    max_data_payload_size: Option<usize>,
+    /// Segment the larger data packets sent by the clients, rather than
+    /// rejecting them.
+    segment_data: bool,
+    /// UCI version reported by the UWBS in CORE_GET_DEVICE_INFO_RSP.
+    android_uci_version: Arc<std::sync::Mutex<Option<i32>>>,
+    /// Capabilities reported by the UWBS in CORE_GET_CAPS_INFO_RSP,
+    /// queried by coreInit().
+    capabilities: Arc<std::sync::Mutex<Option<UwbCapabilities>>>,
+    /// Most recent error of the reader task, cleared once the UWBS is
+    /// connected again.
+    last_error: Arc<std::sync::Mutex<Option<String>>>,
+    /// Recorders of the UCI packets exchanged with the UWBS.
+    captures: Vec<Arc<Capture>>,
+    reconnect_backoff: Backoff,
+    /// Number of times the reader task of an opened chip connects to the
+    /// UWBS again after it failed, before releasing the chip.
+    restart_budget: u32,
+    command_retry: CommandRetry,
+    /// Number of notifications of a reconnected UWBS retained until the
+    /// chip is opened again, none if 0.
+    pre_open_replay: usize,
+    /// Limit of the commands sent by the clients, unlimited if None.
+    rate_limiter: Option<RateLimiter>,
+    /// Counters of the UCI traffic, shared with the reader task
+    /// outside of the state lock.
+    metrics: Arc<Metrics>,
+    /// Time source of the timestamps and timeouts of the UCI traffic.
+    clock: Arc<dyn Clock>,
+    /// Check that the device speaks UCI when opened.
+    probe: bool,
+    vendor_handler: Arc<dyn VendorUciHandler>,
+    state: Arc<Mutex<State>>,
+}
+
+impl UwbChip {
+    pub fn new(name: String, path: String) -> Self {
+        Self::with_transport(name, TransportConfig::Serial(path))
+    }
+
+    /// Create a chip connecting to the UWBS over TCP.
+    pub fn new_tcp(name: String, addr: SocketAddr) -> Self {
+        Self::with_transport(name, TransportConfig::Tcp(addr))
+    }
+
+    /// Create a chip connecting to the UWBS over TCP, with the UCI packets
+    /// exchanged in frames checked with a CRC.
+    pub fn new_crc_tcp(name: String, addr: SocketAddr) -> Self {
+        Self::with_transport(name, TransportConfig::CrcTcp(addr))
+    }
+
+    /// Create a chip connecting to the UWBS over the spidev at `path`,
+    /// signaling its pending packets with the sysfs gpio `irq_gpio`.
+    #[cfg(feature = "spi")]
+    pub fn new_spi(name: String, path: String, irq_gpio: u32) -> Self {
+        Self::with_transport(name, TransportConfig::Spi { path, irq_gpio })
+    }
+
+    /// Create a chip connected to an in-memory UWBS.
+    #[cfg(test)]
+    pub fn new_mock(name: String, uwbs: crate::mock::MockUwbs) -> Self {
+        Self::with_transport(name, TransportConfig::Mock(uwbs))
+    }
 
-        if matches!(*state, State::Opened { .. }) {
-            log::error!("the state is already opened");
-            return Err(binder::ExceptionCode::ILLEGAL_STATE.into());
+    fn with_transport(name: String, transport: TransportConfig) -> Self {
+        Self {
+            name,
+            transport,
+            open_mode: OpenMode::default(),
+            connected: AtomicBool::new(false),
+            readers: Default::default(),
+            read_timeout: None,
+            idle_timeout: None,
+            idle_close: false,
+            max_packet_size: DEFAULT_MAX_PACKET_SIZE,
+            max_data_payload_size: None,
+            segment_data: false,
+            android_uci_version: Default::default(),
+            capabilities: Default::default(),
+            last_error: Default::default(),
+            captures: Vec::new(),
+            reconnect_backoff: Backoff::default(),
+            restart_budget: 0,
+            command_retry: CommandRetry::default(),
+            pre_open_replay: 0,
+            rate_limiter: None,
+            metrics: Default::default(),
+            clock: Arc::new(TokioClock),
+            probe: false,
+            vendor_handler: Arc::new(vendor::PassThrough),
+            state: Arc::new(Mutex::new(State::Closed)),
         }
+    }
 
-        let serial = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(false)
-            .custom_flags(libc::O_NONBLOCK)
-            .open(&self.path)
-            .and_then(makeraw)
-            .map_err(|_| binder::StatusCode::UNKNOWN_ERROR)?;
+    /// Select the access to the UWBS. A chip opened read-only still
+    /// delivers the UCI packets received from the UWBS, but rejects
+    /// sendUciMessage() and does not reset the device on close().
+    pub fn with_open_mode(mut self, open_mode: OpenMode) -> Self {
+        self.open_mode = open_mode;
+        self
+    }
 
-        let state_death_recipient = self.state.clone();
-        let mut death_recipient = DeathRecipient::new(move || {
-            let mut state = state_death_recipient.blocking_lock();
-            log::info!("Uwb service has died");
-            if let State::Opened { ref mut token, .. } = *state {
-                token.cancel();
-                *state = State::Closed;
-            }
-        });
+    /// Bound the time allowed for reading the remainder of a UCI packet
+    /// once its first bytes have been received. The partial packet is
+    /// discarded and the client notified of the error on timeout.
+    pub fn with_read_timeout(mut self, read_timeout: Duration) -> Self {
+        self.read_timeout = Some(read_timeout);
+        self
+    }
 
-        callbacks.as_binder().link_to_death(&mut death_recipient)?;
+    /// Warn the clients when no UCI packets are exchanged with the UWBS for
+    /// `idle_timeout` while opened. AIDL has no warning event, so they are
+    /// notified with ERROR and ERR_CMD_TIMEOUT, once per idle period.
+    pub fn with_idle_timeout(mut self, idle_timeout: Duration) -> Self {
+        self.idle_timeout = Some(idle_timeout);
+        self
+    }
 
-        let token = CancellationToken::new();
-        let cloned_token = token.clone();
-
-        let client_callbacks = callbacks.clone();
-
-        let reader = serial
-            .try_clone()
-            .map_err(|_| binder::StatusCode::UNKNOWN_ERROR)?;
-
-        let join_handle = tokio::task::spawn(async move {
-            log::info!("UCI reader task started");
-            let mut reader = AsyncFd::new(reader).unwrap();
-
-            loop {
-                const UWB_HEADER_SIZE: usize = 4;
-                let mut buffer = vec![0; UWB_HEADER_SIZE];
-
-                // The only time where the task can be safely
-                // cancelled is when no packet bytes have been read.
-                //
-                // - read_exact() cannot be used here since it is not
-                //   cancellation safe.
-                // - read() cannot be used because it cannot be cancelled:
-                //   the syscall is executed blocking on the threadpool
-                //   and completes after termination of the task when
-                //   the pipe receives more data.
-                let read_len = loop {
-                    // On some platforms, the readiness detecting mechanism
-                    // relies on edge-triggered notifications. This means that
-                    // the OS will only notify Tokio when the file descriptor
-                    // transitions from not-ready to ready. For this to work
-                    // you should first try to read or write and only poll for
-                    // readiness if that fails with an error of
-                    // std::io::ErrorKind::WouldBlock.
-                    match reader.get_mut().read(&mut buffer) {
-                        Ok(0) => {
-                            log::error!("file unexpectedly closed");
-                            return;
-                        }
-                        Ok(read_len) => break read_len,
-                        Err(err) if err.kind() == io::ErrorKind::WouldBlock => (),
-                        Err(_) => panic!("unexpected read failure"),
-                    }
+    /// Also close the chip once idle for the idle timeout. The clients are
+    /// then notified with CLOSE_CPLT, as when the UWBS closes the connection.
+    pub fn with_idle_close(mut self, idle_close: bool) -> Self {
+        self.idle_close = idle_close;
+        self
+    }
 
-                    let mut guard = select! {
-                        _ = cloned_token.cancelled() => {
-                            log::info!("task is cancelled!");
-                            return;
-                        },
-                        result = reader.readable() => result.unwrap()
-                    };
+    /// Set the maximum size of the UCI data packets received from the UWBS,
+    /// bounded by `UCI_MAX_PACKET_SIZE`. Larger packets are discarded.
+    pub fn with_max_packet_size(mut self, max_packet_size: usize) -> Self {
+        self.max_packet_size = max_packet_size.min(UCI_MAX_PACKET_SIZE);
+        self
+    }
 
-                    guard.clear_ready();
-                };
+    /// Set the maximum payload size of the UCI data packets written to the
+    /// UWBS. Larger data packets sent by the clients are rejected, unless
+    /// segmentation is enabled.
+    pub fn with_max_data_payload_size(mut self, max_data_payload_size: usize) -> Self {
+        self.max_data_payload_size = Some(max_data_payload_size.max(1));
+        self
+    }
 
-                // Read the remaining header bytes, if truncated.
-                read_exact(reader.get_mut(), &mut buffer[read_len..]).unwrap();
+    /// Split the data packets sent by the clients which exceed the maximum
+    /// data payload size into segments chained with the packet boundary
+    /// flag, written in order.
+    pub fn with_data_segmentation(mut self, segment_data: bool) -> Self {
+        self.segment_data = segment_data;
+        self
+    }
 
-                let length = buffer[3] as usize + UWB_HEADER_SIZE;
-                buffer.resize(length, 0);
+    /// Configure the delays between the attempts to reconnect to the
+    /// UWBS after it failed.
+    pub fn with_reconnect_backoff(mut self, reconnect_backoff: Backoff) -> Self {
+        self.reconnect_backoff = reconnect_backoff;
+        self
+    }
 
-                // Read the payload bytes.
-                read_exact(reader.get_mut(), &mut buffer[UWB_HEADER_SIZE..]).unwrap();
+    /// Connect to the UWBS again up to `restart_budget` times after it
+    /// failed while the chip is opened, without notifying the clients.
+    /// The chip is released and reconnected once the budget is exhausted.
+    pub fn with_restart_budget(mut self, restart_budget: u32) -> Self {
+        self.restart_budget = restart_budget;
+        self
+    }
 
-                client_callbacks.onUciMessage(&buffer).unwrap();
-            }
+    /// Keep reading the UWBS once reconnected after a failure, and replay
+    /// the last `notifications` received until the chip is opened again to
+    /// the next client, e.g. the device status notification sent by the
+    /// UWBS once it has rebooted. Disabled if 0: the UWBS is then only
+    /// connected again by open().
+    pub fn with_pre_open_replay(mut self, notifications: usize) -> Self {
+        self.pre_open_replay = notifications;
+        self
+    }
+
+    /// Limit the rate of the commands sent by each client process. The
+    /// data packets are not limited, their flow is controlled by the UWBS.
+    pub fn with_rate_limit(mut self, limit: RateLimit) -> Self {
+        self.rate_limiter = Some(RateLimiter {
+            limit,
+            buckets: Default::default(),
         });
+        self
+    }
 
-        callbacks.onHalEvent(UwbEvent::OPEN_CPLT, UwbStatus::OK)?;
+    /// Configure the attempts of the commands sent by the HAL, e.g. to
+    /// probe the device, before failing with a timeout.
+    pub fn with_command_retry(mut self, command_retry: CommandRetry) -> Self {
+        self.command_retry = command_retry;
+        self
+    }
 
-        *state = State::Opened {
-            callbacks: callbacks.clone(),
-            handle: join_handle,
-            serial,
-            death_recipient,
-            token,
-        };
+    /// Replace the tokio clock timing the UCI traffic, e.g. with a fake
+    /// clock advanced by the tests.
+    #[cfg(test)]
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
 
-        Ok(())
+    /// Probe the device with CORE_GET_DEVICE_INFO_CMD when opened, and
+    /// fail the open if it does not respond with a valid response.
+    pub fn with_probe(mut self, probe: bool) -> Self {
+        self.probe = probe;
+        self
     }
 
-    async fn close(&self) -> Result<()> {
-        log::debug!("close");
+    /// Intercept the messages of the vendor specific groups received from
+    /// the UWBS, and extract the vendor specific values of the UWBS.
+    /// By default the vendor messages are all delivered to the clients.
+    pub fn with_vendor_handler(mut self, vendor_handler: Arc<dyn VendorUciHandler>) -> Self {
+        self.vendor_handler = vendor_handler;
+        self
+    }
 
-        let mut state = self.state.lock().await;
+    /// Record all UCI packets exchanged with the UWBS to a pcapng file.
+    /// Capture is best-effort: errors are logged and never affect
+    /// the communication with the UWBS.
+    pub fn with_capture(mut self, path: impl Into<PathBuf>) -> Self {
+        self.captures.push(Arc::new(Capture::new(path)));
+        self
+    }
 
-        if let State::Opened { .. } = *state {
-            state.close().await
-        } else {
-            Err(binder::ExceptionCode::ILLEGAL_STATE.into())
-        }
+    /// Copy all UCI packets exchanged with the UWBS to a secondary sink,
+    /// e.g. a socket connected to a hardware sniffer. Like the capture,
+    /// the copy is best-effort.
+    pub fn with_tee(mut self, sink: impl std::io::Write + Send + 'static) -> Self {
+        self.captures.push(Arc::new(Capture::tee(sink)));
+        self
     }
 
-    async fn coreInit(&self) -> Result<()> {
-        log::debug!("coreInit");
+    /// Name of the chip reported by getName().
+    pub fn name(&self) -> &str {
+        &self.name
+    }
 
-        if let State::Opened { ref callbacks, .. } = *self.state.lock().await {
-            callbacks.onHalEvent(UwbEvent::POST_INIT_CPLT, UwbStatus::OK)?;
-            Ok(())
-        } else {
-            Err(binder::ExceptionCode::ILLEGAL_STATE.into())
+    /// Return the UCI traffic counters since the chip was created.
+    pub fn metrics_snapshot(&self) -> UwbMetrics {
+        self.metrics.snapshot()
+    }
+
+    /// Query the UWBS information with CORE_GET_DEVICE_INFO_CMD.
+    /// The chip must be opened.
+    pub async fn get_device_info(&self) -> Result<DeviceInfo> {
+        Ok(query_device_info(&self.state, self.command_retry).await?)
+    }
+
+    /// Describe the transport of the chip, and whether it is currently
+    /// connected to the UWBS.
+    pub fn transport_info(&self) -> TransportInfo {
+        TransportInfo {
+            kind: self.transport.kind(),
+            location: self.transport.location(),
+            connected: self.readers.load(Ordering::Relaxed) > 0,
         }
     }
 
-    async fn sessionInit(&self, _id: i32) -> Result<()> {
-        log::debug!("sessionInit");
+    /// Set the app configurations of the session `session_handle` with
+    /// SESSION_SET_APP_CONFIG_CMD, and return the configurations rejected
+    /// by the UWBS. The chip must be opened.
+    pub async fn set_app_config(
+        &self,
+        session_handle: u32,
+        configs: &[(AppConfigTag, Vec<u8>)],
+    ) -> Result<AppConfigStatus> {
+        let cmd = uci::UciCommandBuilder::session_set_app_config(session_handle, configs)
+            .map_err(|tag| HalError::InvalidArgument(format!("unknown app config {:?}", tag)))?;
+        let rsp = send_command_await_response(&self.state, cmd, self.command_retry).await?;
+        Ok(uci::parse_set_app_config_rsp(&rsp).ok_or_else(|| {
+            tracing::error!(length = rsp.len(), "invalid set app config response");
+            HalError::ProtocolError("invalid set app config response".to_owned())
+        })?)
+    }
 
+    /// Deinitialize the session `id` initialized by sessionInit() with
+    /// SESSION_DEINIT_CMD, and stop tracking it once the UWBS accepts
+    /// the deinit. Fails with IllegalState if the session is not
+    /// initialized.
+    pub async fn session_deinit(&self, id: i32) -> Result<()> {
+        match *self.state.lock().await {
+            State::Opened { ref sessions, .. } if sessions.contains_key(&id) => (),
+            _ => {
+                tracing::error!(session_id = id, "session is not initialized");
+                return Err(HalError::IllegalState.into());
+            }
+        }
+        let cmd = uci::UciCommandBuilder::session_deinit(id as u32);
+        let rsp = send_command_await_response(&self.state, cmd, self.command_retry).await?;
+        let status = uci::response_status(&rsp);
+        if status != Some(uci::STATUS_OK) {
+            tracing::error!(session_id = id, ?status, "session deinit failed");
+            return Err(HalError::ProtocolError(format!(
+                "session deinit failed with status {:?}",
+                status
+            ))
+            .into());
+        }
+        if let State::Opened {
+            ref mut sessions, ..
+        } = *self.state.lock().await
+        {
+            sessions.remove(&id);
+        }
         Ok(())
     }
 
-    async fn getSupportedAndroidUciVersion(&self) -> Result<i32> {
-        Ok(1)
+    /// Capabilities of the UWBS, once queried by coreInit(). No standard
+    /// capability carries the maximum number of sessions: it is only known
+    /// from the vendor handler, e.g. as listed in the configuration file,
+    /// and the sessions are not limited otherwise.
+    pub fn capabilities(&self) -> Option<UwbCapabilities> {
+        self.capabilities.lock().unwrap().clone()
     }
 
-    async fn sendUciMessage(&self, data: &[u8]) -> Result<i32> {
-        log::debug!("sendUciMessage");
+    /// Most recent error of the reader task, e.g. an I/O error of the
+    /// transport, until the UWBS is connected again.
+    pub fn last_error(&self) -> Option<String> {
+        self.last_error.lock().unwrap().clone()
+    }
 
-        if let State::Opened { ref mut serial, .. } = &mut *self.state.lock().await {
-            serial
-                .write(data)
-                .map(|written| written as i32)
-                .map_err(|_| binder::StatusCode::UNKNOWN_ERROR.into())
-        } else {
-            Err(binder::ExceptionCode::ILLEGAL_STATE.into())
-        }
+    /// Register a client like open(), only forwarding it the UCI messages
+    /// selected by `filter`, e.g. the data messages. The HAL events are
+    /// still notified to the client.
+    #[tracing::instrument(level = "debug", skip_all, fields(chip = %self.name))]
+    pub async fn open_with_filter(
+        &self,
+        callbacks: &Strong<dyn IUwbClientCallback>,
+        filter: MessageFilter,
+    ) -> Result<()> {
+        tracing::debug!(transport = ?self.transport, "open");
+
+        let mut state = self.state.lock().await;
+
+        if let State::Reconnecting { ref token } = *state {
+            // Connect immediately instead of waiting for the next attempt.
+            token.cancel();
+            *state = State::Closed;
+        }
+
+        if let State::Opened { ref clients, .. } = *state {
+            if clients.contains(&callbacks.as_binder()) {
+                tracing::error!("the state is already opened");
+                return Err(HalError::IllegalState.into());
+            }
+
+            // Additional clients share the opened device.
+            tracing::info!("registering additional client");
+            self.add_client(clients, callbacks, filter)?;
+            notify_open_complete(clients.clone(), callbacks.as_binder());
+            return Ok(());
+        }
+
+        // A device in standby is already connected.
+        let (mut packets, writer, replayed) = match state.take_standby().await {
+            Some(standby) => standby,
+            None => {
+                let attempt = if self.connected.load(Ordering::Relaxed) {
+                    Attempt::Reconnect
+                } else {
+                    Attempt::First
+                };
+                let transport = self
+                    .transport
+                    .connect(self.open_mode, attempt)
+                    .await
+                    .map_err(HalError::from)?;
+                self.connected.store(true, Ordering::Relaxed);
+                let (reader, writer) = tokio::io::split(transport);
+                let packets = frame(reader, self.max_packet_size, self.read_timeout, &self.clock);
+                (packets, writer, Vec::new())
+            }
+        };
+        self.readers.fetch_add(1, Ordering::Relaxed);
+        let last_traffic = Arc::new(LastTraffic::new(self.clock.clone()));
+        let writer = (self.open_mode == OpenMode::ReadWrite).then(|| {
+            WriteQueue::spawn(
+                writer,
+                self.captures.clone(),
+                self.metrics.clone(),
+                last_traffic.clone(),
+            )
+        });
+
+        let clients = Arc::new(Clients::default());
+        self.add_client(&clients, callbacks, filter)?;
+        let (messages, delivery) = spawn_delivery(clients.clone());
+        // The notifications received in standby are delivered first.
+        for message in replayed {
+            let _ = messages.send(message).await;
+        }
+
+        let token = CancellationToken::new();
+        let credits = Arc::new(DataCredits::default());
+        let phases = Arc::new(SessionPhases::default());
+        let pending_rsp = Arc::new(PendingResponses::new(
+            self.metrics.clone(),
+            self.clock.clone(),
+        ));
+        let context = ReaderContext {
+            state: self.state.clone(),
+            clients: clients.clone(),
+            messages,
+            token: token.clone(),
+            idle_timeout: self.idle_timeout,
+            idle_close: self.idle_close,
+            last_traffic,
+            credits: credits.clone(),
+            phases: phases.clone(),
+            android_uci_version: self.android_uci_version.clone(),
+            pending_rsp: pending_rsp.clone(),
+            captures: self.captures.clone(),
+            metrics: self.metrics.clone(),
+            clock: self.clock.clone(),
+            vendor_handler: self.vendor_handler.clone(),
+        };
+
+        let reader_state = self.state.clone();
+        let reader_transport = self.transport.clone();
+        let open_mode = self.open_mode;
+        let reconnect_backoff = self.reconnect_backoff;
+        let readers = self.readers.clone();
+        let restart_budget = self.restart_budget;
+        let max_packet_size = self.max_packet_size;
+        let read_timeout = self.read_timeout;
+        let last_error = self.last_error.clone();
+        let replay = (self.pre_open_replay > 0).then(|| PreOpenReplay {
+            notifications: self.pre_open_replay,
+            max_packet_size,
+            read_timeout,
+            readers: self.readers.clone(),
+        });
+        let reader_span = tracing::info_span!("uci_reader", chip = %self.name);
+        let reader_task = async move {
+            tracing::info!("UCI reader task started");
+            let mut restarts = 0;
+            let result = loop {
+                let result = select! {
+                    result = read_uci_packets(&mut packets, &context) => result,
+                    _ = watch_inactivity(&context) => {
+                        readers.fetch_sub(1, Ordering::Relaxed);
+                        select! {
+                            _ = context.token.cancelled() => (),
+                            mut state = reader_state.lock() => state.close_and_notify(),
+                        };
+                        return Ok(());
+                    }
+                };
+                readers.fetch_sub(1, Ordering::Relaxed);
+                let Err(ref err) = result else {
+                    break result;
+                };
+                *last_error.lock().unwrap() = Some(err.to_string());
+                if restarts == restart_budget {
+                    break result;
+                }
+                tracing::error!("UCI reader task failed, connecting again: {}", err);
+                context.metrics.record_error();
+                let restarted = restart_reader(
+                    &context,
+                    &reader_transport,
+                    open_mode,
+                    max_packet_size,
+                    read_timeout,
+                )
+                .await;
+                let Some(restarted) = restarted else {
+                    break result;
+                };
+                packets = restarted;
+                *last_error.lock().unwrap() = None;
+                restarts += 1;
+                readers.fetch_add(1, Ordering::Relaxed);
+                context.metrics.record_reader_restart();
+            };
+            if let Err(ref err) = result {
+                if err.kind() == io::ErrorKind::UnexpectedEof {
+                    // The device was closed, e.g. powered down: release it
+                    // without attempting to reconnect.
+                    tracing::info!("UWBS closed the connection");
+                    select! {
+                        _ = context.token.cancelled() => (),
+                        mut state = reader_state.lock() => state.close_and_notify(),
+                    };
+                    return result;
+                }
+                tracing::error!("UCI reader task failed: {}", err);
+                context.metrics.record_error();
+                // close() cancels the task before waiting for it to complete
+                // while holding the state lock: stop contending for the lock
+                // in this case.
+                let reconnect_token = select! {
+                    _ = context.token.cancelled() => None,
+                    mut state = reader_state.lock() => state.abort(),
+                };
+                if let Some(token) = reconnect_token {
+                    drop(packets);
+                    let reconnected = reconnect(
+                        &reader_state,
+                        &reader_transport,
+                        open_mode,
+                        reconnect_backoff,
+                        &token,
+                        replay,
+                        &context.clock,
+                    )
+                    .await;
+                    if reconnected {
+                        *last_error.lock().unwrap() = None;
+                    }
+                }
+            }
+            result
+        };
+        let join_handle = tokio::task::spawn(reader_task.instrument(reader_span));
+
+        // The probe cannot be sent to a read-only chip.
+        if self.probe && writer.is_some() {
+            probe_and_notify_open_complete(
+                self.state.clone(),
+                clients.clone(),
+                callbacks.as_binder(),
+                self.command_retry,
+            );
+        } else {
+            notify_open_complete(clients.clone(), callbacks.as_binder());
+        }
+
+        *state = State::Opened {
+            clients,
+            handle: join_handle,
+            delivery,
+            writer,
+            token,
+            sessions: HashMap::new(),
+            credits,
+            phases,
+            pending_rsp,
+        };
+
+        Ok(())
+    }
+
+    /// Release the chip when the service shuts down. The UCI messages
+    /// already received from the UWBS are delivered to the clients first,
+    /// within a short grace period.
+    pub async fn shutdown(&self) {
+        self.state.lock().await.shutdown().await;
+    }
+
+    /// Register the session `id` initialized by sessionInit(), within the
+    /// maximum number of sessions of the UWBS.
+    async fn insert_session(&self, id: i32) -> Result<()> {
+        if let State::Opened {
+            ref mut sessions, ..
+        } = *self.state.lock().await
+        {
+            let max_sessions = self
+                .capabilities
+                .lock()
+                .unwrap()
+                .as_ref()
+                .and_then(|capabilities| capabilities.max_sessions);
+            if max_sessions.is_some_and(|max_sessions| sessions.len() >= max_sessions) {
+                tracing::error!(session_id = id, "too many sessions");
+                return Err(HalError::IllegalState.into());
+            }
+            match sessions.entry(id) {
+                Entry::Vacant(entry) => {
+                    entry.insert(tracing::debug_span!("session", session_id = id));
+                    Ok(())
+                }
+                Entry::Occupied(_) => {
+                    tracing::error!(session_id = id, "session is already initialized");
+                    Err(HalError::IllegalState.into())
+                }
+            }
+        } else {
+            Err(HalError::IllegalState.into())
+        }
+    }
+
+    /// Wait until `client` may send a command, or fail with Busy if the
+    /// rate limit is exceeded and the commands are not delayed.
+    async fn throttle(&self, client: i32) -> HalResult<()> {
+        let Some(ref limiter) = self.rate_limiter else {
+            return Ok(());
+        };
+        loop {
+            match limiter.try_acquire(client, self.clock.now()) {
+                Ok(()) => return Ok(()),
+                Err(_) if limiter.limit.policy == RateLimitPolicy::Reject => {
+                    tracing::warn!(client, "command rate limit exceeded");
+                    return Err(HalError::Busy);
+                }
+                Err(delay) => self.clock.sleep(delay).await,
+            }
+        }
+    }
+
+    async fn send_uci_message(&self, data: &[u8]) -> HalResult<i32> {
+        // A malformed packet would desynchronize the UWBS.
+        let header = uci::parse_packet(data).map_err(|err| {
+            tracing::error!("invalid UCI packet: {}", err);
+            HalError::InvalidArgument(err.to_string())
+        })?;
+        let packets = match self.max_data_payload_size {
+            Some(max)
+                if header.message_type == MessageType::Data && header.payload_length > max =>
+            {
+                if !self.segment_data {
+                    tracing::error!(
+                        length = header.payload_length,
+                        max,
+                        "UCI data packet is too large"
+                    );
+                    return Err(HalError::InvalidArgument(format!(
+                        "data payload of {} bytes exceeds {} bytes",
+                        header.payload_length, max
+                    )));
+                }
+                uci::segment_data_packet(data, max)
+            }
+            _ => vec![data.to_vec()],
+        };
+
+        let (queue, credits, phases) = match *self.state.lock().await {
+            State::Opened { writer: None, .. } => return Err(HalError::NotSupported),
+            State::Opened {
+                writer: Some(ref writer),
+                ref credits,
+                ref phases,
+                ..
+            } => (writer.clone(), credits.clone(), phases.clone()),
+            _ => return Err(HalError::IllegalState),
+        };
+
+        // Data packets must wait for a credit from the UWBS for each of
+        // their segments, control packets are written immediately.
+        // The credit is given back if the segment is not written.
+        let Some(session_handle) = uci::data_packet_session_handle(data) else {
+            self.write_packets(&queue, packets, data).await?;
+            trace_uci_message("UCI message sent", data);
+            return Ok(data.len() as i32);
+        };
+        if !phases.accepts_data(session_handle) {
+            tracing::error!(session_handle, "session is not started");
+            return Err(HalError::IllegalState);
+        }
+        let _sending = credits.lock_session(session_handle).await;
+        for packet in packets {
+            let credit = credits.acquire(session_handle);
+            let credit = match self.read_timeout {
+                Some(timeout) => clock::timeout(&*self.clock, timeout, credit)
+                    .await
+                    .ok_or_else(|| {
+                        tracing::error!(session_handle, "timed out waiting for the session credit");
+                        HalError::Timeout
+                    })?,
+                None => credit.await,
+            };
+            self.write_packets(&queue, vec![packet], data).await?;
+            credit.consume();
+        }
+        trace_uci_message("UCI message sent", data);
+        phases.transfer_started(session_handle);
+        Ok(data.len() as i32)
+    }
+
+    /// Write the packets of the UCI message `data` to `queue`, and wait
+    /// for them to be written.
+    async fn write_packets(
+        &self,
+        queue: &WriteQueue,
+        packets: Vec<Vec<u8>>,
+        data: &[u8],
+    ) -> HalResult<()> {
+        // The packets are queued with the state lock held, to register the
+        // session deinit in order, but written once the lock is released.
+        let slot = queue.reserve().await?;
+        let written = if let State::Opened {
+            writer: Some(ref writer),
+            ref sessions,
+            ref pending_rsp,
+            ..
+        } = *self.state.lock().await
+        {
+            // The chip may have been reopened while waiting for the slot.
+            if !writer.same_queue(queue) {
+                return Err(HalError::IllegalState);
+            }
+            // The deinit of the sessions not initialized by sessionInit(),
+            // e.g. with raw UCI commands, is forwarded without tracking.
+            let session_deinit = uci::parse_session_deinit_cmd(data)
+                .map(|id| id as i32)
+                .and_then(|id| Some((id, sessions.get(&id)?)));
+            let recipient = match session_deinit {
+                Some((id, span)) => {
+                    tracing::debug!(parent: span, session_id = id, "session deinit");
+                    let (recipient, receiver) = Recipient::new(true);
+                    tokio::task::spawn(remove_session_on_deinit_rsp(
+                        self.state.clone(),
+                        pending_rsp.clone(),
+                        id,
+                        receiver,
+                    ));
+                    Some(recipient)
+                }
+                None => None,
+            };
+            pending_rsp.write(slot, packets, recipient.as_ref(), UCI_RESPONSE_TIMEOUT)
+        } else {
+            return Err(HalError::IllegalState);
+        };
+
+        Ok(written.await?)
+    }
+
+    /// Register a client, and link to its death to unregister it
+    /// if it dies.
+    fn add_client(
+        &self,
+        clients: &Clients,
+        callbacks: &Strong<dyn IUwbClientCallback>,
+        filter: MessageFilter,
+    ) -> Result<()> {
+        let state_death_recipient = self.state.clone();
+        let binder = callbacks.as_binder();
+        let runtime = tokio::runtime::Handle::current();
+        let mut death_recipient = DeathRecipient::new(move || {
+            remove_dead_client(&runtime, state_death_recipient.clone(), binder.clone());
+        });
+
+        match callbacks.as_binder().link_to_death(&mut death_recipient) {
+            // Local binders, e.g. the callbacks of the unit tests,
+            // cannot be linked to death.
+            Ok(()) | Err(binder::StatusCode::INVALID_OPERATION) => (),
+            Err(err) => return Err(err.into()),
+        }
+
+        clients.add(Client {
+            callbacks: callbacks.clone(),
+            death_recipient,
+            filter,
+        });
+        Ok(())
+    }
+}
+
+/// Unregister a client that has died. The death notification may be
+/// received on a thread of the runtime, where the state lock cannot be
+/// waited for synchronously: the client is removed by a task instead.
+fn remove_dead_client(
+    runtime: &tokio::runtime::Handle,
+    state: Arc<Mutex<State>>,
+    binder: SpIBinder,
+) {
+    runtime.spawn(async move {
+        tracing::info!("Uwb service has died");
+        state.lock().await.remove_client(&binder);
+    });
+}
+
+impl Drop for UwbChip {
+    fn drop(&mut self) {
+        // Cancel the reader task and release the device. The state also
+        // needs to be reset to break the reference cycle created by the
+        // death recipient. The lock can only be held by the reader task or
+        // the removal of a dead client, which are already closing the state
+        // then.
+        if let Ok(mut state) = self.state.try_lock() {
+            if let State::Opened { .. } = *state {
+                tracing::info!("releasing chip {}", self.name);
+            }
+            state.release();
+        }
+    }
+}
+
+impl State {
+    /// Reset the device and terminate the reader task. The clients are
+    /// notified with CLOSE_CPLT, with FAILED status if the device could
+    /// not be reset. Returns NotSupported, once closed, if the chip is
+    /// read-only and the device was thus not reset.
+    async fn close(&mut self) -> HalResult<()> {
+        let mut result = Ok(());
+        if let State::Opened {
+            ref mut token,
+            ref clients,
+            ref mut handle,
+            ref writer,
+            ref pending_rsp,
+            ..
+        } = *self
+        {
+            let mut clients = clients.take();
+            for client in clients.iter_mut() {
+                client.unlink();
+            }
+
+            // The reader task keeps running until the device reset response,
+            // and the device status notification which follows it, are
+            // received, so that they are not delivered to the next client.
+            let (recipient, reset_rsp_receiver) = Recipient::new(false);
+            let status_ntf_receiver =
+                pending_rsp.expect_notification(uci::GID_CORE, uci::OID_CORE_DEVICE_STATUS);
+
+            // DeviceResetCmd need to be send to reset the device to stop all running
+            // activities on UWBS.
+            let packets = uci::UciCommandBuilder::core_device_reset(ResetConfig::UwbsReset);
+            let mut status = UwbStatus::OK;
+            match writer {
+                Some(writer) => {
+                    let written = match writer.reserve().await {
+                        Ok(slot) => {
+                            pending_rsp
+                                .write(slot, packets, Some(&recipient), UCI_RESPONSE_TIMEOUT)
+                                .await
+                        }
+                        Err(err) => Err(err),
+                    };
+                    if let Err(err) = written {
+                        tracing::error!("failed to write UCI Device Reset command: {}", err);
+                        status = UwbStatus::FAILED;
+                    }
+                }
+                None => {
+                    tracing::warn!("not resetting the device of a read-only chip");
+                    status = UwbStatus::FAILED;
+                    result = Err(HalError::NotSupported);
+                }
+            }
+
+            if status == UwbStatus::OK {
+                match clock::timeout(
+                    &*pending_rsp.clock,
+                    UCI_RESPONSE_TIMEOUT,
+                    reset_rsp_receiver,
+                )
+                .await
+                {
+                    Some(Ok(_)) => (),
+                    Some(Err(_)) => {
+                        tracing::warn!("UCI reader task exited before the device reset response")
+                    }
+                    None => {
+                        tracing::warn!("timed out waiting for the device reset response");
+                        pending_rsp.timed_out();
+                    }
+                }
+                if clock::timeout(
+                    &*pending_rsp.clock,
+                    UCI_RESPONSE_TIMEOUT,
+                    status_ntf_receiver,
+                )
+                .await
+                .is_none()
+                {
+                    tracing::warn!("timed out waiting for the device status after the reset");
+                }
+            }
+
+            tracing::info!("waiting for task cancellation");
+            token.cancel();
+            if let Err(err) = handle.await.unwrap() {
+                tracing::warn!("UCI reader task exited with error: {}", err);
+            }
+            tracing::info!("task successfully cancelled");
+            *self = State::Closed;
+            for client in clients.iter() {
+                if let Err(err) = client.callbacks.onHalEvent(UwbEvent::CLOSE_CPLT, status) {
+                    tracing::warn!(
+                        "failed to notify HAL event {:?}: {:?}",
+                        UwbEvent::CLOSE_CPLT,
+                        err
+                    );
+                }
+            }
+        }
+        result
+    }
+
+    /// Terminate the reader task and release the device on shutdown,
+    /// once the UCI messages already received are delivered to the clients.
+    /// The device is not reset.
+    async fn shutdown(&mut self) {
+        if let State::Opened {
+            ref token,
+            ref mut handle,
+            ref mut delivery,
+            ref pending_rsp,
+            ..
+        } = *self
+        {
+            token.cancel();
+            let delivered = async {
+                let _ = handle.await;
+                let _ = delivery.await;
+            };
+            if clock::timeout(&*pending_rsp.clock, SHUTDOWN_GRACE_PERIOD, delivered)
+                .await
+                .is_none()
+            {
+                tracing::warn!("timed out waiting for the UCI reader task");
+            }
+        }
+        self.release();
+    }
+
+    /// Release the device after it was closed by the UWBS, e.g. when it
+    /// is powered down, or left idle, and notify the clients with CLOSE_CPLT.
+    fn close_and_notify(&mut self) {
+        if let State::Opened { ref clients, .. } = *self {
+            let clients = clients.take();
+            *self = State::Closed;
+            for mut client in clients {
+                client.unlink();
+                if let Err(err) = client
+                    .callbacks
+                    .onHalEvent(UwbEvent::CLOSE_CPLT, UwbStatus::OK)
+                {
+                    tracing::warn!("failed to notify HAL close: {:?}", err);
+                }
+            }
+        }
+    }
+
+    /// Release the device after a fatal error in the reader task,
+    /// and notify the clients. Returns the token cancelling the
+    /// reconnection of the device.
+    fn abort(&mut self) -> Option<CancellationToken> {
+        if let State::Opened { ref clients, .. } = *self {
+            for mut client in clients.take() {
+                client.unlink();
+                if let Err(err) = client
+                    .callbacks
+                    .onHalEvent(UwbEvent::ERROR, UwbStatus::FAILED)
+                {
+                    tracing::warn!("failed to notify HAL error: {:?}", err);
+                }
+            }
+            let token = CancellationToken::new();
+            *self = State::Reconnecting {
+                token: token.clone(),
+            };
+            Some(token)
+        } else {
+            None
+        }
+    }
+
+    /// Stop reading a device in standby, and return its connection with
+    /// the notifications received since it was reconnected. Returns None
+    /// if the state is not Standby, or if the connection failed.
+    async fn take_standby(&mut self) -> Option<(Reader, Writer, Vec<Vec<u8>>)> {
+        match std::mem::replace(self, State::Closed) {
+            State::Standby {
+                token,
+                reader,
+                writer,
+                notifications,
+            } => {
+                token.cancel();
+                let reader = reader.await.ok().flatten()?;
+                let notifications = std::mem::take(&mut *notifications.lock().unwrap());
+                Some((reader, writer, notifications.into()))
+            }
+            state => {
+                *self = state;
+                None
+            }
+        }
+    }
+
+    /// Cancel the reader task and unregister the clients, without
+    /// resetting the device.
+    fn release(&mut self) {
+        match *self {
+            State::Opened {
+                ref token,
+                ref clients,
+                ..
+            } => {
+                token.cancel();
+                for mut client in clients.take() {
+                    client.unlink();
+                }
+            }
+            State::Reconnecting { ref token } | State::Standby { ref token, .. } => token.cancel(),
+            State::Closed => (),
+        }
+        *self = State::Closed;
+    }
+
+    /// Unregister a client that has died, and release the device
+    /// if it was the last one.
+    fn remove_client(&mut self, binder: &SpIBinder) {
+        if let State::Opened {
+            ref clients,
+            ref token,
+            ..
+        } = *self
+        {
+            if clients.remove(binder) {
+                token.cancel();
+                *self = State::Closed;
+            }
+        }
+    }
+}
+
+/// Wait for the device to become available again after a failure.
+/// The state returns to Closed once the device could be reopened,
+/// unless the reconnection was cancelled in the meantime, or to Standby
+/// if the notifications received before open() are replayed. Returns
+/// whether the device could be reopened.
+async fn reconnect(
+    state: &Mutex<State>,
+    transport: &TransportConfig,
+    open_mode: OpenMode,
+    backoff: Backoff,
+    token: &CancellationToken,
+    replay: Option<PreOpenReplay>,
+    clock: &Arc<dyn Clock>,
+) -> bool {
+    tracing::info!(?transport, "reconnecting");
+    let Some(connection) = transport
+        .reconnect(open_mode, backoff, token, &**clock)
+        .await
+    else {
+        return false;
+    };
+    let mut state = state.lock().await;
+    if token.is_cancelled() {
+        return false;
+    }
+    let Some(replay) = replay else {
+        tracing::info!(?transport, "reconnected, waiting for open()");
+        *state = State::Closed;
+        return true;
+    };
+    tracing::info!(?transport, "reconnected, reading the device until open()");
+    let (reader, writer) = tokio::io::split(connection);
+    let token = CancellationToken::new();
+    let notifications = Arc::new(std::sync::Mutex::new(VecDeque::new()));
+    let packets = frame(reader, replay.max_packet_size, replay.read_timeout, clock);
+    let read = read_before_open(
+        packets,
+        replay.notifications,
+        notifications.clone(),
+        token.clone(),
+    );
+    let readers = replay.readers;
+    readers.fetch_add(1, Ordering::Relaxed);
+    let reader = tokio::task::spawn(
+        async move {
+            let reader = read.await;
+            readers.fetch_sub(1, Ordering::Relaxed);
+            reader
+        }
+        .in_current_span(),
+    );
+    *state = State::Standby {
+        token,
+        reader,
+        writer,
+        notifications,
+    };
+    true
+}
+
+/// Notifications of a reconnected UWBS retained until open(), with the
+/// framing of its packets.
+struct PreOpenReplay {
+    /// Number of notifications retained, the older ones are dropped.
+    notifications: usize,
+    max_packet_size: usize,
+    read_timeout: Option<Duration>,
+    /// Readers of the chip, counting the task reading the device in standby.
+    readers: Arc<AtomicUsize>,
+}
+
+/// Read the UWBS until the token is cancelled by open(), retaining the
+/// last `count` notifications. Returns the reader once cancelled, or None
+/// if the connection failed.
+async fn read_before_open(
+    mut packets: Reader,
+    count: usize,
+    notifications: Arc<std::sync::Mutex<VecDeque<Vec<u8>>>>,
+    token: CancellationToken,
+) -> Option<Reader> {
+    let mut reassembler = Reassembler::default();
+    loop {
+        let packet = select! {
+            _ = token.cancelled() => return Some(packets),
+            packet = packets.next() => packet,
+        };
+        let packet = match packet {
+            Some(Ok(packet)) => packet,
+            Some(Err(HalError::IoError(err))) => {
+                tracing::error!("failed to read the UWBS before open(): {}", err);
+                return None;
+            }
+            Some(Err(_)) => continue,
+            None => return None,
+        };
+        let Some(message) = reassembler.push(packet.header, packet.bytes) else {
+            continue;
+        };
+        if packet.header.message_type != MessageType::Notification {
+            continue;
+        }
+        let mut notifications = notifications.lock().unwrap();
+        if notifications.len() == count {
+            notifications.pop_front();
+        }
+        notifications.push_back(message);
+    }
+}
+
+/// Frame the UCI packets read from a connection to the UWBS.
+fn frame(
+    reader: ReadHalf<Box<dyn Transport>>,
+    max_packet_size: usize,
+    read_timeout: Option<Duration>,
+    clock: &Arc<dyn Clock>,
+) -> Reader {
+    let packets = UciFramedReader::new(reader, max_packet_size);
+    match read_timeout {
+        Some(read_timeout) => packets.with_read_timeout(read_timeout, clock.clone()),
+        None => packets,
+    }
+}
+
+/// Connect the reader task of an opened chip to the UWBS again after it
+/// failed, and replace the writer of the chip. Returns None if the UWBS
+/// cannot be connected, or if the chip was closed in the meantime.
+async fn restart_reader(
+    context: &ReaderContext,
+    transport: &TransportConfig,
+    open_mode: OpenMode,
+    max_packet_size: usize,
+    read_timeout: Option<Duration>,
+) -> Option<Reader> {
+    // close() waits for the reader task while holding the state lock.
+    let connection = select! {
+        _ = context.token.cancelled() => return None,
+        connection = transport.connect(open_mode, Attempt::Reconnect) => connection,
+    };
+    let (reader, writer) = match connection {
+        Ok(connection) => tokio::io::split(connection),
+        Err(err) => {
+            tracing::error!("failed to connect to the UWBS again: {}", err);
+            return None;
+        }
+    };
+    let mut state = select! {
+        _ = context.token.cancelled() => return None,
+        state = context.state.lock() => state,
+    };
+    let State::Opened {
+        ref clients,
+        writer: ref mut opened_writer,
+        ..
+    } = *state
+    else {
+        return None;
+    };
+    if !Arc::ptr_eq(clients, &context.clients) {
+        return None;
+    }
+    // A read-only chip has no writer.
+    if opened_writer.is_some() {
+        *opened_writer = Some(WriteQueue::spawn(
+            writer,
+            context.captures.clone(),
+            context.metrics.clone(),
+            context.last_traffic.clone(),
+        ));
+    }
+    Some(frame(reader, max_packet_size, read_timeout, &context.clock))
+}
+
+/// Send a command on behalf of the HAL, and wait for its response.
+/// The command is sent again each time the UWBS does not respond in
+/// time, up to the attempts of `retry`. The response is not delivered
+/// to the clients. The chip must be opened.
+async fn send_command_await_response(
+    state: &Mutex<State>,
+    packets: Vec<Vec<u8>>,
+    retry: CommandRetry,
+) -> HalResult<Vec<u8>> {
+    let header = UciHeader::parse(packets.last().map_or(&[][..], Vec::as_slice))?;
+    let (pending_rsp, writer) = match *state.lock().await {
+        State::Opened { writer: None, .. } => return Err(HalError::NotSupported),
+        State::Opened {
+            writer: Some(ref writer),
+            ref pending_rsp,
+            ..
+        } => (pending_rsp.clone(), writer.clone()),
+        _ => return Err(HalError::IllegalState),
+    };
+
+    let (recipient, mut receiver) = Recipient::new(false);
+    for attempt in 1.. {
+        let slot = writer.reserve().await?;
+        pending_rsp
+            .write(slot, packets.clone(), Some(&recipient), retry.timeout)
+            .await?;
+        if let Some(rsp) = clock::timeout(&*pending_rsp.clock, retry.timeout, &mut receiver).await {
+            return rsp.map_err(|_| {
+                io::Error::new(io::ErrorKind::BrokenPipe, "UCI reader task exited").into()
+            });
+        }
+        pending_rsp.timed_out();
+        if attempt >= retry.attempts {
+            break;
+        }
+        tracing::warn!(
+            command = %header.name(),
+            attempt,
+            "timed out waiting for the response, sending the command again"
+        );
+    }
+    tracing::error!(command = %header.name(), "timed out waiting for the response");
+    Err(HalError::Timeout)
+}
+
+/// Query the UWBS information with CORE_GET_DEVICE_INFO_CMD.
+/// The chip must be opened.
+async fn query_device_info(state: &Mutex<State>, retry: CommandRetry) -> HalResult<DeviceInfo> {
+    let rsp =
+        send_command_await_response(state, uci::UciCommandBuilder::core_get_device_info(), retry)
+            .await?;
+    uci::parse_device_info_rsp(&rsp).ok_or_else(|| {
+        tracing::error!(length = rsp.len(), "invalid device info response");
+        HalError::ProtocolError("invalid device info response".to_owned())
+    })
+}
+
+/// Query the UWBS capabilities with CORE_GET_CAPS_INFO_CMD, completed
+/// with the maximum number of sessions found by the vendor handler.
+/// The chip must be opened.
+async fn query_capabilities(
+    state: &Mutex<State>,
+    retry: CommandRetry,
+    vendor_handler: &dyn VendorUciHandler,
+) -> HalResult<UwbCapabilities> {
+    let rsp =
+        send_command_await_response(state, uci::UciCommandBuilder::core_get_caps_info(), retry)
+            .await?;
+    let mut capabilities = uci::parse_caps_info_rsp(&rsp).ok_or_else(|| {
+        tracing::error!(length = rsp.len(), "invalid capabilities response");
+        HalError::ProtocolError("invalid capabilities response".to_owned())
+    })?;
+    capabilities.max_sessions = vendor_handler.max_sessions(&rsp);
+    Ok(capabilities)
+}
+
+/// Check that the newly opened device responds to CORE_GET_DEVICE_INFO_CMD
+/// before sending OPEN_CPLT to the client. The device is released and
+/// OPEN_CPLT sent with FAILED status if the probe fails.
+fn probe_and_notify_open_complete(
+    state: Arc<Mutex<State>>,
+    clients: Arc<Clients>,
+    binder: SpIBinder,
+    retry: CommandRetry,
+) {
+    tokio::task::spawn(async move {
+        let Err(err) = query_device_info(&state, retry).await else {
+            clients.on_client_hal_event(&binder, UwbEvent::OPEN_CPLT, UwbStatus::OK);
+            return;
+        };
+        tracing::error!("UWBS failed the device probe: {:?}", err);
+        let mut state = state.lock().await;
+        // Only release the device if it was not closed and reopened
+        // in the meantime.
+        if let State::Opened {
+            clients: ref opened_clients,
+            ..
+        } = *state
+        {
+            if Arc::ptr_eq(opened_clients, &clients) {
+                clients.on_hal_event(UwbEvent::OPEN_CPLT, UwbStatus::FAILED);
+                state.release();
+            }
+        }
+    });
+}
+
+/// Stop tracking a session once the UWBS has accepted its deinit.
+async fn remove_session_on_deinit_rsp(
+    state: Arc<Mutex<State>>,
+    pending_rsp: Arc<PendingResponses>,
+    id: i32,
+    receiver: oneshot::Receiver<Vec<u8>>,
+) {
+    let rsp = match clock::timeout(&*pending_rsp.clock, UCI_RESPONSE_TIMEOUT, receiver).await {
+        Some(Ok(rsp)) => rsp,
+        Some(Err(_)) => {
+            tracing::warn!(session_id = id, "no response to the session deinit");
+            return;
+        }
+        None => {
+            tracing::warn!(
+                session_id = id,
+                "timed out waiting for the session deinit response"
+            );
+            pending_rsp.timed_out();
+            return;
+        }
+    };
+    if uci::response_status(&rsp) != Some(uci::STATUS_OK) {
+        tracing::warn!(
+            session_id = id,
+            status = ?uci::response_status(&rsp),
+            "session deinit failed"
+        );
+        return;
+    }
+    if let State::Opened {
+        ref mut sessions, ..
+    } = *state.lock().await
+    {
+        sessions.remove(&id);
+    }
+}
+
+/// Stop tracking the sessions after the UWBS has lost them, unless the
+/// chip was closed and reopened in the meantime.
+async fn clear_sessions(state: Arc<Mutex<State>>, clients: Arc<Clients>) {
+    if let State::Opened {
+        clients: ref opened_clients,
+        ref mut sessions,
+        ..
+    } = *state.lock().await
+    {
+        if Arc::ptr_eq(opened_clients, &clients) {
+            sessions.clear();
+        }
+    }
+}
+
+/// Write the response of the vendor handler back to the UWBS, unless the
+/// chip was closed and reopened in the meantime. The reader task cannot
+/// wait for the state lock, which is held by close() while waiting for it.
+async fn write_vendor_response(state: Arc<Mutex<State>>, clients: Arc<Clients>, response: Vec<u8>) {
+    let writer = match *state.lock().await {
+        State::Opened {
+            clients: ref opened_clients,
+            ref writer,
+            ..
+        } if Arc::ptr_eq(opened_clients, &clients) => writer.clone(),
+        _ => return,
+    };
+    let Some(writer) = writer else {
+        tracing::warn!("not writing the vendor response to a read-only chip");
+        return;
+    };
+    if let Err(err) = writer.write(response).await {
+        tracing::warn!("failed to write the vendor response: {}", err);
+    }
+}
+
+/// Send OPEN_CPLT to a newly registered client once open() has returned.
+/// The event is not sent if the client is released in the meantime,
+/// e.g. by close().
+fn notify_open_complete(clients: Arc<Clients>, binder: SpIBinder) {
+    tokio::task::spawn(async move {
+        clients.on_client_hal_event(&binder, UwbEvent::OPEN_CPLT, UwbStatus::OK);
+    });
+}
+
+/// Trace the header fields of a UCI message exchanged with the UWBS.
+fn trace_uci_message(event: &str, message: &[u8]) {
+    match UciHeader::parse(message) {
+        Ok(header) => tracing::debug!(
+            name = %header.name(),
+            length = message.len(),
+            "{}",
+            event
+        ),
+        Err(err) => tracing::debug!(length = message.len(), %err, "{}", event),
+    }
+}
+
+/// State shared with the reader task.
+struct ReaderContext {
+    state: Arc<Mutex<State>>,
+    clients: Arc<Clients>,
+    /// Queue of the UCI messages delivered to the clients.
+    messages: mpsc::Sender<Vec<u8>>,
+    token: CancellationToken,
+    idle_timeout: Option<Duration>,
+    idle_close: bool,
+    last_traffic: Arc<LastTraffic>,
+    credits: Arc<DataCredits>,
+    phases: Arc<SessionPhases>,
+    android_uci_version: Arc<std::sync::Mutex<Option<i32>>>,
+    pending_rsp: Arc<PendingResponses>,
+    captures: Vec<Arc<Capture>>,
+    metrics: Arc<Metrics>,
+    clock: Arc<dyn Clock>,
+    vendor_handler: Arc<dyn VendorUciHandler>,
+}
+
+/// Instant of the last UCI packet exchanged with the UWBS, on the clock
+/// of the chip. Updated without locking by the reader and writer tasks.
+struct LastTraffic {
+    clock: Arc<dyn Clock>,
+    /// Opening of the chip, from which the instants are counted.
+    start: Instant,
+    elapsed_nanos: AtomicU64,
+}
+
+impl LastTraffic {
+    fn new(clock: Arc<dyn Clock>) -> Self {
+        let start = clock.now();
+        Self {
+            clock,
+            start,
+            elapsed_nanos: AtomicU64::new(0),
+        }
+    }
+
+    fn record(&self) {
+        let elapsed = self.clock.now().saturating_duration_since(self.start);
+        self.elapsed_nanos
+            .fetch_max(elapsed.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    fn get(&self) -> Instant {
+        self.start + Duration::from_nanos(self.elapsed_nanos.load(Ordering::Relaxed))
+    }
+}
+
+/// Warn the clients each time no UCI packets are exchanged with the UWBS
+/// for the idle timeout, and return then if the chip is closed once idle.
+/// Never returns if the idle timeout is not configured.
+async fn watch_inactivity(context: &ReaderContext) {
+    let Some(idle_timeout) = context.idle_timeout else {
+        return std::future::pending().await;
+    };
+    let mut warned = None;
+    loop {
+        let last_traffic = context.last_traffic.get();
+        let idle = context.clock.now().saturating_duration_since(last_traffic);
+        if idle < idle_timeout {
+            context.clock.sleep(idle_timeout - idle).await;
+            continue;
+        }
+        if warned != Some(last_traffic) {
+            tracing::warn!(?idle_timeout, "no UCI traffic");
+            context
+                .clients
+                .on_hal_event(UwbEvent::ERROR, UwbStatus::ERR_CMD_TIMEOUT);
+            warned = Some(last_traffic);
+        }
+        if context.idle_close {
+            tracing::info!("closing the idle chip");
+            return;
+        }
+        // Wait for the traffic to resume.
+        context.clock.sleep(idle_timeout).await;
+    }
+}
+
+/// Status of the ERROR event notified for the CORE_GENERIC_ERROR_NTF
+/// `status`, or None if the UWBS recovers from the error by itself or
+/// with the help of the client, e.g. by sending the command again.
+fn generic_error_hal_status(status: u8) -> Option<UwbStatus> {
+    match status {
+        uci::STATUS_FAILED | uci::STATUS_UNKNOWN => Some(UwbStatus::FAILED),
+        _ => None,
+    }
+}
+
+/// Read UCI packets from the device and forward them to the client
+/// until the token is cancelled. Returns an error if the device
+/// fails, or an UnexpectedEof error if it is closed.
+async fn read_uci_packets<R: AsyncRead + Unpin>(
+    packets: &mut UciFramedReader<R>,
+    context: &ReaderContext,
+) -> io::Result<()> {
+    let mut reassembler = Reassembler::default();
+
+    loop {
+        // The framed reader keeps the partially received packets,
+        // next() can be cancelled at any time.
+        let packet = select! {
+            _ = context.token.cancelled() => {
+                tracing::info!("task is cancelled!");
+                drain_uci_packets(packets, &mut reassembler, context, None).await;
+                return Ok(());
+            },
+            packet = packets.next() => packet,
+        };
+
+        let packet = match packet {
+            Some(Ok(packet)) => packet,
+            Some(Err(HalError::IoError(err))) => return Err(err),
+            Some(Err(HalError::Timeout)) => {
+                tracing::warn!("timed out reading UCI packet, discarding partial packet");
+                context.metrics.record_error();
+                context
+                    .clients
+                    .on_hal_event(UwbEvent::ERROR, UwbStatus::FAILED);
+                continue;
+            }
+            Some(Err(_)) => {
+                context.metrics.record_error();
+                continue;
+            }
+            None => return Err(io::ErrorKind::UnexpectedEof.into()),
+        };
+        let Some(message) = process_uci_packet(packet, &mut reassembler, context) else {
+            continue;
+        };
+
+        select! {
+            _ = context.token.cancelled() => {
+                tracing::info!("task is cancelled!");
+                drain_uci_packets(packets, &mut reassembler, context, Some(message)).await;
+                return Ok(());
+            },
+            permit = context.messages.reserve() => {
+                if let Ok(permit) = permit {
+                    permit.send(message);
+                }
+            },
+        }
+    }
+}
+
+/// Deliver the UCI packets already received from the UWBS once the reader
+/// task is cancelled, starting with the pending `message`, so that e.g.
+/// the last ranging notification is not dropped on shutdown. Gives up
+/// after `SHUTDOWN_GRACE_PERIOD`.
+async fn drain_uci_packets<R: AsyncRead + Unpin>(
+    packets: &mut UciFramedReader<R>,
+    reassembler: &mut Reassembler,
+    context: &ReaderContext,
+    mut message: Option<Vec<u8>>,
+) {
+    let drain = async {
+        loop {
+            if let Some(message) = message.take() {
+                let _ = context.messages.send(message).await;
+            }
+            // Only the packets which can be read without waiting are drained.
+            let packet = match packets.next().now_or_never() {
+                Some(Some(Ok(packet))) => packet,
+                Some(Some(Err(HalError::IoError(_))) | None) | None => return,
+                Some(Some(Err(_))) => continue,
+            };
+            message = process_uci_packet(packet, reassembler, context);
+        }
+    };
+    if clock::timeout(&*context.clock, SHUTDOWN_GRACE_PERIOD, drain)
+        .await
+        .is_none()
+    {
+        tracing::warn!("timed out delivering the received UCI messages");
+    }
+}
+
+/// Process a UCI packet received from the UWBS. Returns the complete
+/// message to deliver to the clients, if any.
+fn process_uci_packet(
+    packet: UciPacket,
+    reassembler: &mut Reassembler,
+    context: &ReaderContext,
+) -> Option<Vec<u8>> {
+    let UciPacket {
+        header,
+        bytes: buffer,
+    } = packet;
+    for capture in context.captures.iter() {
+        capture.record(Direction::Inbound, &buffer);
+    }
+    context.metrics.record_received(&header, &buffer);
+    context.last_traffic.record();
+
+    // Only deliver complete messages to the client.
+    let message = reassembler.push(header, buffer)?;
+    trace_uci_message("UCI message received", &message);
+
+    if let Some((session_handle, available)) = uci::parse_data_credit_ntf(&message) {
+        context.credits.update(session_handle, available);
+    }
+
+    if let Some((session_handle, state)) = uci::parse_session_status_ntf(&message) {
+        context.phases.update(session_handle, state);
+    }
+
+    if let Some((session_handle, status)) = uci::parse_data_transfer_status_ntf(&message) {
+        tracing::debug!(session_handle, status, "data transfer status");
+        context.phases.transfer_completed(session_handle);
+    }
+
+    if let Some(device_info) = uci::parse_device_info_rsp(&message) {
+        if let Some(version) = context.vendor_handler.android_uci_version(&device_info) {
+            *context.android_uci_version.lock().unwrap() = Some(version);
+        }
+    }
+
+    // The UWBS loses all sessions when it fails. The state lock is held
+    // by close() while waiting for the reader task, so the sessions are
+    // cleared asynchronously. The notification is still forwarded.
+    if uci::parse_device_status_ntf(&message) == Some(uci::DEVICE_STATE_ERROR) {
+        tracing::warn!("UWBS reported an error, clearing the session state");
+        context.credits.reset();
+        context.phases.reset();
+        tokio::task::spawn(clear_sessions(
+            context.state.clone(),
+            context.clients.clone(),
+        ));
+    }
+
+    // The fatal errors are also notified as HAL events. The notification
+    // is still forwarded, e.g. for the client to retry its last command.
+    if let Some(status) = uci::parse_generic_error_ntf(&message) {
+        tracing::warn!(status, "UWBS reported a generic error");
+        if let Some(status) = generic_error_hal_status(status) {
+            context.clients.on_hal_event(UwbEvent::ERROR, status);
+        }
+    }
+
+    // The responses to the commands sent by the HAL are consumed
+    // here, and not forwarded to the clients.
+    let message = context.pending_rsp.complete(&header, message)?;
+
+    if let Some(response) = vendor::handle(&*context.vendor_handler, &header, &message) {
+        tokio::task::spawn(write_vendor_response(
+            context.state.clone(),
+            context.clients.clone(),
+            response,
+        ));
+        return None;
+    }
+
+    Some(message)
+}
+
+impl binder::Interface for UwbChip {}
+
+#[async_trait]
+impl IUwbChipAsyncServer for UwbChip {
+    async fn getName(&self) -> Result<String> {
+        Ok(self.name.clone())
+    }
+
+    async fn open(&self, callbacks: &Strong<dyn IUwbClientCallback>) -> Result<()> {
+        self.open_with_filter(callbacks, MessageFilter::default())
+            .await
+    }
+
+    #[tracing::instrument(level = "debug", skip_all, fields(chip = %self.name))]
+    async fn close(&self) -> Result<()> {
+        tracing::debug!("close");
+
+        let mut state = self.state.lock().await;
+
+        if let State::Opened { .. } = *state {
+            let result = state.close().await;
+            tracing::debug!(metrics = ?self.metrics_snapshot(), "UCI traffic");
+            Ok(result?)
+        } else {
+            Err(HalError::IllegalState.into())
+        }
+    }
+
+    #[tracing::instrument(level = "debug", skip_all, fields(chip = %self.name))]
+    async fn coreInit(&self) -> Result<()> {
+        tracing::debug!("coreInit");
+
+        if let State::Opened { ref clients, .. } = *self.state.lock().await {
+            clients.on_hal_event(UwbEvent::POST_INIT_CPLT, UwbStatus::OK);
+        } else {
+            return Err(HalError::IllegalState.into());
+        }
+
+        // The device info and the capabilities are queried in the
+        // background, not to delay the clients. The Android UCI version is
+        // cached by the reader task from the device info response. The chip
+        // keeps working with a UWBS not reporting them, without validating
+        // the sessions.
+        let state = self.state.clone();
+        let capabilities = self.capabilities.clone();
+        let command_retry = self.command_retry;
+        let vendor_handler = self.vendor_handler.clone();
+        tokio::spawn(
+            async move {
+                match query_device_info(&state, command_retry).await {
+                    Ok(device_info) => tracing::debug!(?device_info, "UWBS device info"),
+                    Err(err) => tracing::warn!(?err, "failed to query the UWBS device info"),
+                }
+                match query_capabilities(&state, command_retry, &*vendor_handler).await {
+                    Ok(queried) => {
+                        tracing::debug!(capabilities = ?queried, "UWBS capabilities");
+                        *capabilities.lock().unwrap() = Some(queried);
+                    }
+                    Err(err) => tracing::warn!(?err, "failed to query the UWBS capabilities"),
+                }
+            }
+            .in_current_span(),
+        );
+        Ok(())
+    }
+
+    #[tracing::instrument(level = "debug", skip_all, fields(chip = %self.name, session_id = id))]
+    async fn sessionInit(&self, id: i32) -> Result<()> {
+        tracing::debug!(session_id = id, "sessionInit");
+
+        self.insert_session(id).await?;
+        let configs = self.vendor_handler.session_app_configs(id);
+        if configs.is_empty() {
+            return Ok(());
+        }
+        let status = self.set_app_config(id as u32, &configs).await?;
+        if !status.rejected.is_empty() {
+            tracing::error!(session_id = id, rejected = ?status.rejected, "app configs rejected");
+            return Err(
+                HalError::InvalidArgument("app configs rejected by the UWBS".to_owned()).into(),
+            );
+        }
+        Ok(())
+    }
+
+    async fn getSupportedAndroidUciVersion(&self) -> Result<i32> {
+        // The version is extracted by the vendor handler, e.g. as listed in
+        // the configuration file, once the device info has been received.
+        Ok(self
+            .android_uci_version
+            .lock()
+            .unwrap()
+            .unwrap_or(DEFAULT_ANDROID_UCI_VERSION))
+    }
+
+    #[tracing::instrument(level = "debug", skip_all, fields(chip = %self.name))]
+    async fn sendUciMessage(&self, data: &[u8]) -> Result<i32> {
+        if UciHeader::parse(data).is_ok_and(|header| header.message_type == MessageType::Command) {
+            self.throttle(binder::ThreadState::get_calling_pid())
+                .await?;
+        }
+        Ok(self.send_uci_message(data).await?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::FakeClock;
+    use crate::mock::{InjectedTransport, MockUwbs, TestClient};
+    use android_hardware_uwb::aidl::android::hardware::uwb::IUwbClientCallback::BnUwbClientCallback;
+    use tokio::io::AsyncReadExt;
+
+    const DEVICE_RESET_CMD: [u8; 5] = [0x20, 0x00, 0x00, 0x01, 0x00];
+    const DEVICE_RESET_RSP: [u8; 5] = [0x40, 0x00, 0x00, 0x01, 0x00];
+    const DEVICE_STATUS_READY_NTF: [u8; 5] = [0x60, 0x01, 0x00, 0x01, 0x01];
+    const DEVICE_INFO_RSP: [u8; 14] = [
+        0x40, 0x02, 0x00, 0x0a, 0x00, 0x02, 0x00, 0x01, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00,
+    ];
+
+    /// Wait for the tasks of the chip to meet a condition.
+    async fn wait_until(condition: impl Fn() -> bool) {
+        tokio::time::timeout(Duration::from_secs(1), async {
+            while !condition() {
+                tokio::time::sleep(Duration::from_millis(1)).await;
+            }
+        })
+        .await
+        .expect("timed out waiting for the chip");
+    }
+
+    /// Client blocking in the delivery of UCI messages until released.
+    #[derive(Clone, Default)]
+    struct BlockingClient {
+        /// Blocked and released flags.
+        state: Arc<(std::sync::Mutex<(bool, bool)>, std::sync::Condvar)>,
+    }
+
+    impl binder::Interface for BlockingClient {}
+
+    impl IUwbClientCallback for BlockingClient {
+        fn onUciMessage(&self, _data: &[u8]) -> Result<()> {
+            let (state, condvar) = &*self.state;
+            let mut state = state.lock().unwrap();
+            state.0 = true;
+            let _state = condvar.wait_while(state, |state| !state.1).unwrap();
+            Ok(())
+        }
+
+        fn onHalEvent(&self, _event: UwbEvent, _status: UwbStatus) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    impl BlockingClient {
+        fn callbacks(&self) -> Strong<dyn IUwbClientCallback> {
+            BnUwbClientCallback::new_binder(self.clone(), binder::BinderFeatures::default())
+        }
+
+        fn is_blocked(&self) -> bool {
+            self.state.0.lock().unwrap().0
+        }
+
+        fn release(&self) {
+            let (state, condvar) = &*self.state;
+            state.lock().unwrap().1 = true;
+            condvar.notify_all();
+        }
+    }
+
+    #[tokio::test]
+    async fn open_reports_missing_device() {
+        let chip = UwbChip::new("0".to_owned(), "/nonexistent/ttyUSB0".to_owned());
+        let client = TestClient::default();
+
+        let status = chip.open(&client.callbacks()).await.unwrap_err();
+        assert_eq!(
+            status.transaction_error(),
+            binder::StatusCode::UNKNOWN_ERROR
+        );
+        assert!(matches!(*chip.state.lock().await, State::Closed));
+        assert!(client.events().is_empty());
+    }
+
+    #[tokio::test]
+    async fn close_resets_device() {
+        let uwbs = MockUwbs::default().with_response(
+            uci::GID_CORE,
+            uci::OID_CORE_DEVICE_RESET,
+            &DEVICE_RESET_RSP,
+        );
+        // The timeouts never expire on the fake clock.
+        let chip = UwbChip::new_mock("0".to_owned(), uwbs.clone())
+            .with_clock(Arc::new(FakeClock::default()));
+        let client = TestClient::default();
+
+        chip.open(&client.callbacks()).await.unwrap();
+        let close = chip.close();
+        tokio::pin!(close);
+
+        // The chip waits for the device status following the response.
+        let closed = tokio::time::timeout(Duration::from_millis(10), &mut close);
+        assert!(closed.await.is_err());
+        assert_eq!(uwbs.written(), DEVICE_RESET_CMD);
+        uwbs.notify(&DEVICE_STATUS_READY_NTF);
+        close.await.unwrap();
+
+        assert_eq!(
+            client.events().last(),
+            Some(&(UwbEvent::CLOSE_CPLT, UwbStatus::OK))
+        );
+        // Neither the response nor the notification reach the client.
+        assert!(client.messages().is_empty());
+    }
+
+    #[tokio::test]
+    async fn chips_are_independent() {
+        const GET_CAPS_INFO_CMD: [u8; 4] = [0x20, 0x03, 0x00, 0x00];
+
+        let uwbs = [
+            MockUwbs::default().with_response(
+                uci::GID_CORE,
+                uci::OID_CORE_DEVICE_RESET,
+                &[DEVICE_RESET_RSP, DEVICE_STATUS_READY_NTF].concat(),
+            ),
+            MockUwbs::default(),
+        ];
+        let chips = [
+            UwbChip::new_mock("0".to_owned(), uwbs[0].clone()),
+            UwbChip::new_mock("1".to_owned(), uwbs[1].clone()),
+        ];
+        let clients = [TestClient::default(), TestClient::default()];
+
+        for (chip, client) in chips.iter().zip(clients.iter()) {
+            chip.open(&client.callbacks()).await.unwrap();
+        }
+        uwbs[0].notify(&DEVICE_STATUS_READY_NTF);
+        chips[1].sendUciMessage(&GET_CAPS_INFO_CMD).await.unwrap();
+
+        wait_until(|| !clients[0].messages().is_empty()).await;
+        assert_eq!(clients[0].messages(), [DEVICE_STATUS_READY_NTF]);
+        assert!(clients[1].messages().is_empty());
+        assert!(uwbs[0].written().is_empty());
+        assert_eq!(uwbs[1].written(), GET_CAPS_INFO_CMD);
+
+        // Closing a chip leaves the other one opened.
+        chips[0].close().await.unwrap();
+        assert!(matches!(*chips[1].state.lock().await, State::Opened { .. }));
+    }
+
+    #[tokio::test]
+    async fn socketpair_open_send_close() {
+        const SESSION_STATUS_NTF: [u8; 10] =
+            [0x61, 0x02, 0x00, 0x06, 0x01, 0x00, 0x00, 0x00, 0x02, 0x00];
+        const GET_CAPS_INFO_CMD: [u8; 4] = [0x20, 0x03, 0x00, 0x00];
+        let (mut device, hal) = tokio::net::UnixStream::pair().unwrap();
+        let chip = UwbChip::with_transport(
+            "0".to_owned(),
+            TransportConfig::Injected(InjectedTransport::new(hal)),
+        );
+        let client = TestClient::default();
+        let callbacks = client.callbacks();
+
+        chip.open(&callbacks).await.unwrap();
+        match *chip.state.lock().await {
+            State::Opened { ref clients, .. } => {
+                assert!(clients.contains(&callbacks.as_binder()))
+            }
+            _ => panic!("the chip is not opened"),
+        }
+        wait_until(|| client.events() == [(UwbEvent::OPEN_CPLT, UwbStatus::OK)]).await;
+
+        device.write_all(&SESSION_STATUS_NTF).await.unwrap();
+        wait_until(|| client.messages() == [SESSION_STATUS_NTF]).await;
+
+        chip.sendUciMessage(&GET_CAPS_INFO_CMD).await.unwrap();
+        let mut cmd = [0; GET_CAPS_INFO_CMD.len()];
+        device.read_exact(&mut cmd).await.unwrap();
+        assert_eq!(cmd, GET_CAPS_INFO_CMD);
+
+        let device = tokio::spawn(async move {
+            let mut cmd = [0; DEVICE_RESET_CMD.len()];
+            device.read_exact(&mut cmd).await.unwrap();
+            assert_eq!(cmd, DEVICE_RESET_CMD);
+            device.write_all(&DEVICE_RESET_RSP).await.unwrap();
+            device.write_all(&DEVICE_STATUS_READY_NTF).await.unwrap();
+            device
+        });
+        chip.close().await.unwrap();
+        let _device = device.await.unwrap();
+        assert_eq!(
+            client.events().last(),
+            Some(&(UwbEvent::CLOSE_CPLT, UwbStatus::OK))
+        );
+    }
+
+    #[tokio::test]
+    async fn dropped_chip_stops_reader() {
+        let (mut device, hal) = tokio::io::duplex(64);
+        let chip = UwbChip::with_transport(
+            "0".to_owned(),
+            TransportConfig::Injected(InjectedTransport::new(hal)),
+        );
+        let client = TestClient::default();
+
+        chip.open(&client.callbacks()).await.unwrap();
+        let readers = chip.readers.clone();
+        assert_eq!(readers.load(Ordering::SeqCst), 1);
+        drop(chip);
+
+        // The reader task exits and releases the device.
+        wait_until(|| readers.load(Ordering::SeqCst) == 0).await;
+        let mut bytes = [0; 1];
+        let read = tokio::time::timeout(Duration::from_secs(1), device.read(&mut bytes));
+        assert_eq!(read.await.unwrap().unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn blocked_write_does_not_hold_state() {
+        const SESSION_STATUS_NTF: [u8; 10] =
+            [0x61, 0x02, 0x00, 0x06, 0x01, 0x00, 0x00, 0x00, 0x02, 0x00];
+        // The device does not read the commands until the end of the test,
+        // so that the writes block once the pipe is full.
+        let (mut device, hal) = tokio::io::duplex(64);
+        let chip = Arc::new(UwbChip::with_transport(
+            "0".to_owned(),
+            TransportConfig::Injected(InjectedTransport::new(hal)),
+        ));
+        let client = TestClient::default();
+
+        chip.open(&client.callbacks()).await.unwrap();
+        let cmd = uci::RawCommandBuilder::new(uci::GID_CORE, 0x20)
+            .payload(&[0; 100])
+            .build();
+        let sends: Vec<_> = (0..4)
+            .map(|_| {
+                let chip = chip.clone();
+                let cmd = cmd.clone();
+                tokio::spawn(async move { chip.sendUciMessage(&cmd).await })
+            })
+            .collect();
+
+        // The chip keeps handling the clients and the UWBS meanwhile.
+        chip.sessionInit(1).await.unwrap();
+        device.write_all(&SESSION_STATUS_NTF).await.unwrap();
+        wait_until(|| client.messages() == [SESSION_STATUS_NTF]).await;
+        assert!(sends.iter().all(|send| !send.is_finished()));
+
+        let mut written = vec![0; 4 * cmd.len()];
+        device.read_exact(&mut written).await.unwrap();
+        for send in sends {
+            assert_eq!(send.await.unwrap().unwrap(), cmd.len() as i32);
+        }
+        assert_eq!(written, cmd.repeat(4));
+    }
+
+    #[tokio::test]
+    async fn device_info_response_is_not_forwarded() {
+        const DEVICE_INFO_CMD: [u8; 4] = [0x20, 0x02, 0x00, 0x00];
+        let uwbs = MockUwbs::default().with_response(
+            uci::GID_CORE,
+            uci::OID_CORE_GET_DEVICE_INFO,
+            &DEVICE_INFO_RSP,
+        );
+        let chip = UwbChip::new_mock("0".to_owned(), uwbs.clone());
+        let client = TestClient::default();
+
+        chip.open(&client.callbacks()).await.unwrap();
+        let device_info = chip.get_device_info().await.unwrap();
+        assert_eq!(device_info.uci_version.major, 2);
+        assert!(client.messages().is_empty());
+
+        // The response to the same command sent by the client is forwarded.
+        chip.sendUciMessage(&DEVICE_INFO_CMD).await.unwrap();
+        wait_until(|| !client.messages().is_empty()).await;
+        assert_eq!(client.messages(), [DEVICE_INFO_RSP]);
+    }
+
+    #[tokio::test]
+    async fn concurrent_client_command_gets_its_response() {
+        const DEVICE_INFO_CMD: [u8; 4] = [0x20, 0x02, 0x00, 0x00];
+        let mut client_rsp = DEVICE_INFO_RSP;
+        client_rsp[5] = 0x01;
+        let uwbs = MockUwbs::default();
+        let chip = Arc::new(UwbChip::new_mock("0".to_owned(), uwbs.clone()));
+        let client = TestClient::default();
+
+        // The client command is sent first, the same command of the HAL
+        // before the response.
+        chip.open(&client.callbacks()).await.unwrap();
+        chip.sendUciMessage(&DEVICE_INFO_CMD).await.unwrap();
+        let query_chip = chip.clone();
+        let query = tokio::spawn(async move { query_chip.get_device_info().await });
+        wait_until(|| uwbs.written() == DEVICE_INFO_CMD.repeat(2)).await;
+
+        // The UWBS responds in order.
+        uwbs.notify(&client_rsp);
+        uwbs.notify(&DEVICE_INFO_RSP);
+        assert_eq!(query.await.unwrap().unwrap().uci_version.major, 2);
+        wait_until(|| !client.messages().is_empty()).await;
+        assert_eq!(client.messages(), [client_rsp]);
+    }
+
+    #[tokio::test]
+    async fn command_is_sent_again_on_timeout() {
+        const DEVICE_INFO_CMD: [u8; 4] = [0x20, 0x02, 0x00, 0x00];
+        let uwbs = MockUwbs::default();
+        let chip = Arc::new(
+            UwbChip::new_mock("0".to_owned(), uwbs.clone()).with_command_retry(CommandRetry {
+                attempts: 2,
+                timeout: Duration::from_millis(50),
+            }),
+        );
+        let client = TestClient::default();
+
+        chip.open(&client.callbacks()).await.unwrap();
+        let query_chip = chip.clone();
+        let query = tokio::spawn(async move { query_chip.get_device_info().await });
+
+        // The UWBS responds late to the first attempt, then to the second.
+        wait_until(|| uwbs.written() == DEVICE_INFO_CMD.repeat(2)).await;
+        uwbs.notify(&DEVICE_INFO_RSP);
+        uwbs.notify(&DEVICE_INFO_RSP);
+        uwbs.notify(&DEVICE_STATUS_READY_NTF);
+        assert_eq!(query.await.unwrap().unwrap().uci_version.major, 2);
+
+        // The second response is not delivered to the client.
+        wait_until(|| !client.messages().is_empty()).await;
+        assert_eq!(client.messages(), [DEVICE_STATUS_READY_NTF]);
+        assert_eq!(chip.metrics_snapshot().response_timeouts, 1);
+    }
+
+    #[tokio::test]
+    async fn command_times_out_on_fake_clock() {
+        let uwbs = MockUwbs::default();
+        let clock = FakeClock::default();
+        let chip =
+            UwbChip::new_mock("0".to_owned(), uwbs.clone()).with_clock(Arc::new(clock.clone()));
+        let client = TestClient::default();
+
+        chip.open(&client.callbacks()).await.unwrap();
+        let advance = async {
+            // The UWBS never responds: the command times out once the
+            // clock reaches the response timeout.
+            wait_until(|| clock.sleeps() == 1).await;
+            clock.advance(UCI_RESPONSE_TIMEOUT - Duration::from_millis(1));
+            tokio::task::yield_now().await;
+            assert_eq!(chip.metrics_snapshot().response_timeouts, 0);
+            clock.advance(Duration::from_millis(1));
+        };
+        let (result, ()) = tokio::join!(chip.get_device_info(), advance);
+        assert!(result.is_err());
+        assert_eq!(chip.metrics_snapshot().response_timeouts, 1);
+    }
+
+    #[tokio::test]
+    async fn command_bursts_are_throttled() {
+        const GET_CAPS_INFO_CMD: [u8; 4] = [0x20, 0x03, 0x00, 0x00];
+        let uwbs = MockUwbs::default();
+        let clock = FakeClock::default();
+        let chip = UwbChip::new_mock("0".to_owned(), uwbs.clone())
+            .with_clock(Arc::new(clock.clone()))
+            .with_rate_limit(RateLimit {
+                commands_per_second: 2,
+                burst: 3,
+                policy: RateLimitPolicy::Reject,
+            });
+        let client = TestClient::default();
+
+        chip.open(&client.callbacks()).await.unwrap();
+        for _ in 0..3 {
+            chip.sendUciMessage(&GET_CAPS_INFO_CMD).await.unwrap();
+        }
+        let status = chip.sendUciMessage(&GET_CAPS_INFO_CMD).await.unwrap_err();
+        assert_eq!(status.transaction_error(), binder::StatusCode::WOULD_BLOCK);
+
+        // A token is available again after half a second.
+        clock.advance(Duration::from_millis(500));
+        chip.sendUciMessage(&GET_CAPS_INFO_CMD).await.unwrap();
+        assert!(chip.sendUciMessage(&GET_CAPS_INFO_CMD).await.is_err());
+        assert_eq!(uwbs.written(), GET_CAPS_INFO_CMD.repeat(4));
+    }
+
+    #[tokio::test]
+    async fn throttled_commands_wait_for_a_token() {
+        const GET_CAPS_INFO_CMD: [u8; 4] = [0x20, 0x03, 0x00, 0x00];
+        let uwbs = MockUwbs::default();
+        let clock = FakeClock::default();
+        let chip = UwbChip::new_mock("0".to_owned(), uwbs.clone())
+            .with_clock(Arc::new(clock.clone()))
+            .with_rate_limit(RateLimit {
+                commands_per_second: 1,
+                burst: 1,
+                policy: RateLimitPolicy::Wait,
+            });
+        let client = TestClient::default();
+
+        chip.open(&client.callbacks()).await.unwrap();
+        chip.sendUciMessage(&GET_CAPS_INFO_CMD).await.unwrap();
+        let advance = async {
+            wait_until(|| clock.sleeps() == 1).await;
+            assert_eq!(uwbs.written(), GET_CAPS_INFO_CMD);
+            clock.advance(Duration::from_secs(1));
+        };
+        let (sent, ()) = tokio::join!(chip.sendUciMessage(&GET_CAPS_INFO_CMD), advance);
+        sent.unwrap();
+        assert_eq!(uwbs.written(), GET_CAPS_INFO_CMD.repeat(2));
+    }
+
+    #[tokio::test]
+    async fn set_app_config_reports_rejected_configs() {
+        // STATUS_INVALID_PARAM, with the channel number rejected.
+        const SET_APP_CONFIG_RSP: [u8; 8] = [0x41, 0x03, 0x00, 0x04, 0x04, 0x01, 0x04, 0x04];
+        let uwbs = MockUwbs::default().with_response(
+            uci::GID_SESSION_CONFIG,
+            uci::OID_SESSION_SET_APP_CONFIG,
+            &SET_APP_CONFIG_RSP,
+        );
+        let chip = UwbChip::new_mock("0".to_owned(), uwbs.clone());
+        let client = TestClient::default();
+
+        chip.open(&client.callbacks()).await.unwrap();
+        let status = chip
+            .set_app_config(
+                1,
+                &[
+                    (AppConfigTag::DEVICE_TYPE, vec![0x01]),
+                    (AppConfigTag::CHANNEL_NUMBER, vec![0x07]),
+                ],
+            )
+            .await
+            .unwrap();
+        assert_eq!(status.status, 0x04);
+        assert_eq!(status.rejected, [(AppConfigTag::CHANNEL_NUMBER, 0x04)]);
+        assert_eq!(
+            uwbs.written(),
+            [
+                0x21, 0x03, 0x00, 0x0b, 0x01, 0x00, 0x00, 0x00, 0x02, 0x00, 0x01, 0x01, 0x04, 0x01,
+                0x07
+            ]
+        );
+        assert!(client.messages().is_empty());
+    }
+
+    #[tokio::test]
+    async fn traffic_is_counted_by_message_type() {
+        const GET_CAPS_INFO_CMD: [u8; 4] = [0x20, 0x03, 0x00, 0x00];
+        const GET_CAPS_INFO_RSP: [u8; 6] = [0x40, 0x03, 0x00, 0x02, 0x00, 0x00];
+        let uwbs = MockUwbs::default().with_response(
+            uci::GID_CORE,
+            uci::OID_CORE_GET_CAPS_INFO,
+            &GET_CAPS_INFO_RSP,
+        );
+        let chip = UwbChip::new_mock("0".to_owned(), uwbs.clone());
+        let client = TestClient::default();
+
+        chip.open(&client.callbacks()).await.unwrap();
+        chip.sendUciMessage(&GET_CAPS_INFO_CMD).await.unwrap();
+        uwbs.notify(&DEVICE_STATUS_READY_NTF);
+        uwbs.notify(&data_packet(8));
+        wait_until(|| client.messages().len() == 3).await;
+        assert_eq!(
+            chip.metrics_snapshot(),
+            UwbMetrics {
+                commands_sent: 1,
+                bytes_sent: 4,
+                responses_received: 1,
+                notifications_received: 1,
+                data_packets_received: 1,
+                bytes_received: 6 + 5 + 12,
+                ..Default::default()
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn hal_commands_are_counted() {
+        let uwbs = MockUwbs::default().with_response(
+            uci::GID_CORE,
+            uci::OID_CORE_GET_DEVICE_INFO,
+            &DEVICE_INFO_RSP,
+        );
+        let chip = UwbChip::new_mock("0".to_owned(), uwbs.clone());
+        let client = TestClient::default();
+
+        chip.open(&client.callbacks()).await.unwrap();
+        chip.get_device_info().await.unwrap();
+        let metrics = chip.metrics_snapshot();
+        assert_eq!(metrics.commands_sent, 1);
+        assert_eq!(metrics.bytes_sent, 4);
+        assert_eq!(metrics.responses_received, 1);
+    }
+
+    #[tokio::test]
+    async fn response_latency_is_recorded() {
+        let uwbs = MockUwbs::default();
+        let chip = UwbChip::new_mock("0".to_owned(), uwbs.clone());
+        let client = TestClient::default();
+
+        chip.open(&client.callbacks()).await.unwrap();
+        let delayed_uwbs = uwbs.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(30)).await;
+            delayed_uwbs.notify(&DEVICE_INFO_RSP);
+        });
+        chip.get_device_info().await.unwrap();
+
+        // The response is counted in the 20 to 50 ms bucket, or above
+        // if the test was delayed.
+        let metrics = chip.metrics_snapshot();
+        let bucket = crate::metrics::LATENCY_BUCKETS_MS
+            .iter()
+            .position(|&bound| bound == 20)
+            .unwrap()
+            + 1;
+        assert_eq!(metrics.response_latency[..bucket].iter().sum::<u64>(), 0);
+        assert_eq!(metrics.response_latency[bucket..].iter().sum::<u64>(), 1);
+        assert_eq!(metrics.response_timeouts, 0);
+    }
+
+    #[tokio::test]
+    async fn filtered_client_only_receives_subscribed_messages() {
+        const DATA_MESSAGE: [u8; 6] = [0x02, 0x00, 0x02, 0x00, 0xaa, 0xbb];
+        let uwbs = MockUwbs::default();
+        let chip = UwbChip::new_mock("0".to_owned(), uwbs.clone());
+        let data_client = TestClient::default();
+        let client = TestClient::default();
+
+        chip.open_with_filter(
+            &data_client.callbacks(),
+            MessageFilter::default().with_message_types(&[MessageType::Data]),
+        )
+        .await
+        .unwrap();
+        chip.open(&client.callbacks()).await.unwrap();
+        uwbs.notify(&DEVICE_STATUS_READY_NTF);
+        uwbs.notify(&DATA_MESSAGE);
+
+        wait_until(|| client.messages().len() == 2).await;
+        assert_eq!(
+            client.messages(),
+            [&DEVICE_STATUS_READY_NTF[..], &DATA_MESSAGE[..]]
+        );
+        assert_eq!(data_client.messages(), [DATA_MESSAGE]);
+    }
+
+    #[tokio::test]
+    async fn slow_client_does_not_block_reader() {
+        const GET_CAPS_INFO_CMD: [u8; 4] = [0x20, 0x03, 0x00, 0x00];
+        const GET_CAPS_INFO_RSP: [u8; 6] = [0x40, 0x03, 0x00, 0x02, 0x00, 0x00];
+        let uwbs = MockUwbs::default()
+            .with_response(uci::GID_CORE, 0x03, &GET_CAPS_INFO_RSP)
+            .with_response(
+                uci::GID_CORE,
+                uci::OID_CORE_GET_DEVICE_INFO,
+                &DEVICE_INFO_RSP,
+            );
+        let chip = UwbChip::new_mock("0".to_owned(), uwbs.clone());
+        let client = BlockingClient::default();
+
+        chip.open(&client.callbacks()).await.unwrap();
+        chip.sendUciMessage(&GET_CAPS_INFO_CMD).await.unwrap();
+        wait_until(|| client.is_blocked()).await;
+
+        // The reader task keeps processing the responses while the client
+        // is blocked receiving the previous one.
+        chip.get_device_info().await.unwrap();
+        chip.sendUciMessage(&GET_CAPS_INFO_CMD).await.unwrap();
+        client.release();
+    }
+
+    #[tokio::test]
+    async fn core_init_caches_capabilities() {
+        const CAPS_INFO_RSP: [u8; 12] = [
+            0x40, 0x03, 0x00, 0x08, 0x00, 0x02, 0x0b, 0x01, 0x09, 0xe3, 0x01, 0x01,
+        ];
+
+        /// Report the maximum number of sessions in the vendor TLV 0xe3.
+        struct SessionsHandler;
+
+        impl VendorUciHandler for SessionsHandler {
+            fn on_vendor_message(&self, _gid: u8, _data: &[u8]) -> Option<Vec<u8>> {
+                None
+            }
+
+            fn max_sessions(&self, caps_info_rsp: &[u8]) -> Option<usize> {
+                match caps_info_rsp[caps_info_rsp.len() - 3..] {
+                    [0xe3, 0x01, max_sessions] => Some(max_sessions as usize),
+                    _ => None,
+                }
+            }
+        }
+
+        let uwbs = MockUwbs::default()
+            .with_response(
+                uci::GID_CORE,
+                uci::OID_CORE_GET_DEVICE_INFO,
+                &DEVICE_INFO_RSP,
+            )
+            .with_response(uci::GID_CORE, uci::OID_CORE_GET_CAPS_INFO, &CAPS_INFO_RSP);
+        let chip = UwbChip::new_mock("0".to_owned(), uwbs.clone())
+            .with_vendor_handler(Arc::new(SessionsHandler));
+        let client = TestClient::default();
+
+        chip.open(&client.callbacks()).await.unwrap();
+        // Clients initialize the chip once notified that it is opened.
+        wait_until(|| !client.events().is_empty()).await;
+        chip.coreInit().await.unwrap();
+        wait_until(|| chip.capabilities().is_some()).await;
+        let capabilities = chip.capabilities().unwrap();
+        assert_eq!(capabilities.channels, [5, 9]);
+        assert_eq!(capabilities.max_sessions, Some(1));
+        assert!(client.messages().is_empty());
+        assert_eq!(
+            client.events().last(),
+            Some(&(UwbEvent::POST_INIT_CPLT, UwbStatus::OK))
+        );
+
+        // Sessions beyond the limit of the UWBS are rejected.
+        chip.sessionInit(1).await.unwrap();
+        let err = chip.sessionInit(2).await.unwrap_err();
+        assert_eq!(err.exception_code(), binder::ExceptionCode::ILLEGAL_STATE);
+    }
+
+    #[tokio::test]
+    async fn core_init_does_not_wait_for_the_uwbs() {
+        let uwbs = MockUwbs::default();
+        // The device info query never times out on the fake clock.
+        let chip = UwbChip::new_mock("0".to_owned(), uwbs.clone())
+            .with_clock(Arc::new(FakeClock::default()));
+        let client = TestClient::default();
+
+        chip.open(&client.callbacks()).await.unwrap();
+        // Clients initialize the chip once notified that it is opened.
+        wait_until(|| !client.events().is_empty()).await;
+        chip.coreInit().await.unwrap();
+        assert_eq!(
+            client.events().last(),
+            Some(&(UwbEvent::POST_INIT_CPLT, UwbStatus::OK))
+        );
+        wait_until(|| uwbs.written() == [0x20, 0x02, 0x00, 0x00]).await;
+        assert_eq!(chip.capabilities(), None);
+    }
+
+    #[tokio::test]
+    async fn configured_max_sessions_limits_sessions() {
+        const CAPS_INFO_RSP: [u8; 6] = [0x40, 0x03, 0x00, 0x02, 0x00, 0x00];
+        let uwbs = MockUwbs::default()
+            .with_response(
+                uci::GID_CORE,
+                uci::OID_CORE_GET_DEVICE_INFO,
+                &DEVICE_INFO_RSP,
+            )
+            .with_response(uci::GID_CORE, uci::OID_CORE_GET_CAPS_INFO, &CAPS_INFO_RSP);
+        let chip = UwbChip::new_mock("0".to_owned(), uwbs).with_vendor_handler(Arc::new(
+            vendor::Configured {
+                max_sessions: Some(1),
+                ..Default::default()
+            },
+        ));
+        let client = TestClient::default();
+
+        chip.open(&client.callbacks()).await.unwrap();
+        chip.coreInit().await.unwrap();
+        wait_until(|| chip.capabilities().is_some()).await;
+        assert_eq!(chip.capabilities().unwrap().max_sessions, Some(1));
+        chip.sessionInit(1).await.unwrap();
+        let err = chip.sessionInit(2).await.unwrap_err();
+        assert_eq!(err.exception_code(), binder::ExceptionCode::ILLEGAL_STATE);
+    }
+
+    #[tokio::test]
+    async fn duplicate_session_init_is_rejected() {
+        let chip = UwbChip::new_mock("0".to_owned(), MockUwbs::default());
+        let client = TestClient::default();
+
+        chip.open(&client.callbacks()).await.unwrap();
+        chip.sessionInit(1).await.unwrap();
+        let err = chip.sessionInit(1).await.unwrap_err();
+        assert_eq!(err.exception_code(), binder::ExceptionCode::ILLEGAL_STATE);
+
+        let state = chip.state.lock().await;
+        let State::Opened { ref sessions, .. } = *state else {
+            panic!("the chip is not opened");
+        };
+        assert_eq!(sessions.keys().collect::<Vec<_>>(), [&1]);
+    }
+
+    #[tokio::test]
+    async fn session_init_sets_vendor_app_configs() {
+        // STATUS_INVALID_PARAM, with the channel number rejected.
+        const SET_APP_CONFIG_RSP: [u8; 8] = [0x41, 0x03, 0x00, 0x04, 0x04, 0x01, 0x04, 0x04];
+
+        let uwbs = MockUwbs::default().with_response(
+            uci::GID_SESSION_CONFIG,
+            uci::OID_SESSION_SET_APP_CONFIG,
+            &SET_APP_CONFIG_RSP,
+        );
+        let chip = UwbChip::new_mock("0".to_owned(), uwbs.clone()).with_vendor_handler(Arc::new(
+            vendor::Configured {
+                // The configured channel of the sessions.
+                session_app_configs: vec![(AppConfigTag::CHANNEL_NUMBER, vec![0x07])],
+                ..Default::default()
+            },
+        ));
+        let client = TestClient::default();
+
+        chip.open(&client.callbacks()).await.unwrap();
+        let err = chip.sessionInit(1).await.unwrap_err();
+        assert_eq!(
+            err.exception_code(),
+            binder::ExceptionCode::ILLEGAL_ARGUMENT
+        );
+        assert_eq!(
+            uwbs.written(),
+            [0x21, 0x03, 0x00, 0x08, 0x01, 0x00, 0x00, 0x00, 0x01, 0x04, 0x01, 0x07]
+        );
+        assert!(client.messages().is_empty());
+    }
+
+    #[tokio::test]
+    async fn session_deinit_removes_session() {
+        const SESSION_DEINIT_CMD: [u8; 8] = [0x21, 0x01, 0x00, 0x04, 0x01, 0x00, 0x00, 0x00];
+        const SESSION_DEINIT_RSP: [u8; 5] = [0x41, 0x01, 0x00, 0x01, 0x00];
+        let uwbs = MockUwbs::default().with_response(
+            uci::GID_SESSION_CONFIG,
+            uci::OID_SESSION_DEINIT,
+            &SESSION_DEINIT_RSP,
+        );
+        let chip = UwbChip::new_mock("0".to_owned(), uwbs.clone());
+        let client = TestClient::default();
+
+        chip.open(&client.callbacks()).await.unwrap();
+        chip.sessionInit(1).await.unwrap();
+        chip.sendUciMessage(&SESSION_DEINIT_CMD).await.unwrap();
+
+        // The session is removed once the reader task has processed the response.
+        wait_until(|| {
+            matches!(
+                chip.state.try_lock().as_deref(),
+                Ok(State::Opened { sessions, .. }) if sessions.is_empty()
+            )
+        })
+        .await;
+
+        // The deinit of an unknown session is forwarded to the UWBS.
+        chip.sendUciMessage(&SESSION_DEINIT_CMD).await.unwrap();
+        assert_eq!(
+            uwbs.written(),
+            [SESSION_DEINIT_CMD, SESSION_DEINIT_CMD].concat()
+        );
+    }
+
+    #[tokio::test]
+    async fn session_deinit_helper_removes_session() {
+        const SESSION_DEINIT_RSP: [u8; 5] = [0x41, 0x01, 0x00, 0x01, 0x00];
+        let uwbs = MockUwbs::default().with_response(
+            uci::GID_SESSION_CONFIG,
+            uci::OID_SESSION_DEINIT,
+            &SESSION_DEINIT_RSP,
+        );
+        let chip = UwbChip::new_mock("0".to_owned(), uwbs.clone());
+        let client = TestClient::default();
+
+        chip.open(&client.callbacks()).await.unwrap();
+        chip.sessionInit(1).await.unwrap();
+        chip.session_deinit(1).await.unwrap();
+        assert_eq!(
+            uwbs.written(),
+            [0x21, 0x01, 0x00, 0x04, 0x01, 0x00, 0x00, 0x00]
+        );
+        assert!(matches!(
+            *chip.state.lock().await,
+            State::Opened { ref sessions, .. } if sessions.is_empty()
+        ));
+
+        let err = chip.session_deinit(1).await.unwrap_err();
+        assert_eq!(err.exception_code(), binder::ExceptionCode::ILLEGAL_STATE);
+        assert!(client.messages().is_empty());
+    }
+
+    #[tokio::test]
+    async fn data_packets_require_started_session() {
+        const DATA_PACKET: [u8; 10] = [0x01, 0x00, 0x06, 0x00, 0x01, 0x00, 0x00, 0x00, 0xaa, 0xbb];
+        const SESSION_ACTIVE_NTF: [u8; 10] =
+            [0x61, 0x02, 0x00, 0x06, 0x01, 0x00, 0x00, 0x00, 0x02, 0x00];
+        const SESSION_IDLE_NTF: [u8; 10] =
+            [0x61, 0x02, 0x00, 0x06, 0x01, 0x00, 0x00, 0x00, 0x03, 0x00];
+        const DATA_TRANSFER_STATUS_NTF: [u8; 11] = [
+            0x62, 0x05, 0x00, 0x07, 0x01, 0x00, 0x00, 0x00, 0x00, 0x01, 0x01,
+        ];
+        let uwbs = MockUwbs::default();
+        let chip = UwbChip::new_mock("0".to_owned(), uwbs.clone());
+        let client = TestClient::default();
+        let phase = || async {
+            let State::Opened { ref phases, .. } = *chip.state.lock().await else {
+                panic!("the chip is not opened");
+            };
+            let phase = phases.0.lock().unwrap().get(&1).copied();
+            phase
+        };
+
+        chip.open(&client.callbacks()).await.unwrap();
+        let err = chip.sendUciMessage(&DATA_PACKET).await.unwrap_err();
+        assert_eq!(err.exception_code(), binder::ExceptionCode::ILLEGAL_STATE);
+        assert_eq!(phase().await, None);
+
+        // The notifications are processed before being delivered.
+        uwbs.notify(&SESSION_ACTIVE_NTF);
+        wait_until(|| client.messages().len() == 1).await;
+        assert_eq!(phase().await, Some(SessionPhase::Active));
+        chip.sendUciMessage(&DATA_PACKET).await.unwrap();
+        assert_eq!(phase().await, Some(SessionPhase::DataTransfer));
+
+        uwbs.notify(&DATA_TRANSFER_STATUS_NTF);
+        wait_until(|| client.messages().len() == 2).await;
+        assert_eq!(phase().await, Some(SessionPhase::Active));
+
+        uwbs.notify(&SESSION_IDLE_NTF);
+        wait_until(|| client.messages().len() == 3).await;
+        assert_eq!(phase().await, Some(SessionPhase::Idle));
+        let err = chip.sendUciMessage(&DATA_PACKET).await.unwrap_err();
+        assert_eq!(err.exception_code(), binder::ExceptionCode::ILLEGAL_STATE);
+        assert_eq!(uwbs.written(), DATA_PACKET);
+    }
+
+    /// DATA_CREDIT_NTF granting a credit to session 1.
+    const DATA_CREDIT_NTF: [u8; 9] = [0x62, 0x04, 0x00, 0x05, 0x01, 0x00, 0x00, 0x00, 0x01];
+
+    /// Build a data packet of session 1 carrying `length` payload bytes.
+    fn data_packet(length: usize) -> Vec<u8> {
+        let mut packet = vec![0x01, 0x00];
+        packet.extend_from_slice(&(length as u16).to_le_bytes());
+        packet.extend_from_slice(&[0x01, 0x00, 0x00, 0x00]);
+        packet.resize(uci::UCI_HEADER_SIZE + length, 0xaa);
+        packet
+    }
+
+    #[tokio::test]
+    async fn unused_credit_is_given_back() {
+        let credits = DataCredits::default();
+
+        // The credit of a packet failing to be written is given back.
+        drop(credits.acquire(1).await);
+        credits.acquire(1).await.consume();
+        let acquire = tokio::time::timeout(Duration::from_millis(10), credits.acquire(1));
+        assert!(acquire.await.is_err());
+    }
+
+    #[tokio::test]
+    async fn oversized_data_packets_are_segmented() {
+        const SESSION_ACTIVE_NTF: [u8; 10] =
+            [0x61, 0x02, 0x00, 0x06, 0x01, 0x00, 0x00, 0x00, 0x02, 0x00];
+        let uwbs = MockUwbs::default();
+        let chip = UwbChip::new_mock("0".to_owned(), uwbs.clone())
+            .with_max_data_payload_size(255)
+            .with_data_segmentation(true);
+        let client = TestClient::default();
+
+        chip.open(&client.callbacks()).await.unwrap();
+        uwbs.notify(&SESSION_ACTIVE_NTF);
+        wait_until(|| client.messages().len() == 1).await;
+        let packet = data_packet(600);
+        // The first segment takes the credit the session starts with.
+        let grant_credits = async {
+            for written in [259, 518] {
+                wait_until(|| uwbs.written().len() == written).await;
+                uwbs.notify(&DATA_CREDIT_NTF);
+            }
+        };
+        let (sent, ()) = tokio::join!(chip.sendUciMessage(&packet), grant_credits);
+        assert_eq!(sent.unwrap(), 604);
+
+        // The segments carry the payload in order.
+        let written = uwbs.written();
+        let mut reassembler = Reassembler::default();
+        let mut segments = Vec::new();
+        let mut message = None;
+        let mut offset = 0;
+        while offset < written.len() {
+            let header = UciHeader::parse(&written[offset..]).unwrap();
+            let end = offset + uci::UCI_HEADER_SIZE + header.payload_length;
+            segments.push((header.pbf, header.payload_length));
+            message = reassembler.push(header, written[offset..end].to_vec());
+            offset = end;
+        }
+        assert_eq!(segments, [(true, 255), (true, 255), (false, 90)]);
+        assert_eq!(message, Some(packet));
+    }
+
+    #[tokio::test]
+    async fn segments_wait_for_data_credits() {
+        const SESSION_ACTIVE_NTF: [u8; 10] =
+            [0x61, 0x02, 0x00, 0x06, 0x01, 0x00, 0x00, 0x00, 0x02, 0x00];
+        let uwbs = MockUwbs::default();
+        let chip = UwbChip::new_mock("0".to_owned(), uwbs.clone())
+            .with_max_data_payload_size(255)
+            .with_data_segmentation(true);
+        let client = TestClient::default();
+
+        chip.open(&client.callbacks()).await.unwrap();
+        uwbs.notify(&SESSION_ACTIVE_NTF);
+        wait_until(|| client.messages().len() == 1).await;
+        chip.sendUciMessage(&data_packet(10)).await.unwrap();
+
+        // Each of the two segments is written once the UWBS grants a credit.
+        let grant_credits = async {
+            for written in [14, 14 + 259] {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+                assert_eq!(uwbs.written().len(), written);
+                uwbs.notify(&DATA_CREDIT_NTF);
+                wait_until(|| uwbs.written().len() > written).await;
+            }
+        };
+        let packet = data_packet(400);
+        let (sent, ()) = tokio::join!(chip.sendUciMessage(&packet), grant_credits);
+        assert_eq!(sent.unwrap(), 404);
+        assert_eq!(uwbs.written().len(), 14 + 259 + 149);
+    }
+
+    #[tokio::test]
+    async fn oversized_data_packets_are_rejected() {
+        const SESSION_ACTIVE_NTF: [u8; 10] =
+            [0x61, 0x02, 0x00, 0x06, 0x01, 0x00, 0x00, 0x00, 0x02, 0x00];
+        let uwbs = MockUwbs::default();
+        let chip = UwbChip::new_mock("0".to_owned(), uwbs.clone()).with_max_data_payload_size(255);
+        let client = TestClient::default();
+
+        chip.open(&client.callbacks()).await.unwrap();
+        uwbs.notify(&SESSION_ACTIVE_NTF);
+        wait_until(|| client.messages().len() == 1).await;
+        let err = chip.sendUciMessage(&data_packet(600)).await.unwrap_err();
+        assert_eq!(
+            err.exception_code(),
+            binder::ExceptionCode::ILLEGAL_ARGUMENT
+        );
+        chip.sendUciMessage(&data_packet(255)).await.unwrap();
+        assert_eq!(uwbs.written(), data_packet(255));
+    }
+
+    #[tokio::test]
+    async fn client_death_while_state_is_locked() {
+        let uwbs = MockUwbs::default();
+        let chip = UwbChip::new_mock("0".to_owned(), uwbs.clone());
+        let callbacks = TestClient::default().callbacks();
+
+        chip.open(&callbacks).await.unwrap();
+        let state = chip.state.lock().await;
+        // The death notification does not wait for the lock.
+        remove_dead_client(
+            &tokio::runtime::Handle::current(),
+            chip.state.clone(),
+            callbacks.as_binder(),
+        );
+        assert!(matches!(*state, State::Opened { .. }));
+        drop(state);
+        wait_until(|| matches!(chip.state.try_lock().as_deref(), Ok(State::Closed))).await;
+    }
+
+    #[tokio::test]
+    async fn device_error_clears_sessions() {
+        const DEVICE_STATUS_ERROR_NTF: [u8; 5] = [0x60, 0x01, 0x00, 0x01, 0xff];
+        let uwbs = MockUwbs::default();
+        let chip = UwbChip::new_mock("0".to_owned(), uwbs.clone());
+        let client = TestClient::default();
+
+        chip.open(&client.callbacks()).await.unwrap();
+        chip.sessionInit(1).await.unwrap();
+        uwbs.notify(&DEVICE_STATUS_ERROR_NTF);
+        wait_until(|| !client.messages().is_empty()).await;
+        assert_eq!(client.messages(), [DEVICE_STATUS_ERROR_NTF]);
+
+        wait_until(|| {
+            matches!(
+                chip.state.try_lock().as_deref(),
+                Ok(State::Opened { sessions, .. }) if sessions.is_empty()
+            )
+        })
+        .await;
+        chip.sessionInit(1).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn fatal_generic_error_notifies_hal_error() {
+        // STATUS_COMMAND_RETRY is recovered by the client.
+        const COMMAND_RETRY_NTF: [u8; 5] = [0x60, 0x07, 0x00, 0x01, 0x0a];
+        const FAILED_NTF: [u8; 5] = [0x60, 0x07, 0x00, 0x01, 0x02];
+        let uwbs = MockUwbs::default();
+        let chip = UwbChip::new_mock("0".to_owned(), uwbs.clone());
+        let client = TestClient::default();
+
+        chip.open(&client.callbacks()).await.unwrap();
+        wait_until(|| client.events() == [(UwbEvent::OPEN_CPLT, UwbStatus::OK)]).await;
+        uwbs.notify(&COMMAND_RETRY_NTF);
+        uwbs.notify(&FAILED_NTF);
+
+        wait_until(|| client.messages().len() == 2).await;
+        assert_eq!(client.messages(), [COMMAND_RETRY_NTF, FAILED_NTF]);
+        assert_eq!(
+            client.events(),
+            [
+                (UwbEvent::OPEN_CPLT, UwbStatus::OK),
+                (UwbEvent::ERROR, UwbStatus::FAILED)
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn vendor_handler_intercepts_messages() {
+        const VENDOR_PING_NTF: [u8; 4] = [0x6e, 0x01, 0x00, 0x00];
+        const VENDOR_PONG_CMD: [u8; 4] = [0x2e, 0x02, 0x00, 0x00];
+        const VENDOR_OTHER_NTF: [u8; 4] = [0x6e, 0x03, 0x00, 0x00];
+
+        /// Answer the vendor ping notifications.
+        struct PingHandler;
+
+        impl VendorUciHandler for PingHandler {
+            fn on_vendor_message(&self, _gid: u8, data: &[u8]) -> Option<Vec<u8>> {
+                (data == VENDOR_PING_NTF).then(|| VENDOR_PONG_CMD.to_vec())
+            }
+        }
+
+        let uwbs = MockUwbs::default();
+        let chip = UwbChip::new_mock("0".to_owned(), uwbs.clone())
+            .with_vendor_handler(Arc::new(PingHandler));
+        let client = TestClient::default();
+
+        chip.open(&client.callbacks()).await.unwrap();
+        uwbs.notify(&VENDOR_PING_NTF);
+        uwbs.notify(&VENDOR_OTHER_NTF);
+        wait_until(|| uwbs.written() == VENDOR_PONG_CMD).await;
+        wait_until(|| !client.messages().is_empty()).await;
+        assert_eq!(client.messages(), [VENDOR_OTHER_NTF]);
+    }
+
+    #[tokio::test]
+    async fn android_uci_version_is_reported() {
+        const DEVICE_INFO_RSP: [u8; 15] = [
+            0x40, 0x02, 0x00, 0x0b, 0x00, 0x02, 0x00, 0x01, 0x00, 0x01, 0x00, 0x01, 0x00, 0x01,
+            0x02,
+        ];
+        const CAPS_INFO_RSP: [u8; 6] = [0x40, 0x03, 0x00, 0x02, 0x00, 0x00];
+
+        let uwbs = MockUwbs::default()
+            .with_response(
+                uci::GID_CORE,
+                uci::OID_CORE_GET_DEVICE_INFO,
+                &DEVICE_INFO_RSP,
+            )
+            .with_response(uci::GID_CORE, uci::OID_CORE_GET_CAPS_INFO, &CAPS_INFO_RSP);
+        let chip = UwbChip::new_mock("0".to_owned(), uwbs.clone()).with_vendor_handler(Arc::new(
+            vendor::Configured {
+                // The Android UCI version is the first vendor specific octet.
+                android_uci_version_offset: Some(0),
+                ..Default::default()
+            },
+        ));
+        let client = TestClient::default();
+
+        chip.open(&client.callbacks()).await.unwrap();
+        assert_eq!(
+            chip.getSupportedAndroidUciVersion().await.unwrap(),
+            DEFAULT_ANDROID_UCI_VERSION
+        );
+        chip.coreInit().await.unwrap();
+        wait_until(|| *chip.android_uci_version.lock().unwrap() == Some(2)).await;
+        assert_eq!(chip.getSupportedAndroidUciVersion().await.unwrap(), 2);
+        assert!(client.messages().is_empty());
+    }
+
+    #[tokio::test]
+    async fn corrupt_header_is_resynchronized() {
+        let uwbs = MockUwbs::default();
+        let chip = UwbChip::new_mock("0".to_owned(), uwbs.clone());
+        let client = TestClient::default();
+
+        chip.open(&client.callbacks()).await.unwrap();
+        // Garbage byte with a reserved message type.
+        let mut stream = vec![0xff];
+        stream.extend_from_slice(&DEVICE_STATUS_READY_NTF);
+        stream.extend_from_slice(&DEVICE_STATUS_READY_NTF);
+        uwbs.notify(&stream);
+
+        wait_until(|| client.messages().len() == 2).await;
+        assert_eq!(
+            client.messages(),
+            [DEVICE_STATUS_READY_NTF, DEVICE_STATUS_READY_NTF]
+        );
+        assert!(matches!(*chip.state.lock().await, State::Opened { .. }));
+    }
+
+    #[tokio::test]
+    async fn oversized_packet_is_discarded() {
+        let uwbs = MockUwbs::default();
+        let chip = UwbChip::new_mock("0".to_owned(), uwbs.clone());
+        let client = TestClient::default();
+
+        chip.open(&client.callbacks()).await.unwrap();
+        // The payload exceeds the default maximum packet size.
+        let mut stream = data_packet(2000);
+        stream.extend_from_slice(&DEVICE_STATUS_READY_NTF);
+        uwbs.notify(&stream);
+
+        wait_until(|| !client.messages().is_empty()).await;
+        assert_eq!(client.messages(), [DEVICE_STATUS_READY_NTF]);
+        assert!(matches!(*chip.state.lock().await, State::Opened { .. }));
+    }
+
+    #[tokio::test]
+    async fn large_data_packet_is_received_across_reads() {
+        let uwbs = MockUwbs::default();
+        let chip = UwbChip::new_mock("0".to_owned(), uwbs.clone()).with_max_packet_size(4096);
+        let client = TestClient::default();
+
+        chip.open(&client.callbacks()).await.unwrap();
+        let packet = data_packet(4096 - uci::UCI_HEADER_SIZE);
+        uwbs.notify(&packet[..1500]);
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert!(client.messages().is_empty());
+        uwbs.notify(&packet[1500..]);
+
+        wait_until(|| !client.messages().is_empty()).await;
+        assert_eq!(client.messages(), [packet]);
+    }
+
+    #[tokio::test]
+    async fn tee_copies_both_directions() {
+        const GET_CAPS_INFO_CMD: [u8; 4] = [0x20, 0x03, 0x00, 0x00];
+
+        /// Sink shared with the tee thread.
+        #[derive(Clone, Default)]
+        struct Sink(Arc<std::sync::Mutex<Vec<u8>>>);
+
+        impl std::io::Write for Sink {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                self.0.lock().unwrap().extend_from_slice(buf);
+                Ok(buf.len())
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let uwbs = MockUwbs::default();
+        let sink = Sink::default();
+        let chip = UwbChip::new_mock("0".to_owned(), uwbs.clone()).with_tee(sink.clone());
+        let client = TestClient::default();
+
+        chip.open(&client.callbacks()).await.unwrap();
+        uwbs.notify(&DEVICE_STATUS_READY_NTF);
+        wait_until(|| !client.messages().is_empty()).await;
+        chip.sendUciMessage(&GET_CAPS_INFO_CMD).await.unwrap();
+
+        let expected = [&DEVICE_STATUS_READY_NTF[..], &GET_CAPS_INFO_CMD[..]].concat();
+        wait_until(|| *sink.0.lock().unwrap() == expected).await;
+        assert_eq!(uwbs.written(), GET_CAPS_INFO_CMD);
+    }
+
+    #[tokio::test]
+    async fn send_rejects_malformed_packets() {
+        let uwbs = MockUwbs::default();
+        let chip = UwbChip::new_mock("0".to_owned(), uwbs.clone());
+        let client = TestClient::default();
+
+        chip.open(&client.callbacks()).await.unwrap();
+        for packet in [
+            // Truncated header.
+            &[0x20, 0x03][..],
+            // Control packet declaring a 1 byte payload.
+            &[0x20, 0x03, 0x00, 0x01][..],
+            // Data packet declaring a 2 byte payload.
+            &[0x00, 0x00, 0x02, 0x00, 0x01, 0x02, 0x03][..],
+        ] {
+            let status = chip.sendUciMessage(packet).await.unwrap_err();
+            assert_eq!(
+                status.exception_code(),
+                binder::ExceptionCode::ILLEGAL_ARGUMENT
+            );
+        }
+        assert!(uwbs.written().is_empty());
+    }
+
+    #[tokio::test]
+    async fn idle_chip_is_closed() {
+        let uwbs = MockUwbs::default();
+        let chip = UwbChip::new_mock("0".to_owned(), uwbs.clone())
+            .with_idle_timeout(Duration::from_millis(20))
+            .with_idle_close(true);
+        let client = TestClient::default();
+
+        chip.open(&client.callbacks()).await.unwrap();
+        wait_until(|| {
+            client
+                .events()
+                .contains(&(UwbEvent::CLOSE_CPLT, UwbStatus::OK))
+        })
+        .await;
+        assert!(client.events().ends_with(&[
+            (UwbEvent::ERROR, UwbStatus::ERR_CMD_TIMEOUT),
+            (UwbEvent::CLOSE_CPLT, UwbStatus::OK)
+        ]));
+        assert!(matches!(*chip.state.lock().await, State::Closed));
+    }
+
+    #[tokio::test]
+    async fn idle_chip_is_warned_once_per_idle_period() {
+        let uwbs = MockUwbs::default();
+        let clock = FakeClock::default();
+        let chip = UwbChip::new_mock("0".to_owned(), uwbs.clone())
+            .with_clock(Arc::new(clock.clone()))
+            .with_idle_timeout(Duration::from_secs(1));
+        let client = TestClient::default();
+        let idle_warnings = || {
+            client
+                .events()
+                .iter()
+                .filter(|event| **event == (UwbEvent::ERROR, UwbStatus::ERR_CMD_TIMEOUT))
+                .count()
+        };
+
+        chip.open(&client.callbacks()).await.unwrap();
+        wait_until(|| clock.sleeps() > 0).await;
+        clock.advance(Duration::from_millis(600));
+        uwbs.notify(&DEVICE_INFO_RSP);
+        wait_until(|| client.messages().len() == 1).await;
+        // Idle for the timeout since the notification, not since the open.
+        clock.advance(Duration::from_millis(600));
+        wait_until(|| clock.sleeps() > 0).await;
+        assert_eq!(idle_warnings(), 0);
+        clock.advance(Duration::from_millis(400));
+        wait_until(|| idle_warnings() == 1 && clock.sleeps() > 0).await;
+        clock.advance(Duration::from_secs(3));
+        wait_until(|| clock.sleeps() > 0).await;
+        assert_eq!(idle_warnings(), 1);
+        assert!(matches!(*chip.state.lock().await, State::Opened { .. }));
+    }
+
+    #[tokio::test]
+    async fn device_eof_closes_chip() {
+        let uwbs = MockUwbs::default();
+        let chip = UwbChip::new_mock("0".to_owned(), uwbs.clone());
+        let client = TestClient::default();
+
+        chip.open(&client.callbacks()).await.unwrap();
+        uwbs.close();
+        wait_until(|| {
+            client
+                .events()
+                .contains(&(UwbEvent::CLOSE_CPLT, UwbStatus::OK))
+        })
+        .await;
+
+        assert!(matches!(*chip.state.lock().await, State::Closed));
+        assert!(!client
+            .events()
+            .contains(&(UwbEvent::ERROR, UwbStatus::FAILED)));
+    }
+
+    #[tokio::test]
+    async fn transport_info_reports_connection() {
+        let uwbs = MockUwbs::default();
+        let chip = UwbChip::new_mock("0".to_owned(), uwbs.clone());
+        let client = TestClient::default();
+        assert!(!chip.transport_info().connected);
+
+        chip.open(&client.callbacks()).await.unwrap();
+        let info = chip.transport_info();
+        assert_eq!(info.kind, crate::transport::TransportKind::Test);
+        assert_eq!(info.location, "mock");
+        assert!(info.connected);
+
+        uwbs.close();
+        wait_until(|| !chip.transport_info().connected).await;
+    }
+
+    #[tokio::test]
+    async fn device_read_error_reconnects_chip() {
+        let uwbs = MockUwbs::default();
+        let chip = UwbChip::new_mock("0".to_owned(), uwbs.clone());
+        let client = TestClient::default();
+
+        chip.open(&client.callbacks()).await.unwrap();
+        uwbs.fail_reads();
+        wait_until(|| {
+            client
+                .events()
+                .contains(&(UwbEvent::ERROR, UwbStatus::FAILED))
+        })
+        .await;
+
+        assert!(matches!(
+            *chip.state.lock().await,
+            State::Reconnecting { .. }
+        ));
+        assert!(!client
+            .events()
+            .contains(&(UwbEvent::CLOSE_CPLT, UwbStatus::OK)));
+    }
+
+    #[tokio::test]
+    async fn pre_open_notification_is_replayed() {
+        let uwbs = MockUwbs::default();
+        let chip = UwbChip::new_mock("0".to_owned(), uwbs.clone())
+            .with_reconnect_backoff(Backoff {
+                initial: Duration::from_millis(1),
+                ..Default::default()
+            })
+            .with_pre_open_replay(4);
+        let client = TestClient::default();
+
+        chip.open(&client.callbacks()).await.unwrap();
+        uwbs.fail_next_read();
+        wait_until(|| matches!(chip.state.try_lock().as_deref(), Ok(State::Standby { .. }))).await;
+        // The connection is held by the task reading the device in standby.
+        assert!(chip.transport_info().connected);
+
+        // The UWBS notifies that it is ready before the chip is reopened.
+        uwbs.notify(&DEVICE_STATUS_READY_NTF);
+        wait_until(|| {
+            matches!(
+                chip.state.try_lock().as_deref(),
+                Ok(State::Standby { notifications, .. })
+                    if notifications.lock().unwrap().len() == 1
+            )
+        })
+        .await;
+
+        let next_client = TestClient::default();
+        chip.open(&next_client.callbacks()).await.unwrap();
+        wait_until(|| !next_client.messages().is_empty()).await;
+        assert_eq!(next_client.messages(), [DEVICE_STATUS_READY_NTF]);
+        assert!(client.messages().is_empty());
+        assert!(matches!(*chip.state.lock().await, State::Opened { .. }));
+    }
+
+    #[tokio::test]
+    async fn device_read_error_restarts_reader() {
+        let uwbs = MockUwbs::default().with_response(
+            uci::GID_CORE,
+            uci::OID_CORE_GET_DEVICE_INFO,
+            &DEVICE_INFO_RSP,
+        );
+        let chip = UwbChip::new_mock("0".to_owned(), uwbs.clone()).with_restart_budget(3);
+        let client = TestClient::default();
+
+        chip.open(&client.callbacks()).await.unwrap();
+        uwbs.fail_next_read();
+        wait_until(|| chip.metrics_snapshot().reader_restarts == 1).await;
+
+        // The chip keeps working with the new connection.
+        uwbs.notify(&DEVICE_STATUS_READY_NTF);
+        wait_until(|| !client.messages().is_empty()).await;
+        chip.get_device_info().await.unwrap();
+        assert!(matches!(*chip.state.lock().await, State::Opened { .. }));
+        assert_eq!(chip.metrics_snapshot().reader_restarts, 1);
+        assert_eq!(chip.last_error(), None);
+        assert!(!client
+            .events()
+            .contains(&(UwbEvent::ERROR, UwbStatus::FAILED)));
+    }
+
+    #[tokio::test]
+    async fn read_error_is_kept_as_last_error() {
+        let uwbs = MockUwbs::default();
+        let chip =
+            UwbChip::new_mock("0".to_owned(), uwbs.clone()).with_reconnect_backoff(Backoff {
+                initial: Duration::from_secs(10),
+                ..Default::default()
+            });
+        let client = TestClient::default();
+
+        chip.open(&client.callbacks()).await.unwrap();
+        assert_eq!(chip.last_error(), None);
+        uwbs.fail_reads();
+        wait_until(|| chip.last_error().is_some()).await;
+        assert_eq!(
+            chip.last_error(),
+            Some(io::Error::from(io::ErrorKind::BrokenPipe).to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn send_while_reconnecting_fails() {
+        const GET_CAPS_INFO_CMD: [u8; 4] = [0x20, 0x03, 0x00, 0x00];
+
+        let uwbs = MockUwbs::default();
+        let chip =
+            UwbChip::new_mock("0".to_owned(), uwbs.clone()).with_reconnect_backoff(Backoff {
+                initial: Duration::from_secs(10),
+                ..Default::default()
+            });
+        let client = TestClient::default();
+
+        chip.open(&client.callbacks()).await.unwrap();
+        uwbs.fail_reads();
+        wait_until(|| {
+            client
+                .events()
+                .contains(&(UwbEvent::ERROR, UwbStatus::FAILED))
+        })
+        .await;
+
+        let status = chip.sendUciMessage(&GET_CAPS_INFO_CMD).await.unwrap_err();
+        assert_eq!(
+            status.exception_code(),
+            binder::ExceptionCode::ILLEGAL_STATE
+        );
+        assert!(uwbs.written().is_empty());
+        assert!(matches!(
+            *chip.state.lock().await,
+            State::Reconnecting { .. }
+        ));
+    }
+
+    #[tokio::test]
+    async fn read_only_chip_rejects_sends() {
+        const GET_CAPS_INFO_CMD: [u8; 4] = [0x20, 0x03, 0x00, 0x00];
+        let uwbs = MockUwbs::default();
+        let chip = UwbChip::new_mock("0".to_owned(), uwbs.clone())
+            .with_open_mode(OpenMode::ReadOnly)
+            .with_probe(true);
+        let client = TestClient::default();
+
+        chip.open(&client.callbacks()).await.unwrap();
+        uwbs.notify(&DEVICE_STATUS_READY_NTF);
+        wait_until(|| client.messages() == [DEVICE_STATUS_READY_NTF]).await;
+        assert!(client
+            .events()
+            .contains(&(UwbEvent::OPEN_CPLT, UwbStatus::OK)));
+
+        let err = chip.sendUciMessage(&GET_CAPS_INFO_CMD).await.unwrap_err();
+        assert_eq!(
+            err.exception_code(),
+            binder::ExceptionCode::UNSUPPORTED_OPERATION
+        );
+
+        // The chip is closed without resetting the device.
+        let err = chip.close().await.unwrap_err();
+        assert_eq!(
+            err.exception_code(),
+            binder::ExceptionCode::UNSUPPORTED_OPERATION
+        );
+        assert!(matches!(*chip.state.lock().await, State::Closed));
+        assert!(uwbs.written().is_empty());
+    }
+
+    #[tokio::test]
+    async fn shutdown_delivers_received_messages() {
+        const SESSION_STATUS_NTF: [u8; 10] =
+            [0x61, 0x02, 0x00, 0x06, 0x01, 0x00, 0x00, 0x00, 0x02, 0x00];
+        const RANGE_DATA_NTF: [u8; 6] = [0x62, 0x00, 0x00, 0x02, 0x01, 0x02];
+        let uwbs = MockUwbs::default();
+        let chip = UwbChip::new_mock("0".to_owned(), uwbs.clone());
+        let client = TestClient::default();
+
+        chip.open(&client.callbacks()).await.unwrap();
+        uwbs.notify(&SESSION_STATUS_NTF);
+        uwbs.notify(&RANGE_DATA_NTF);
+        uwbs.notify(&RANGE_DATA_NTF);
+        chip.shutdown().await;
+
+        assert_eq!(
+            client.messages(),
+            [
+                SESSION_STATUS_NTF.to_vec(),
+                RANGE_DATA_NTF.to_vec(),
+                RANGE_DATA_NTF.to_vec()
+            ]
+        );
+        assert!(matches!(*chip.state.lock().await, State::Closed));
+    }
+
+    #[tokio::test]
+    async fn close_reports_reset_write_error() {
+        let uwbs = MockUwbs::default();
+        let chip = UwbChip::new_mock("0".to_owned(), uwbs.clone());
+        let client = TestClient::default();
+
+        chip.open(&client.callbacks()).await.unwrap();
+        uwbs.fail_writes();
+        chip.close().await.unwrap();
+
+        assert_eq!(
+            client.events().last(),
+            Some(&(UwbEvent::CLOSE_CPLT, UwbStatus::FAILED))
+        );
+        assert!(chip.close().await.is_err());
     }
 }
@@ -0,0 +1,108 @@
+//! Blocking interface to a UWB chip, for callers without a tokio runtime.
+
+use android_hardware_uwb::aidl::android::hardware::uwb::{
+    IUwbChip::IUwbChipAsyncServer, IUwbClientCallback::IUwbClientCallback,
+};
+use android_hardware_uwb::binder::{Result, Strong};
+
+use std::io;
+use std::thread;
+
+use tokio::runtime::{Builder, Handle};
+use tokio::sync::oneshot;
+
+use crate::uwb_chip::UwbChip;
+
+/// Wrapper exposing the methods of a chip as blocking calls. The chip
+/// runs on a dedicated current-thread runtime, driven by a background
+/// thread so that the UCI messages keep being received between calls.
+///
+/// The methods block the calling thread, and must not be called from
+/// within a tokio runtime.
+pub struct UwbChipSync {
+    chip: UwbChip,
+    handle: Handle,
+    /// Stops the thread driving the runtime when dropped,
+    /// after the chip has been released.
+    _shutdown: oneshot::Sender<()>,
+}
+
+impl UwbChipSync {
+    pub fn new(chip: UwbChip) -> io::Result<Self> {
+        let runtime = Builder::new_current_thread().enable_all().build()?;
+        let handle = runtime.handle().clone();
+        let (shutdown, stopped) = oneshot::channel::<()>();
+        thread::spawn(move || {
+            runtime.block_on(async {
+                let _ = stopped.await;
+            })
+        });
+        Ok(UwbChipSync {
+            chip,
+            handle,
+            _shutdown: shutdown,
+        })
+    }
+
+    pub fn open(&self, callbacks: &Strong<dyn IUwbClientCallback>) -> Result<()> {
+        self.handle.block_on(self.chip.open(callbacks))
+    }
+
+    /// Send a UCI message to the UWBS. Returns the number of bytes written.
+    pub fn send(&self, data: &[u8]) -> Result<i32> {
+        self.handle.block_on(self.chip.sendUciMessage(data))
+    }
+
+    pub fn close(&self) -> Result<()> {
+        self.handle.block_on(self.chip.close())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{MockUwbs, TestClient};
+    use crate::uci;
+    use android_hardware_uwb::aidl::android::hardware::uwb::{
+        UwbEvent::UwbEvent, UwbStatus::UwbStatus,
+    };
+    use std::time::{Duration, Instant};
+
+    /// Wait for the chip to meet a condition, from a blocking thread.
+    fn wait_until(condition: impl Fn() -> bool) {
+        let deadline = Instant::now() + Duration::from_secs(1);
+        while !condition() {
+            assert!(Instant::now() < deadline, "timed out waiting for the chip");
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn open_send_close() {
+        const GET_CAPS_INFO_CMD: [u8; 4] = [0x20, 0x03, 0x00, 0x00];
+        const GET_CAPS_INFO_RSP: [u8; 6] = [0x40, 0x03, 0x00, 0x02, 0x00, 0x00];
+        const DEVICE_RESET_RSP_NTF: [u8; 10] =
+            [0x40, 0x00, 0x00, 0x01, 0x00, 0x60, 0x01, 0x00, 0x01, 0x01];
+        let uwbs = MockUwbs::default()
+            .with_response(uci::GID_CORE, 0x03, &GET_CAPS_INFO_RSP)
+            .with_response(
+                uci::GID_CORE,
+                uci::OID_CORE_DEVICE_RESET,
+                &DEVICE_RESET_RSP_NTF,
+            );
+        let chip = UwbChipSync::new(UwbChip::new_mock("0".to_owned(), uwbs)).unwrap();
+        let client = TestClient::default();
+
+        chip.open(&client.callbacks()).unwrap();
+        wait_until(|| client.events() == [(UwbEvent::OPEN_CPLT, UwbStatus::OK)]);
+
+        assert_eq!(chip.send(&GET_CAPS_INFO_CMD).unwrap(), 4);
+        wait_until(|| client.messages() == [GET_CAPS_INFO_RSP]);
+
+        chip.close().unwrap();
+        assert_eq!(
+            client.events().last(),
+            Some(&(UwbEvent::CLOSE_CPLT, UwbStatus::OK))
+        );
+    }
+}
@@ -0,0 +1,91 @@
+//! Extension point for the vendor specific UCI groups.
+
+use crate::uci::{AppConfigTag, DeviceInfo, UciHeader};
+
+/// Group identifiers reserved for vendor specific messages. 0xC and 0xD
+/// are used by the Android and test groups.
+pub fn is_vendor_group(group_id: u8) -> bool {
+    matches!(group_id, 0x09..=0x0b | 0x0e..=0x0f)
+}
+
+/// Handler of the messages of the vendor specific groups received from
+/// the UWBS, consulted by the reader task before they are delivered to
+/// the clients.
+pub trait VendorUciHandler: Send + Sync {
+    /// Handle a message of the vendor group `gid`. Returns the message
+    /// to write back to the UWBS, in which case the message is not
+    /// delivered to the clients, or None to deliver it.
+    fn on_vendor_message(&self, gid: u8, data: &[u8]) -> Option<Vec<u8>>;
+
+    /// Extract the Android UCI version supported by the UWBS from the
+    /// vendor specific information of its CORE_GET_DEVICE_INFO_RSP, the
+    /// standard fields only carrying the FiRa versions.
+    fn android_uci_version(&self, _device_info: &DeviceInfo) -> Option<i32> {
+        None
+    }
+
+    /// Extract the maximum number of concurrent sessions from the vendor
+    /// specific TLVs of CORE_GET_CAPS_INFO_RSP, the standard capabilities
+    /// not including it. The sessions are not limited by default.
+    fn max_sessions(&self, _caps_info_rsp: &[u8]) -> Option<usize> {
+        None
+    }
+
+    /// App configurations of the vendor specific initialization of the
+    /// session `session_id`, set by sessionInit(). None by default.
+    fn session_app_configs(&self, _session_id: i32) -> Vec<(AppConfigTag, Vec<u8>)> {
+        Vec::new()
+    }
+}
+
+/// Default handler delivering all vendor messages to the clients.
+pub struct PassThrough;
+
+impl VendorUciHandler for PassThrough {
+    fn on_vendor_message(&self, _gid: u8, _data: &[u8]) -> Option<Vec<u8>> {
+        None
+    }
+}
+
+/// Handler of the boards listing the vendor specific values of their UWBS
+/// in the configuration file, delivering all vendor messages to the clients.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Configured {
+    /// Offset of the octet carrying the Android UCI version in the vendor
+    /// specific information of CORE_GET_DEVICE_INFO_RSP.
+    pub android_uci_version_offset: Option<usize>,
+    /// Maximum number of concurrent sessions of the UWBS.
+    pub max_sessions: Option<usize>,
+    /// App configurations set by sessionInit() on each session.
+    pub session_app_configs: Vec<(AppConfigTag, Vec<u8>)>,
+}
+
+impl VendorUciHandler for Configured {
+    fn on_vendor_message(&self, _gid: u8, _data: &[u8]) -> Option<Vec<u8>> {
+        None
+    }
+
+    fn android_uci_version(&self, device_info: &DeviceInfo) -> Option<i32> {
+        let offset = self.android_uci_version_offset?;
+        device_info
+            .vendor_specific_info
+            .get(offset)
+            .map(|&version| version as i32)
+    }
+
+    fn max_sessions(&self, _caps_info_rsp: &[u8]) -> Option<usize> {
+        self.max_sessions
+    }
+
+    fn session_app_configs(&self, _session_id: i32) -> Vec<(AppConfigTag, Vec<u8>)> {
+        self.session_app_configs.clone()
+    }
+}
+
+/// Consult the handler if the message belongs to a vendor group.
+pub fn handle(handler: &dyn VendorUciHandler, header: &UciHeader, data: &[u8]) -> Option<Vec<u8>> {
+    if !is_vendor_group(header.group_id) {
+        return None;
+    }
+    handler.on_vendor_message(header.group_id, data)
+}